@@ -0,0 +1,103 @@
+//Compares the old HashMap<u64, T> component storage against SparseSet<T> for 10k entities.
+//Run with cargo bench --bench component_storage
+use std::collections::HashMap;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+#[path = "../src/sparse_set.rs"]
+#[allow(dead_code)]
+mod sparse_set;
+use sparse_set::SparseSet;
+
+const ENTITIES: u64 = 10_000;
+const ROUNDS: u32 = 100;
+
+//Same shape as the game's Position so the iteration work is comparable
+struct Position {
+	x: f64,
+	y: f64,
+}
+
+fn time<F: FnMut()>(mut f: F) -> Duration {
+	let start = Instant::now();
+	for _ in 0..ROUNDS {
+		f();
+	}
+	return start.elapsed() / ROUNDS;
+}
+
+fn report(name: &str, hashmap: Duration, sparse_set: Duration) {
+	println!("{:<10} hashmap:{:>10.2?} sparse_set:{:>10.2?} ({:.2}x)", name, hashmap, sparse_set, hashmap.as_secs_f64() / sparse_set.as_secs_f64());
+}
+
+fn main() {
+	println!("{} entities, averaged over {} rounds", ENTITIES, ROUNDS);
+
+	let insert_hashmap = time(|| {
+		let mut store = HashMap::new();
+		for id in 0..ENTITIES {
+			store.insert(id, Position {x: id as f64, y: 0.0});
+		}
+		black_box(store);
+	});
+	let insert_sparse_set = time(|| {
+		let mut store = SparseSet::new();
+		for id in 0..ENTITIES {
+			store.insert(id, Position {x: id as f64, y: 0.0}, 0);
+		}
+		black_box(store);
+	});
+	report("insert", insert_hashmap, insert_sparse_set);
+
+	let mut hashmap = HashMap::new();
+	let mut sparse_set = SparseSet::new();
+	for id in 0..ENTITIES {
+		hashmap.insert(id, Position {x: id as f64, y: 0.0});
+		sparse_set.insert(id, Position {x: id as f64, y: 0.0}, 0);
+	}
+
+	let iterate_hashmap = time(|| {
+		for (_, p) in hashmap.iter_mut() {
+			p.y += p.x * 0.5;
+		}
+		black_box(&hashmap);
+	});
+	let iterate_sparse_set = time(|| {
+		for (_, p) in sparse_set.iter_mut() {
+			p.y += p.x * 0.5;
+		}
+		black_box(&sparse_set);
+	});
+	report("iterate", iterate_hashmap, iterate_sparse_set);
+
+	let lookup_hashmap = time(|| {
+		let mut sum = 0.0;
+		for id in (0..ENTITIES).rev() {
+			sum += hashmap.get(&id).map_or(0.0, |p| p.x);
+		}
+		black_box(sum);
+	});
+	let lookup_sparse_set = time(|| {
+		let mut sum = 0.0;
+		for id in (0..ENTITIES).rev() {
+			sum += sparse_set.get(id).map_or(0.0, |p| p.x);
+		}
+		black_box(sum);
+	});
+	report("lookup", lookup_hashmap, lookup_sparse_set);
+
+	//Remove every other entity then put them back so the stores end each round as they started
+	let remove_hashmap = time(|| {
+		for id in (0..ENTITIES).step_by(2) {
+			let p = hashmap.remove(&id).unwrap();
+			hashmap.insert(id, p);
+		}
+	});
+	let remove_sparse_set = time(|| {
+		for id in (0..ENTITIES).step_by(2) {
+			let p = sparse_set.remove(id).unwrap();
+			sparse_set.insert(id, p, 0);
+		}
+	});
+	report("remove", remove_hashmap, remove_sparse_set);
+}
@@ -0,0 +1,106 @@
+use std::any::TypeId;
+
+use crate::entity_system::{Component, Entity, EntitySystem, EntitySystemError};
+
+//A group of components added to an entity together with EntitySystem::spawn/insert. Implemented
+//for tuples of components and for structs through bundle_struct!.
+//
+//prepare is everything that can go wrong for the built in bundles, it runs for the whole bundle
+//before anything gets added. insert doesn't run the on_add hooks, it records what it did in
+//inserted so the hooks can be run once the whole bundle is in, or what it did undone if it fails
+//part way through.
+pub trait Bundle: Send + Sync + 'static {
+	fn prepare(&self, es: &mut EntitySystem) -> Result<(), EntitySystemError>;
+
+	fn insert(self, es: &mut EntitySystem, ent: Entity, inserted: &mut Inserted) -> Result<(), EntitySystemError>;
+}
+
+type UndoFn = Box<dyn FnOnce(&mut EntitySystem, Entity)>;
+
+//What a Bundle has done to an entity so far
+#[derive(Default)]
+pub struct Inserted {
+	added: Vec<TypeId>, //Types the entity didn't already have, for the on_add hooks
+	undo: Vec<UndoFn>,
+}
+
+impl Inserted {
+	pub fn new() -> Self {
+		return Inserted {added: Vec::new(), undo: Vec::new()};
+	}
+
+	pub fn added(&self) -> &[TypeId] {
+		return &self.added;
+	}
+
+	//Puts the entity back the way it was before the bundle, newest first so a component inserted
+	//twice ends up as it was before either. No hooks run and nothing shows up in removed().
+	pub fn undo(self, es: &mut EntitySystem, ent: Entity) {
+		for undo in self.undo.into_iter().rev() {
+			undo(es, ent);
+		}
+	}
+}
+
+//Lets bundle_struct! get at the type of each field without having to be told it
+pub fn prepare_component<ComponentType: Component>(_component: &ComponentType, es: &mut EntitySystem) -> Result<(), EntitySystemError> {
+	return es.prepare_component_store::<ComponentType>();
+}
+
+pub fn insert_component<ComponentType: Component>(component: ComponentType, es: &mut EntitySystem, ent: Entity, inserted: &mut Inserted) -> Result<(), EntitySystemError> {
+	let replaced = es.store_component(ent, component)?;
+	if replaced.is_none() {
+		inserted.added.push(TypeId::of::<ComponentType>());
+	}
+
+	inserted.undo.push(Box::new(move |es, ent| es.unstore_component::<ComponentType>(ent, replaced)));
+	return Ok(());
+}
+
+macro_rules! tuple_bundle {
+	($($name:ident),+) => {
+		#[allow(non_snake_case)]
+		impl<$($name: Component),+> Bundle for ($($name,)+) {
+			fn prepare(&self, es: &mut EntitySystem) -> Result<(), EntitySystemError> {
+				let ($($name,)+) = self;
+				$(prepare_component($name, es)?;)+
+				return Ok(());
+			}
+
+			fn insert(self, es: &mut EntitySystem, ent: Entity, inserted: &mut Inserted) -> Result<(), EntitySystemError> {
+				let ($($name,)+) = self;
+				$(insert_component($name, es, ent, inserted)?;)+
+				return Ok(());
+			}
+		}
+	};
+}
+
+tuple_bundle!(A);
+tuple_bundle!(A, B);
+tuple_bundle!(A, B, C);
+tuple_bundle!(A, B, C, D);
+tuple_bundle!(A, B, C, D, E);
+tuple_bundle!(A, B, C, D, E, F);
+tuple_bundle!(A, B, C, D, E, F, G);
+tuple_bundle!(A, B, C, D, E, F, G, H);
+
+//Implements Bundle for a struct whose fields are all components, each field is added as its own
+//component e.g. bundle_struct!(PlayerBundle { position, moveable, direction, animations });
+macro_rules! bundle_struct {
+	($type:ident { $($field:ident),* $(,)? }) => {
+		impl $crate::bundle::Bundle for $type {
+			fn prepare(&self, es: &mut $crate::entity_system::EntitySystem) -> Result<(), $crate::entity_system::EntitySystemError> {
+				$($crate::bundle::prepare_component(&self.$field, es)?;)*
+				return Ok(());
+			}
+
+			fn insert(self, es: &mut $crate::entity_system::EntitySystem, ent: $crate::entity_system::Entity, inserted: &mut $crate::bundle::Inserted) -> Result<(), $crate::entity_system::EntitySystemError> {
+				$($crate::bundle::insert_component(self.$field, es, ent, inserted)?;)*
+				return Ok(());
+			}
+		}
+	};
+}
+
+pub(crate) use bundle_struct;
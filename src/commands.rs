@@ -0,0 +1,261 @@
+use std::sync::Mutex;
+
+use crate::bundle::{self, Bundle, Inserted};
+use crate::entity_system::{Component, Entity, EntitySystem, EntitySystemError};
+
+type CommandFn = Box<dyn FnOnce(&mut EntitySystem) -> Result<(), EntitySystemError> + Send>;
+
+struct QueuedCommand {
+	system: Option<&'static str>, //The system that queued it, used to keep the apply order deterministic
+	command: CommandFn,
+}
+
+//Changes to the EntitySystem that can't be made while systems hold borrows of it. Systems push
+//them through Commands and the schedule applies them at the end of each stage.
+pub struct CommandQueue {
+	queued: Mutex<Vec<QueuedCommand>>,
+}
+
+impl CommandQueue {
+	pub fn new() -> Self {
+		return CommandQueue {queued: Mutex::new(Vec::new())};
+	}
+
+	fn push(&self, system: Option<&'static str>, command: CommandFn) {
+		self.queued.lock().unwrap_or_else(|e| e.into_inner()).push(QueuedCommand {system, command});
+	}
+
+	pub fn is_empty(&self) -> bool {
+		return self.queued.lock().unwrap_or_else(|e| e.into_inner()).is_empty();
+	}
+
+	//Commands are grouped by the position of the system that queued them in system_order, each
+	//system's commands staying in the order it queued them. Systems running in parallel queue
+	//commands in whatever order the threads get there so this keeps the results the same as
+	//running them one after the other. Commands from systems not in the list go last.
+	pub(crate) fn take_ordered(&mut self, system_order: &[&'static str]) -> Vec<CommandFn> {
+		let mut queued = std::mem::take(self.queued.get_mut().unwrap_or_else(|e| e.into_inner()));
+		queued.sort_by_key(|q| {
+			match q.system {
+				Some(name) => system_order.iter().position(|s| *s == name).unwrap_or(system_order.len()),
+				None => system_order.len(),
+			}
+		});
+
+		return queued.into_iter().map(|q| q.command).collect();
+	}
+}
+
+//Handed out by EntitySystem::commands, queues changes on behalf of the running system
+pub struct Commands<'w> {
+	es: &'w EntitySystem,
+	system: Option<&'static str>,
+}
+
+impl<'w> Commands<'w> {
+	pub fn new(es: &'w EntitySystem, system: Option<&'static str>) -> Self {
+		return Commands {es, system};
+	}
+
+	//Anything that needs the EntitySystem mutably
+	pub fn add<F>(&self, command: F) where
+		F: FnOnce(&mut EntitySystem) -> Result<(), EntitySystemError> + Send + 'static {
+		self.es.command_queue().push(self.system, Box::new(command));
+	}
+
+	//Creates the entity with every component inserted on the returned SpawnCommands, which are
+	//queued together when it's dropped. The entity is reserved straight away so other commands can
+	//refer to it, as long as they're queued after the spawn is e.g.
+	//let flame = commands.spawn("Flame")?.insert(position).insert(drawable).id();
+	//commands.add(move |es| es.set_parent(flame, bomb));
+	pub fn spawn(&self, name: &str) -> Result<SpawnCommands<'w>, EntitySystemError> {
+		let ent = self.es.reserve_entity()?;
+		return Ok(SpawnCommands {queue: self.es.command_queue(), system: self.system, ent, name: name.to_string(), inserts: Vec::new()});
+	}
+
+	pub fn insert<ComponentType: Component>(&self, ent: Entity, component: ComponentType) {
+		self.add(move |es| es.add_component_to_entity(ent, component));
+	}
+
+	#[cfg(test)]
+	pub fn insert_bundle<B: Bundle>(&self, ent: Entity, bundle: B) {
+		self.add(move |es| es.insert(ent, bundle));
+	}
+
+	#[cfg(test)]
+	pub fn remove<ComponentType: Component>(&self, ent: Entity) {
+		self.add(move |es| es.remove_component_from_entity::<ComponentType>(ent));
+	}
+
+	pub fn despawn(&self, ent: Entity) {
+		self.add(move |es| es.remove_entity(ent));
+	}
+}
+
+type InsertFn = Box<dyn FnOnce(&mut EntitySystem, Entity, &mut Inserted) -> Result<(), EntitySystemError> + Send + Sync>;
+
+pub struct SpawnCommands<'w> {
+	queue: &'w CommandQueue,
+	system: Option<&'static str>,
+	ent: Entity,
+	name: String,
+	inserts: Vec<InsertFn>,
+}
+
+impl<'w> SpawnCommands<'w> {
+	//The entity this will spawn, it isn't alive until the commands are applied
+	#[cfg(test)]
+	pub fn id(&self) -> Entity {
+		return self.ent;
+	}
+
+	pub fn insert<ComponentType: Component>(mut self, component: ComponentType) -> Self {
+		self.inserts.push(Box::new(move |es, ent, inserted| bundle::insert_component(component, es, ent, inserted)));
+		return self;
+	}
+
+	#[cfg(test)]
+	pub fn insert_bundle<B: Bundle>(mut self, bundle: B) -> Self {
+		self.inserts.push(Box::new(move |es, ent, inserted| {
+			bundle.prepare(es)?;
+			return bundle.insert(es, ent, inserted);
+		}));
+		return self;
+	}
+}
+
+//Everything inserted on a SpawnCommands as one bundle, so the spawn goes through
+//EntitySystem::spawn_reserved like any other and the hooks wait until all of it is in
+struct QueuedInserts(Vec<InsertFn>);
+
+impl Bundle for QueuedInserts {
+	//Each insert prepares its own stores when it runs
+	fn prepare(&self, _es: &mut EntitySystem) -> Result<(), EntitySystemError> {
+		return Ok(());
+	}
+
+	fn insert(self, es: &mut EntitySystem, ent: Entity, inserted: &mut Inserted) -> Result<(), EntitySystemError> {
+		for insert in self.0 {
+			insert(es, ent, inserted)?;
+		}
+		return Ok(());
+	}
+}
+
+impl<'w> Drop for SpawnCommands<'w> {
+	fn drop(&mut self) {
+		let ent = self.ent;
+		let name = std::mem::take(&mut self.name);
+		let inserts = QueuedInserts(std::mem::take(&mut self.inserts));
+
+		self.queue.push(self.system, Box::new(move |es| es.spawn_reserved(ent, name, inserts)));
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Arc;
+	use std::sync::atomic::{AtomicUsize, Ordering};
+
+	use super::*;
+	use crate::components::{LocalPosition, Position};
+
+	#[test]
+	fn spawn_hands_back_the_entity() {
+		let mut es = EntitySystem::new();
+		let parent = es.spawn((Position::new(1.0, 2.0),)).unwrap();
+
+		let child = {
+			let commands = es.commands();
+			let child = commands.spawn("Child").unwrap().insert(Position::new(0.0, 0.0)).insert(LocalPosition::new(1.0, 1.0)).id();
+			commands.add(move |es| es.set_parent(child, parent));
+			child
+		};
+		assert!(!es.is_alive(child)); //Not until the commands are applied
+
+		//Making an entity directly in between doesn't take the reserved index
+		let other = es.new_entity().unwrap();
+		assert_ne!(other.index(), child.index());
+
+		es.apply_commands().unwrap();
+		assert!(es.is_alive(child));
+		assert_eq!(es.parent_of(child), Some(parent));
+		assert_eq!(es.entities_with_name("Child"), vec![child]);
+	}
+
+	#[test]
+	fn spawn_reuses_removed_indices() {
+		let mut es = EntitySystem::new();
+		let removed = es.new_entity().unwrap();
+		es.new_entity().unwrap();
+		es.remove_entity(removed).unwrap();
+
+		let (first, second) = {
+			let commands = es.commands();
+			(commands.spawn("First").unwrap().id(), commands.spawn("Second").unwrap().id())
+		};
+		assert_eq!(first.index(), removed.index());
+		assert_eq!(first.generation(), removed.generation() + 1);
+		assert_eq!(second.index(), 2);
+
+		es.apply_commands().unwrap();
+		assert!(es.is_alive(first) && es.is_alive(second));
+		assert!(!es.is_alive(removed));
+		assert_eq!(es.new_entity().unwrap().index(), 3);
+	}
+
+	#[test]
+	fn inserts_and_removes_wait_for_the_commands() {
+		let mut es = EntitySystem::new();
+		let ent = es.spawn((Position::new(1.0, 2.0),)).unwrap();
+
+		{
+			let commands = es.commands();
+			commands.insert_bundle(ent, (LocalPosition::new(3.0, 4.0),));
+			commands.remove::<Position>(ent);
+		}
+		assert!(es.get::<LocalPosition>(ent).is_err());
+		assert!(es.get::<Position>(ent).is_ok());
+
+		es.apply_commands().unwrap();
+		assert_eq!(es.get::<LocalPosition>(ent).unwrap().x, 3.0);
+		assert!(es.get::<Position>(ent).is_err());
+		assert_eq!(es.removed::<Position>(), vec![ent]);
+	}
+
+	//Fails once it's been handed the entity, like a bundle that runs into trouble part way through
+	struct FailingBundle;
+
+	impl Bundle for FailingBundle {
+		fn prepare(&self, _es: &mut EntitySystem) -> Result<(), EntitySystemError> {
+			return Ok(());
+		}
+
+		fn insert(self, _es: &mut EntitySystem, _ent: Entity, _inserted: &mut Inserted) -> Result<(), EntitySystemError> {
+			return Err(EntitySystemError::NoSuchName {name: "failing".to_string()});
+		}
+	}
+
+	#[test]
+	fn spawn_runs_the_hooks_once_everything_is_in() {
+		let mut es = EntitySystem::new();
+		let seen = Arc::new(AtomicUsize::new(0));
+		let counter = seen.clone();
+		es.register_hooks::<Position, _, _>(move |_, _| { counter.fetch_add(1, Ordering::Relaxed); }, |_, _| panic!("nothing was removed"));
+
+		es.commands().spawn("Works").unwrap().insert(Position::new(0.0, 0.0)).insert(LocalPosition::new(1.0, 1.0));
+		es.apply_commands().unwrap();
+		assert_eq!(seen.load(Ordering::Relaxed), 1);
+
+		let failed = es.commands().spawn("Fails").unwrap().insert(Position::new(0.0, 0.0)).insert_bundle(FailingBundle).id();
+		match es.apply_commands() {
+			Err(EntitySystemError::NoSuchName {name}) => assert_eq!(name, "failing"),
+			_ => panic!("expected the bundle's error"),
+		}
+
+		assert_eq!(seen.load(Ordering::Relaxed), 1);
+		assert!(!es.is_alive(failed));
+		assert!(es.entities_with_name("Fails").is_empty());
+		assert!(es.removed::<Position>().is_empty());
+	}
+}
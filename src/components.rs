@@ -1,6 +1,44 @@
 use std::fmt::{self, Display};
 use std::collections::HashMap;
+use std::any::Any;
 
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::bundle::bundle_struct;
+use crate::entity_system::{Entity, EntitySystem, EntitySystemError};
+use crate::reflect::{reflect_struct, Reflect};
+
+//Makes the components available to prefabs and saves under their type names
+pub fn register_components(es: &mut EntitySystem) {
+	es.register_component::<Position>("Position");
+	es.register_component::<LocalPosition>("LocalPosition");
+	es.register_component_with::<Parent>("Parent", deserialize_parent);
+	es.register_component_with::<Children>("Children", deserialize_children);
+	es.register_component::<Moveable>("Moveable");
+	es.register_component::<Collidable>("Collidable");
+	es.register_component::<Drawable>("Drawable");
+	es.register_component::<Animations>("Animations");
+	es.register_component::<Direction>("Direction");
+	es.register_component::<Fuse>("Fuse");
+	es.register_component::<Flame>("Flame");
+
+	es.register_reflect::<Position>();
+	es.register_reflect::<LocalPosition>();
+	//Editing either side on its own would leave the other out of sync, see set_parent
+	es.register_reflect_read_only::<Parent>();
+	es.register_reflect_read_only::<Children>();
+	es.register_reflect::<Collidable>();
+	es.register_reflect::<Moveable>();
+	es.register_reflect::<Drawable>();
+	es.register_reflect::<Animations>();
+	es.register_reflect::<Direction>();
+	es.register_reflect::<Fuse>();
+	es.register_reflect::<Flame>();
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Position {
 	pub x: f64,
 	pub y: f64,
@@ -12,12 +50,77 @@ impl Position {
 	}
 }
 
+reflect_struct!(Position { x, y });
+
 impl Display for Position {
 	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
 		return write!(f, "x:{}, y:{}", self.x, self.y);
 	}
 }
 
+//Position relative to the Parent's Position, system_transform works out the Position from it
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LocalPosition {
+	pub x: f64,
+	pub y: f64,
+}
+
+impl LocalPosition {
+	#[cfg(test)]
+	pub fn new(x: f64, y: f64) -> LocalPosition {
+		return LocalPosition {x, y};
+	}
+}
+
+reflect_struct!(LocalPosition { x, y });
+
+//Parent and Children are kept in sync by EntitySystem::set_parent/remove_parent, don't add them
+//directly
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Parent {
+	pub entity: Entity,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Children {
+	pub entities: Vec<Entity>,
+}
+
+reflect_struct!(Parent { entity });
+reflect_struct!(Children { entities });
+
+//Saves and prefabs go through set_parent as well so they can't make a loop or have the two sides
+//disagree
+fn deserialize_parent(es: &mut EntitySystem, ent: Entity, data: Value) -> Result<(), EntitySystemError> {
+	let parent = parse_component::<Parent>(ent, data)?;
+	return adopt(es, parent.entity, ent);
+}
+
+fn deserialize_children(es: &mut EntitySystem, ent: Entity, data: Value) -> Result<(), EntitySystemError> {
+	for child in parse_component::<Children>(ent, data)?.entities {
+		adopt(es, ent, child)?;
+	}
+	return Ok(());
+}
+
+fn parse_component<ComponentType: DeserializeOwned>(ent: Entity, data: Value) -> Result<ComponentType, EntitySystemError> {
+	match serde_json::from_value::<ComponentType>(data) {
+		Ok(c) => return Ok(c),
+		Err(e) => return Err(EntitySystemError::InvalidComponentData {entity: Some(ent), component: std::any::type_name::<ComponentType>(), reason: e.to_string()}),
+	}
+}
+
+//The Parent and Children of a saved entity describe the same link, so already being the child of
+//that parent is fine but being anyone else's isn't
+fn adopt(es: &mut EntitySystem, parent: Entity, child: Entity) -> Result<(), EntitySystemError> {
+	match es.parent_of(child) {
+		Some(p) if p == parent => return Ok(()),
+		Some(p) => return Err(EntitySystemError::InvalidComponentData {entity: Some(child), component: std::any::type_name::<Parent>(), reason: format!("it's already a child of {}", p)}),
+		None => return es.set_parent(child, parent),
+	}
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Moveable {
 	pub dx: f64,
 	pub dy: f64,
@@ -29,17 +132,23 @@ impl Moveable {
 	}
 }
 
+reflect_struct!(Moveable { dx, dy });
+
 impl fmt::Display for Moveable {
 	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
 		return write!(f, "dx:{}, dy:{}", self.dx, self.dy);
 	}
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Collidable {
 	width: f64,
 	height: f64,
 }
 
+reflect_struct!(Collidable { width, height });
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Drawable {
     pub x: i32,
     pub y: i32,
@@ -54,10 +163,16 @@ impl Drawable {
     }
 }
 
+reflect_struct!(Drawable { x, y, w, h, layer });
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Animation {
     pub frames: Vec<Drawable>,
+    #[serde(default)]
     pub fps: f64,
+    #[serde(default)]
     pub flip_horizontal: bool,
+    #[serde(default)]
     pub flip_vertical: bool,
 }
 
@@ -71,7 +186,7 @@ impl Animation {
     }
 }
 
-#[derive(PartialEq, Eq, std::hash::Hash, Copy, Clone)]
+#[derive(PartialEq, Eq, std::hash::Hash, Copy, Clone, Debug, Serialize, Deserialize)]
 pub enum AnimationType {
     Empty,
     StandingDown,
@@ -84,10 +199,13 @@ pub enum AnimationType {
     WalkingRight,
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Animations {
     pub animations: HashMap<AnimationType, Animation>,
     pub current_animation: AnimationType,
+    #[serde(default)]
     pub current_frame: usize,
+    #[serde(default)]
     pub last_frame_time: f64,
 }
 
@@ -97,10 +215,76 @@ impl Animations {
     }
 }
 
-#[derive(PartialEq, Eq, std::hash::Hash)]
+reflect_struct!(Animations { animations, current_animation, current_frame, last_frame_time });
+
+#[derive(PartialEq, Eq, std::hash::Hash, Clone, Debug, Serialize, Deserialize)]
 pub enum Direction {
     Down,
     Up,
     Left,
     Right,
 }
+
+//No fields, the whole value is the direction. Get and set it through as_any/as_any_mut.
+impl Reflect for Direction {
+    fn type_name(&self) -> &'static str {
+        return "Direction";
+    }
+
+    fn field_names(&self) -> &'static [&'static str] {
+        return &[];
+    }
+
+    fn field(&self, _name: &str) -> Option<&dyn Any> {
+        return None;
+    }
+
+    fn field_mut(&mut self, _name: &str) -> Option<&mut dyn Any> {
+        return None;
+    }
+
+    fn field_to_string(&self, _name: &str) -> Option<String> {
+        return None;
+    }
+
+    #[cfg(test)]
+    fn as_any(&self) -> &dyn Any {
+        return self;
+    }
+
+    #[cfg(test)]
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        return self;
+    }
+
+    fn describe(&self) -> String {
+        return format!("{:?}", self);
+    }
+}
+
+//A bomb counting down, system_fuse blows it up once it runs out
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Fuse {
+    pub remaining: f64, //Seconds
+}
+
+reflect_struct!(Fuse { remaining });
+
+//Left behind by an explosion, burns out after a while
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Flame {
+    pub remaining: f64, //Seconds
+}
+
+reflect_struct!(Flame { remaining });
+
+//The components that make something behave like the player, the name and tags are up to whoever
+//spawns it
+pub struct PlayerBundle {
+    pub position: Position,
+    pub moveable: Moveable,
+    pub direction: Direction,
+    pub animations: Animations,
+}
+
+bundle_struct!(PlayerBundle { position, moveable, direction, animations });
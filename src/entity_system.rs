@@ -1,45 +1,325 @@
 use std::fmt::{self, Display};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::any::{TypeId, Any};
-use std::cell::{RefCell, RefMut, Ref};
-
-use sdl2::render::{Canvas, Texture};
-use sdl2::video::Window;
+use std::cell::{Cell, RefCell, RefMut};
+use std::sync::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread::{self, ThreadId};
+use std::path::Path;
 
 use std::error::Error;
 
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+
+use crate::query::{self, Query, QueryData, QueryFilter};
+use crate::sparse_set::{ComponentTicks, SparseSet};
+use crate::commands::{CommandQueue, Commands};
+use crate::events::{Event, EventQueue, Events};
+use crate::components::{Children, Parent};
+use crate::registry::{ComponentRegistry, DeserializeFn};
+use crate::save::{SavedEntity, WorldSave};
+use crate::snapshot::WorldSnapshot;
+use crate::reflect::Reflect;
+use crate::bundle::{Bundle, Inserted};
+
+//Type names are the full std::any::type_name of the type involved. entity is None when the
+//error isn't about a particular entity, like a component type nothing has ever been added to.
 #[derive(Debug)]
 pub enum EntitySystemError {
-    NoSuchComponent(String),
-    DowncastFailed(String),
+    NoSuchEntity {entity: Entity}, //The index was never handed out
+    StaleEntity {entity: Entity, current_generation: u32},
+    NoSuchComponent {entity: Option<Entity>, component: String},
+    BorrowConflict {entity: Option<Entity>, component: &'static str, mode: BorrowMode, held_by: Option<&'static str>},
+    UndeclaredAccess {entity: Option<Entity>, component: &'static str, mode: BorrowMode, system: &'static str},
+    DowncastFailed {entity: Option<Entity>, type_name: &'static str},
+    OutOfEntities {name: String},
+    NoSuchName {name: String},
+    DuplicateName {name: String, count: usize},
+    InvalidHierarchy {parent: Entity, child: Entity},
+    NoSuchResource {resource: &'static str},
+    WrongThread {resource: &'static str},
+    NoSuchEvent {event: &'static str},
+    NoSuchPrefab {name: String},
+    InvalidPrefab {reason: String},
+    InvalidComponentData {entity: Option<Entity>, component: &'static str, reason: String},
+    InvalidSave {reason: String},
+    NoSuchField {component: &'static str, field: String},
+    ReadOnlyComponent {entity: Entity, component: &'static str}, //Reflected but not editable through reflection
+}
+
+impl EntitySystemError {
+    //Fills in the entity for errors that came from a store lookup that didn't know it
+    pub fn for_entity(self, ent: Entity) -> Self {
+        match self {
+            EntitySystemError::NoSuchComponent {entity: None, component} => return EntitySystemError::NoSuchComponent {entity: Some(ent), component},
+            EntitySystemError::DowncastFailed {entity: None, type_name} => return EntitySystemError::DowncastFailed {entity: Some(ent), type_name},
+            EntitySystemError::UndeclaredAccess {entity: None, component, mode, system} => return EntitySystemError::UndeclaredAccess {entity: Some(ent), component, mode, system},
+            EntitySystemError::BorrowConflict {entity: None, component, mode, held_by} => return EntitySystemError::BorrowConflict {entity: Some(ent), component, mode, held_by},
+            e => return e,
+        }
+    }
+}
+
+//How a component store was being borrowed when the borrow failed
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum BorrowMode {
+    Shared,
+    Exclusive,
+}
+
+impl fmt::Display for BorrowMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BorrowMode::Shared => write!(f, "shared"),
+            BorrowMode::Exclusive => write!(f, "exclusive"),
+        }
+    }
 }
 
 impl fmt::Display for EntitySystemError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            EntitySystemError::NoSuchComponent(msg) => {
-                write!(f, "no such component stored in EntitySystem: {}", msg)
+            EntitySystemError::NoSuchEntity {entity} => {
+                write!(f, "no such entity stored in EntitySystem: {}", entity)
+            },
+            EntitySystemError::StaleEntity {entity, current_generation} => {
+                write!(f, "stale entity handle {}, the entity has been removed and the index is now at generation {}", entity, current_generation)
+            },
+            EntitySystemError::NoSuchComponent {entity: Some(entity), component} => {
+                write!(f, "no <{}> component for entity {}", component, entity)
+            },
+            EntitySystemError::NoSuchComponent {entity: None, component} => {
+                write!(f, "no such component stored in EntitySystem: <{}>", component)
+            },
+            //component is the type that was being borrowed, resources get borrowed the same way
+            EntitySystemError::BorrowConflict {entity, component, mode, held_by} => {
+                let of = match entity {
+                    Some(entity) => format!("<{}> for entity {}", component, entity),
+                    None => format!("<{}>", component),
+                };
+                match held_by {
+                    Some(system) => write!(f, "Unable to take a {} borrow of {}, it's already borrowed by {}", mode, of, system),
+                    None => write!(f, "Unable to take a {} borrow of {}, it's already borrowed", mode, of),
+                }
+            },
+            EntitySystemError::UndeclaredAccess {entity: Some(entity), component, mode, system} => {
+                write!(f, "{} took a {} borrow of <{}> for entity {} without declaring it", system, mode, component, entity)
+            },
+            EntitySystemError::UndeclaredAccess {entity: None, component, mode, system} => {
+                write!(f, "{} took a {} borrow of <{}> without declaring it", system, mode, component)
+            },
+            EntitySystemError::DowncastFailed {entity: Some(entity), type_name} => {
+                write!(f, "Downcast failed for <{}> of entity {}", type_name, entity)
+            },
+            EntitySystemError::DowncastFailed {entity: None, type_name} => {
+                write!(f, "Downcast failed for <{}>", type_name)
+            },
+            EntitySystemError::OutOfEntities {name} => {
+                write!(f, "Unable to create entity {}, ran out of entity indices", name)
+            },
+            EntitySystemError::NoSuchName {name} => {
+                write!(f, "no entity called {}", name)
+            },
+            EntitySystemError::DuplicateName {name, count} => {
+                write!(f, "entity name isn't unique, {} entities are called {}", count, name)
+            },
+            EntitySystemError::InvalidHierarchy {parent, child} => {
+                write!(f, "entity {} can't be the parent of {}, it's one of its descendants", parent, child)
+            },
+            EntitySystemError::NoSuchResource {resource} => {
+                write!(f, "no such resource stored in EntitySystem: <{}>", resource)
+            },
+            EntitySystemError::WrongThread {resource} => {
+                write!(f, "resource <{}> can only be used on the thread that created the EntitySystem", resource)
+            },
+            EntitySystemError::NoSuchEvent {event} => {
+                write!(f, "no such event registered in EntitySystem: <{}>", event)
+            },
+            EntitySystemError::NoSuchPrefab {name} => {
+                write!(f, "no such prefab loaded: {}", name)
             },
-            EntitySystemError::DowncastFailed(msg) => {
-                write!(f, "Downcast failed for component: {}", msg)
+            EntitySystemError::InvalidPrefab {reason} => {
+                write!(f, "invalid prefab: {}", reason)
+            },
+            EntitySystemError::InvalidComponentData {entity: Some(entity), component, reason} => {
+                write!(f, "<{}> data for entity {} doesn't match the type: {}", component, entity, reason)
+            },
+            EntitySystemError::InvalidComponentData {entity: None, component, reason} => {
+                write!(f, "<{}> data doesn't match the type: {}", component, reason)
+            },
+            EntitySystemError::InvalidSave {reason} => {
+                write!(f, "unable to save or load world: {}", reason)
+            },
+            EntitySystemError::NoSuchField {component, field} => {
+                write!(f, "<{}> has no field {}", component, field)
+            },
+            EntitySystemError::ReadOnlyComponent {entity, component} => {
+                write!(f, "<{}> of entity {} can't be edited through reflection", component, entity)
             },
         }
     }
 }
 
-impl Error for EntitySystemError {
-    fn source(&self) -> Option<&(dyn Error + 'static)> {
-        return Some(self);
-    }
+//None of the variants wrap another error
+impl Error for EntitySystemError {}
+
+//Anything stored in the EntitySystem has to be shareable between threads so systems that don't
+//touch the same data can run in parallel
+pub trait Component: Send + Sync + 'static {}
+impl<T: Send + Sync + 'static> Component for T {}
+
+pub trait Resource: Send + Sync + 'static {}
+impl<T: Send + Sync + 'static> Resource for T {}
+
+thread_local! {
+	//Name of the system running on this thread, if anyone said
+	static RUNNING_SYSTEM: Cell<Option<&'static str>> = const { Cell::new(None) };
+	static RUNNING_TICKS: Cell<Option<SystemTicks>> = const { Cell::new(None) };
+	static RUNNING_ACCESS: RefCell<Option<DeclaredAccess>> = const { RefCell::new(None) };
+	static RUNNING_LANE: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+//The components and resources a running system said it would touch. The schedule decides what
+//can run in parallel from these so borrowing anything else is an error rather than a race.
+#[derive(Clone, Debug, Default)]
+pub struct DeclaredAccess {
+	pub reads: Vec<TypeId>,
+	pub writes: Vec<TypeId>,
+}
+
+impl DeclaredAccess {
+	fn allows(&self, type_id: TypeId, mode: BorrowMode) -> bool {
+		match mode {
+			BorrowMode::Shared => return self.reads.contains(&type_id) || self.writes.contains(&type_id),
+			BorrowMode::Exclusive => return self.writes.contains(&type_id),
+		}
+	}
+}
+
+//Change ticks for the running system. Anything added or changed after last_run is new to it and
+//anything it adds or changes gets stamped with this_run.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct SystemTicks {
+	pub last_run: u64,
+	pub this_run: u64,
 }
 
-pub trait ComponentHashMap {
+pub trait ComponentHashMap: Send + Sync {
 	fn as_any(&self) -> & dyn Any;
 	
 	fn as_any_mut(&mut self) -> &mut dyn Any;
+
+	//Type erased removal so an entity can be removed from every store without knowing the types
+	fn remove(&mut self, id: u64) -> bool;
+
+	fn type_name(&self) -> &'static str;
+
+	fn contains(&self, id: u64) -> Result<bool, EntitySystemError>;
+}
+
+//A RwLock that remembers which system last locked it so a failed borrow can say who is most
+//likely still holding it. Used for both component stores and resources. Borrows never block,
+//if the lock is taken the borrow fails with BorrowConflict.
+pub struct TrackedCell<T> {
+	value: RwLock<T>,
+	borrowed_by: Mutex<Option<&'static str>>,
+}
+
+impl<T> TrackedCell<T> {
+	fn new(value: T) -> Self {
+		return TrackedCell {value: RwLock::new(value), borrowed_by: Mutex::new(None)};
+	}
+
+	fn set_borrowed_by(&self, running_system: Option<&'static str>) {
+		*self.borrowed_by.lock().unwrap_or_else(|e| e.into_inner()) = running_system;
+	}
+
+	fn borrowed_by(&self) -> Option<&'static str> {
+		return *self.borrowed_by.lock().unwrap_or_else(|e| e.into_inner());
+	}
+
+	//name is what to call the contents in the error if the borrow fails. A system that panicked
+	//while holding the lock doesn't make the data any less usable for us so poisoning is ignored
+	fn try_borrow(&self, name: &'static str, running_system: Option<&'static str>) -> Result<RwLockReadGuard<'_, T>, EntitySystemError> {
+		let guard = match self.value.try_read() {
+			Ok(v) => v,
+			Err(TryLockError::Poisoned(e)) => e.into_inner(),
+			Err(TryLockError::WouldBlock) => return Err(EntitySystemError::BorrowConflict {entity: None, component: name, mode: BorrowMode::Shared, held_by: self.borrowed_by()}),
+		};
+
+		self.set_borrowed_by(running_system);
+		return Ok(guard);
+	}
+
+	fn try_borrow_mut(&self, name: &'static str, running_system: Option<&'static str>) -> Result<RwLockWriteGuard<'_, T>, EntitySystemError> {
+		let guard = match self.value.try_write() {
+			Ok(v) => v,
+			Err(TryLockError::Poisoned(e)) => e.into_inner(),
+			Err(TryLockError::WouldBlock) => return Err(EntitySystemError::BorrowConflict {entity: None, component: name, mode: BorrowMode::Exclusive, held_by: self.borrowed_by()}),
+		};
+
+		self.set_borrowed_by(running_system);
+		return Ok(guard);
+	}
+
+	fn get_mut(&mut self) -> &mut T {
+		return self.value.get_mut().unwrap_or_else(|e| e.into_inner());
+	}
+
+	fn into_inner(self) -> T {
+		return self.value.into_inner().unwrap_or_else(|e| e.into_inner());
+	}
+}
+
+//Resources that must never leave the thread that created the EntitySystem, like the SDL
+//renderer. They're only handed out on that thread which is what makes it okay to share the rest
+//of the EntitySystem with worker threads. If the EntitySystem gets dropped on another thread
+//they're leaked rather than dropped there.
+struct NonSendResources {
+	owner: ThreadId,
+	resources: HashMap<TypeId, Box<dyn Any>>, //Each one stored in a RefCell
+}
+
+unsafe impl Send for NonSendResources {}
+unsafe impl Sync for NonSendResources {}
+
+impl NonSendResources {
+	fn check_thread<ResourceType: 'static>(&self) -> Result<(), EntitySystemError> {
+		if thread::current().id() != self.owner {
+			return Err(EntitySystemError::WrongThread {resource: std::any::type_name::<ResourceType>()});
+		}
+
+		return Ok(());
+	}
+}
+
+impl Drop for NonSendResources {
+	fn drop(&mut self) {
+		if thread::current().id() != self.owner && !self.resources.is_empty() {
+			println!("Warning: leaking {} non-send resources, the EntitySystem was dropped on a different thread to the one that created it", self.resources.len());
+			std::mem::forget(std::mem::take(&mut self.resources));
+		}
+	}
+}
+
+//Called with the entity a component was added to or removed from. Changes have to go through
+//the commands since the EntitySystem is in the middle of being changed.
+pub type ComponentHook = Box<dyn Fn(Entity, &Commands) + Send + Sync>;
+
+struct ComponentHooks {
+	on_add: ComponentHook,
+	on_remove: ComponentHook,
 }
 
-impl<T: 'static> ComponentHashMap for RefCell<HashMap<u64, T>> {
+//Called after restore and load swap the world out from under everything, for state that's kept in
+//step with the world through change detection e.g. a lookup of where everything is
+pub type ReloadHook = Box<dyn Fn(&EntitySystem) -> Result<(), EntitySystemError> + Send + Sync>;
+
+//Every component of one type
+pub type ComponentStore<T> = TrackedCell<SparseSet<T>>;
+
+impl<T: Component> ComponentHashMap for ComponentStore<T> {
 	fn as_any(&self) -> &dyn Any {
 		return self as &dyn Any;
 	}
@@ -47,185 +327,1748 @@ impl<T: 'static> ComponentHashMap for RefCell<HashMap<u64, T>> {
 	fn as_any_mut(&mut self) -> &mut dyn Any {
 		return self as &mut dyn Any;
 	}
+
+	fn remove(&mut self, id: u64) -> bool {
+		return self.get_mut().remove(id).is_some();
+	}
+
+	fn type_name(&self) -> &'static str {
+		return std::any::type_name::<T>();
+	}
+
+	fn contains(&self, id: u64) -> Result<bool, EntitySystemError> {
+		return Ok(self.try_borrow(std::any::type_name::<T>(), None)?.contains(id));
+	}
 }
 
-#[derive(Copy, Clone, PartialEq, Debug)]
+//An entity is an index into the component stores plus the generation of that index. When an
+//entity is removed its index is recycled with a bumped generation so any handles still held
+//for the old entity are detected as stale instead of silently pointing at the new one
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
 pub struct Entity {
-	id: u64,
+	index: u64,
+	generation: u32,
+}
+
+impl Entity {
+	pub fn index(&self) -> u64 {
+		return self.index;
+	}
+
+	pub fn generation(&self) -> u32 {
+		return self.generation;
+	}
 }
 
 impl Display for Entity {
 	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-		return write!(f, "id:{}", self.id);
+		return write!(f, "id:{}, gen:{}", self.index, self.generation);
+	}
+}
+
+//Indices reserved from a shared reference. Each running system reserves from its own lane of
+//the indices after next_index, lane n getting next_index + n, next_index + n + lanes and so on,
+//so the indices a system gets don't depend on which thread got to the lock first. Lane 0 is code
+//outside of a system. While no schedule is running there's only lane 0 and it takes from the end
+//of free_indices first.
+struct Reservations {
+	lanes: u64,
+	from_free: usize,
+	taken: Vec<u64>, //How many each lane has handed out
+}
+
+impl Reservations {
+	fn with_lanes(lanes: u64) -> Self {
+		return Reservations {lanes: lanes.max(1), from_free: 0, taken: vec![0; lanes.max(1) as usize]};
+	}
+}
+
+impl Default for Reservations {
+	fn default() -> Self {
+		return Reservations::with_lanes(1);
 	}
 }
 
-pub struct EntitySystem<'a> {
-	next_id: u64,
+pub struct EntitySystem {
+	next_index: u64,
+	generations: Vec<u32>, //Current generation of every index handed out so far
+	free_indices: Vec<u64>, //Indices of removed entities waiting to be reused
+	reserved: Mutex<Reservations>, //Handed out by reserve_entity but not taken yet
     entities: HashMap<u64, String>,
-	entity_names: HashMap<String, u64>,
+	entity_names: HashMap<String, Vec<u64>>, //Names aren't unique, indices in the order they were created
+	tags: HashMap<String, Vec<u64>>, //Same for tags, an entity can have any number of them
+	entity_tags: HashMap<u64, Vec<String>>, //The other way round so removing an entity only touches its own tags
 	components: HashMap<TypeId, Box<dyn ComponentHashMap>>,
-    canvas: RefCell<Canvas<Window>>,
-    texture: Texture<'a>
+	resources: HashMap<TypeId, Box<dyn Any + Send + Sync>>, //Global state, one of each type stored in a TrackedCell
+	non_send_resources: NonSendResources,
+	command_queue: CommandQueue,
+	events: HashMap<TypeId, Box<dyn EventQueue>>,
+	change_tick: AtomicU64, //Bumped every time a system starts and for every change made outside of one
+	system_ticks: Mutex<HashMap<&'static str, u64>>, //The this_run of each system's last run
+	removed: HashMap<TypeId, Vec<(Entity, u64)>>, //Removed components and when, per type
+	removed_cleared_at: u64,
+	hooks: HashMap<TypeId, ComponentHooks>,
+	reload_hooks: Vec<ReloadHook>,
+	registry: ComponentRegistry,
 }
 
-impl<'a> EntitySystem<'a> {
-	pub fn new(canvas: Canvas<Window>, texture: Texture<'a>) -> EntitySystem<'a> {
-		return EntitySystem {next_id: 0, entities: HashMap::new(), entity_names: HashMap::new(), components: HashMap::new(), canvas: RefCell::new(canvas), texture};
+impl EntitySystem {
+	pub fn new() -> EntitySystem {
+		return EntitySystem {next_index: 0, generations: Vec::new(), free_indices: Vec::new(), reserved: Mutex::new(Reservations::default()), entities: HashMap::new(), entity_names: HashMap::new(), tags: HashMap::new(), entity_tags: HashMap::new(), components: HashMap::new(), resources: HashMap::new(), non_send_resources: NonSendResources {owner: thread::current().id(), resources: HashMap::new()}, command_queue: CommandQueue::new(), events: HashMap::new(), change_tick: AtomicU64::new(0), system_ticks: Mutex::new(HashMap::new()), removed: HashMap::new(), removed_cleared_at: 0, hooks: HashMap::new(), reload_hooks: Vec::new(), registry: ComponentRegistry::new()};
 	}
 
-	pub fn new_entity(&mut self) -> Result<Entity, String> {
+	pub fn new_entity(&mut self) -> Result<Entity, EntitySystemError> {
 		return self.new_entity_with_name("Unknown".to_string());
 	}
 
-	pub fn new_entity_with_name(&mut self, name: String) -> Result<Entity, String> {
-		if self.next_id == u64::MAX {
-			panic!("Overflowing next_id, this should be fixed...");	
-		}
+	pub fn new_entity_with_name(&mut self, name: String) -> Result<Entity, EntitySystemError> {
+		self.flush_reserved();
+
+		let index = match self.free_indices.pop() {
+			Some(i) => i, //The generation was already bumped when the old entity was removed
+			None => {
+				if self.next_index == u64::MAX {
+					return Err(EntitySystemError::OutOfEntities {name});
+				}
+
+				let i = self.next_index;
+				self.next_index += 1;
+				self.generations.push(0);
+				i
+			},
+		};
 
-		let ent = Entity {id: self.next_id};
+		let ent = Entity {index, generation: self.generations[index as usize]};
 
-		self.next_id += 1;
-		self.entity_names.insert(name.clone(), ent.id);
-        self.entities.insert(ent.id, name);
+		self.entity_names.entry(name.clone()).or_default().push(ent.index);
+        self.entities.insert(ent.index, name);
 
 		return Ok(ent);
 	}
 
-	pub fn _remove_entity(&mut self, ent: Entity) -> Result<(), String> {
-        let name = match self.entities.remove(&ent.id) {
-            Some(n) => n,
-            None => return Err(format!("No such entity with id:{}", ent.id)),
-        };
+	//A handle for an entity that doesn't exist yet, so Commands::spawn can give one back straight
+	//away. It only comes alive when spawn_reserved is called with it.
+	pub fn reserve_entity(&self) -> Result<Entity, EntitySystemError> {
+		let mut reserved = self.reserved.lock().unwrap_or_else(|e| e.into_inner());
 
-		match self.entity_names.remove(&name) {
-            Some(_) => (),
-            None => return Err(format!("No such entity name {} but the entity id did exist", name)),
-        }
+		if reserved.lanes == 1 && reserved.from_free < self.free_indices.len() {
+			let index = self.free_indices[self.free_indices.len() - 1 - reserved.from_free];
+			reserved.from_free += 1;
+			return Ok(Entity {index, generation: self.generations[index as usize]});
+		}
 
-		panic!("Removing components not implemented");
+		let lane = RUNNING_LANE.with(|l| l.get()).filter(|l| (*l as u64) < reserved.lanes).unwrap_or(0);
+		let offset = reserved.taken[lane].checked_mul(reserved.lanes).and_then(|o| o.checked_add(lane as u64));
+		let index = match offset.and_then(|o| self.next_index.checked_add(o)) {
+			Some(i) if i < u64::MAX => i,
+			_ => return Err(EntitySystemError::OutOfEntities {name: "Reserved".to_string()}),
+		};
+
+		reserved.taken[lane] += 1;
+		return Ok(Entity {index, generation: 0});
 	}
 
-	pub fn borrow_all_components_of_type<ComponentType: 'static>(&self) -> Result<Ref<HashMap<u64, ComponentType>>, EntitySystemError> {
-		let component_hashmap = match self.components.get(&TypeId::of::<ComponentType>()) {
-			Some(chm) => chm,
-			None => return Err(EntitySystemError::NoSuchComponent(format!("Unknown component type <{}> in store", std::any::type_name::<ComponentType>()))),
-		};
+	//Takes the reserved indices out of the free list for good, has to happen before anything else
+	//touches it or the reservations would point at different indices. Indices between next_index
+	//and the last one reserved that no lane took go on the free list.
+	fn flush_reserved(&mut self) {
+		let lanes = self.reserved.get_mut().unwrap_or_else(|e| e.into_inner()).lanes;
+		let reserved = std::mem::replace(self.reserved.get_mut().unwrap_or_else(|e| e.into_inner()), Reservations::with_lanes(lanes));
+
+		let remaining = self.free_indices.len() - reserved.from_free;
+		self.free_indices.truncate(remaining);
+
+		let used = reserved.taken.iter().enumerate()
+			.filter(|(_, taken)| **taken > 0)
+			.map(|(lane, taken)| (taken - 1) * reserved.lanes + lane as u64 + 1)
+			.max()
+			.unwrap_or(0);
+
+		let start = self.next_index;
+		self.next_index += used;
+		self.generations.resize(self.next_index as usize, 0);
 
-		if let Some(store) = component_hashmap.as_any().downcast_ref::<RefCell<HashMap<u64,ComponentType>>>() {
-			//This will panic if something has already borrowed it. Should probably not panic....
-			return Ok(store.borrow());
+		//Highest first so the lowest gets reused first
+		for offset in (0..used).rev() {
+			let lane = (offset % reserved.lanes) as usize;
+			if offset / reserved.lanes >= reserved.taken[lane] {
+				self.free_indices.push(start + offset);
+			}
 		}
+	}
 
-		return Err(EntitySystemError::DowncastFailed(format!("Unable to downcast ref to expected component type <{}>", std::any::type_name::<ComponentType>())));
+	//The schedule gives every system its own lane while it runs, see Reservations
+	pub(crate) fn set_reservation_lanes(&mut self, lanes: usize) {
+		self.flush_reserved();
+		*self.reserved.get_mut().unwrap_or_else(|e| e.into_inner()) = Reservations::with_lanes(lanes as u64);
 	}
 
-	pub fn borrow_all_components_of_type_mut<ComponentType: 'static>(&self) -> Result<RefMut<HashMap<u64, ComponentType>>, EntitySystemError> {
-		let component_hashmap = match self.components.get(&TypeId::of::<ComponentType>()) {
-			Some(chm) => chm,
-			None => return Err(EntitySystemError::NoSuchComponent(format!("Unknown component type <{}> in store", std::any::type_name::<ComponentType>()))),
-		};
+	//Brings a reserved entity to life with the bundle on it, the same as spawn_with_name
+	pub fn spawn_reserved<B: Bundle>(&mut self, ent: Entity, name: String, bundle: B) -> Result<(), EntitySystemError> {
+		bundle.prepare(self)?;
+		self.flush_reserved();
 
-		if let Some(store) = component_hashmap.as_any().downcast_ref::<RefCell<HashMap<u64,ComponentType>>>() {
-			//This will panic if something has already borrowed it. Should probably not panic....
-			return Ok(store.borrow_mut());
+		match self.generations.get(ent.index as usize) {
+			Some(generation) if *generation == ent.generation && !self.entities.contains_key(&ent.index) && !self.free_indices.contains(&ent.index) => (),
+			Some(generation) => return Err(EntitySystemError::StaleEntity {entity: ent, current_generation: *generation}),
+			None => return Err(EntitySystemError::NoSuchEntity {entity: ent}),
 		}
 
-		return Err(EntitySystemError::DowncastFailed(format!("Unable to downcast ref to expected component type <{}>", std::any::type_name::<ComponentType>())));
+		self.entity_names.entry(name.clone()).or_default().push(ent.index);
+		self.entities.insert(ent.index, name);
+
+		return self.insert_new(ent, bundle);
 	}
 
-    pub fn component_for_entity<ComponentType: 'static, F>(&self, ent: Entity, cb: F) -> Result<(), String> where
-        F: Fn(&ComponentType) -> Result<(), String> {
-		let component_hashmap = match self.components.get(&TypeId::of::<ComponentType>()) {
-			Some(chm) => chm,
-			None => return Err(format!("Unknown component type in store")),
+	//For entities there should only ever be one of, like the player
+	pub fn new_entity_with_unique_name(&mut self, name: String) -> Result<Entity, EntitySystemError> {
+		let count = self.entities_with_name(&name).len();
+		if count > 0 {
+			return Err(EntitySystemError::DuplicateName {name, count});
+		}
+
+		return self.new_entity_with_name(name);
+	}
+
+	//Checks the handle still refers to a live entity
+	pub fn validate_entity(&self, ent: Entity) -> Result<(), EntitySystemError> {
+		let generation = match self.generations.get(ent.index as usize) {
+			Some(g) => *g,
+			None => return Err(EntitySystemError::NoSuchEntity {entity: ent}),
 		};
 
-		if let Some(store) = component_hashmap.as_any().downcast_ref::<RefCell<HashMap<u64,ComponentType>>>() {
-			//This will panic if something has already borrowed it. Should probably not panic....
-			let store = store.borrow();
-            let comp = match store.get(&ent.id) {
-                Some(c) => c,
-                None => return Err(format!("No such component for entity:{}", ent)),
-            };
+		if generation != ent.generation || !self.entities.contains_key(&ent.index) {
+			return Err(EntitySystemError::StaleEntity {entity: ent, current_generation: generation});
+		}
+
+		return Ok(());
+	}
+
+	pub fn is_alive(&self, ent: Entity) -> bool {
+		return self.validate_entity(ent).is_ok();
+	}
 
-            return cb(comp);
+	//Handle for the live entity at an index, useful when all you have is a key from a store
+	pub fn entity_for_index(&self, index: u64) -> Option<Entity> {
+		if !self.entities.contains_key(&index) {
+			return None;
 		}
 
-		return Err(format!("Unable to downcast ref to expected component type"));
-    }
+		return Some(Entity {index, generation: self.generations[index as usize]});
+	}
 
-    pub fn component_for_entity_mut<ComponentType: 'static, F>(&self, ent: Entity, cb: F) -> Result<(), String> where
-        F: Fn(&mut ComponentType) -> Result<(), String> {
-		let component_hashmap = match self.components.get(&TypeId::of::<ComponentType>()) {
-			Some(chm) => chm,
-			None => return Err(format!("Unknown component type in store")),
+	pub fn entities(&self) -> Vec<Entity> {
+		return self.entities.keys().map(|index| Entity {index: *index, generation: self.generations[*index as usize]}).collect();
+	}
+
+	//e.g. es.query::<(&Position, &mut Moveable, Option<&Direction>)>() matches every entity with a
+	//Position and a Moveable and hands over its Direction if it has one
+	pub fn query<Q: QueryData>(&self) -> Result<Query<'_, Q>, EntitySystemError> {
+		return Query::new(self);
+	}
+
+	//Same as query but the entities also have to pass the filter e.g. Without<Position>
+	pub fn query_filtered<Q: QueryData, F: QueryFilter>(&self) -> Result<Query<'_, Q, F>, EntitySystemError> {
+		return Query::new(self);
+	}
+
+	//Removing a parent removes all of its children too. The whole hierarchy is checked first so
+	//it's either all removed or none of it is.
+	pub fn remove_entity(&mut self, ent: Entity) -> Result<(), EntitySystemError> {
+		let subtree = self.subtree_of(ent)?;
+		self.flush_reserved();
+		self.remove_parent(ent)?;
+
+		//Children go before their parents, the same as removing them one at a time would
+		for e in subtree.into_iter().rev() {
+			self.despawn_one(e, true);
+		}
+
+		return Ok(());
+	}
+
+	//ent and every one of its descendants, parents before their children. Fails if any of them
+	//aren't alive or the hierarchy loops back on itself.
+	fn subtree_of(&self, ent: Entity) -> Result<Vec<Entity>, EntitySystemError> {
+		self.validate_entity(ent)?;
+
+		let mut subtree = vec![ent];
+		let mut seen = HashSet::from([ent.index]);
+		let mut next = 0;
+		while next < subtree.len() {
+			let parent = subtree[next];
+			next += 1;
+
+			for child in self.children_of(parent) {
+				self.validate_entity(child)?;
+				if !seen.insert(child.index) {
+					return Err(EntitySystemError::InvalidHierarchy {parent, child});
+				}
+				subtree.push(child);
+			}
+		}
+
+		return Ok(subtree);
+	}
+
+	//Removes a live entity without touching its parent or children
+	//Without tracked, it's as if the entity never existed: no hooks and nothing for removed()
+	fn despawn_one(&mut self, ent: Entity, tracked: bool) {
+        let name = match self.entities.remove(&ent.index) {
+            Some(n) => n,
+            None => return,
+        };
+
+        if let Some(indices) = self.entity_names.get_mut(&name) {
+            indices.retain(|i| *i != ent.index);
+            if indices.is_empty() {
+                self.entity_names.remove(&name);
+            }
+        }
+
+        for tag in self.entity_tags.remove(&ent.index).unwrap_or_default() {
+            self.untag_index(ent.index, &tag);
+        }
+
+        let tick = self.change_tick();
+        let mut removed_types = Vec::new();
+        for (type_id, store) in self.components.iter_mut() {
+            if store.remove(ent.index) && tracked {
+                self.removed.entry(*type_id).or_default().push((ent, tick));
+                removed_types.push(*type_id);
+            }
+        }
+
+        for type_id in removed_types {
+            if let Some(hooks) = self.hooks.get(&type_id) {
+                (hooks.on_remove)(ent, &self.commands());
+            }
+        }
+
+		//Once a generation is exhausted the index is retired rather than wrapping around and
+		//making ancient handles valid again
+		let generation = &mut self.generations[ent.index as usize];
+		if *generation < u32::MAX {
+			*generation += 1;
+			self.free_indices.push(ent.index);
+		}
+	}
+
+	pub fn parent_of(&self, ent: Entity) -> Option<Entity> {
+		let parents = self.borrow_all_components_of_type::<Parent>().ok()?;
+		return parents.get(ent.index).map(|p| p.entity);
+	}
+
+	pub fn children_of(&self, ent: Entity) -> Vec<Entity> {
+		let children = match self.borrow_all_components_of_type::<Children>() {
+			Ok(c) => c,
+			Err(_) => return Vec::new(),
 		};
 
-		if let Some(store) = component_hashmap.as_any().downcast_ref::<RefCell<HashMap<u64,ComponentType>>>() {
-			//This will panic if something has already borrowed it. Should probably not panic....
-			let mut store = store.borrow_mut();
-            let comp = match store.get_mut(&ent.id) {
-                Some(c) => c,
-                None => return Err(format!("No such component for entity:{}", ent)),
-            };
+		match children.get(ent.index) {
+			Some(c) => return c.entities.clone(),
+			None => return Vec::new(),
+		}
+	}
+
+	//Makes child's LocalPosition relative to parent, taking it away from any parent it already had
+	pub fn set_parent(&mut self, child: Entity, parent: Entity) -> Result<(), EntitySystemError> {
+		self.validate_entity(child)?;
+		self.validate_entity(parent)?;
 
-            return cb(comp);
+		//If we find the child walking up from the new parent it would end up its own ancestor
+		let mut ancestor = Some(parent);
+		while let Some(a) = ancestor {
+			if a == child {
+				return Err(EntitySystemError::InvalidHierarchy {parent, child});
+			}
+			ancestor = self.parent_of(a);
 		}
 
-		return Err(format!("Unable to downcast ref to expected component type"));
-    }
+		self.remove_parent(child)?;
+		self.add_component_to_entity(child, Parent {entity: parent})?;
 
-	pub fn add_component_to_entity<ComponentType: 'static>(&mut self, ent: Entity, component: ComponentType) -> Result<(), String> {
-		if !self.components.contains_key(&TypeId::of::<ComponentType>()) {
-			println!("Store doesn't contain component, creating");
-			self.components.insert(TypeId::of::<ComponentType>(), Box::new(RefCell::new(HashMap::<u64, ComponentType>::new())));
+		if self.children_of(parent).is_empty() {
+			return self.add_component_to_entity(parent, Children {entities: vec![child]});
 		}
 
-		let component_hashmap = match self.components.get_mut(&TypeId::of::<ComponentType>()) {
-			Some(chm) => chm,
-			None => return Err(format!("Unknown component in store")),
+		let tick = self.change_tick();
+		if let Some((children, ticks)) = self.component_store_mut::<Children>()?.get_mut().get_mut_with_ticks(parent.index) {
+			children.entities.push(child);
+			ticks.changed = tick;
+		}
+
+		return Ok(());
+	}
+
+	//The child is left where it is, it just stops following its parent
+	pub fn remove_parent(&mut self, child: Entity) -> Result<(), EntitySystemError> {
+		let parent = match self.parent_of(child) {
+			Some(p) => p,
+			None => return Ok(()),
 		};
 
-		if let Some(store) = component_hashmap.as_any_mut().downcast_mut::<RefCell<HashMap<u64,ComponentType>>>() {
-			store.get_mut().insert(ent.id, component);
+		self.remove_component_from_entity::<Parent>(child)?;
+
+		let remaining = self.children_of(parent).into_iter().filter(|c| *c != child).collect::<Vec<Entity>>();
+		if remaining.is_empty() {
+			return self.remove_component_from_entity::<Children>(parent);
+		}
+
+		let tick = self.change_tick();
+		if let Some((children, ticks)) = self.component_store_mut::<Children>()?.get_mut().get_mut_with_ticks(parent.index) {
+			children.entities = remaining;
+			ticks.changed = tick;
 		}
 
 		return Ok(());
 	}
 
-    pub fn _get_entity_for_name(&self, name: String) -> Result<Entity, String> {
-        let id = match self.entity_names.get(&name) {
-            Some(i) => i,
-            None => return Err(format!("No such entity with name:{}", name)),
-        };
+	//Queue changes that need the EntitySystem mutably, e.g. spawning while iterating a query.
+	//They're applied at the end of the current schedule stage or by apply_commands.
+	pub fn commands(&self) -> Commands<'_> {
+		return Commands::new(self, self.running_system());
+	}
 
-        return Ok(Entity {id: *id});
-    }
+	pub(crate) fn command_queue(&self) -> &CommandQueue {
+		return &self.command_queue;
+	}
 
-	pub fn remove_component_from_entity<ComponentType: 'static>(&mut self, ent: Entity) -> Result<(), String> {
-		let component_hashmap = match self.components.get(&TypeId::of::<ComponentType>()) {
-			Some(chm) => chm,
-			None => return Err(format!("Unknown component type in store")),
+	#[cfg(test)]
+	pub fn apply_commands(&mut self) -> Result<(), EntitySystemError> {
+		return self.apply_commands_ordered(&[]);
+	}
+
+	//Applies queued commands grouped by the system that queued them in the order given. Every
+	//command is applied even if an earlier one fails, the first error is returned. Commands queued
+	//while applying (by hooks for example) are applied too.
+	pub fn apply_commands_ordered(&mut self, system_order: &[&'static str]) -> Result<(), EntitySystemError> {
+		let mut first_error = None;
+		while !self.command_queue.is_empty() {
+			let commands = self.command_queue.take_ordered(system_order);
+
+			for command in commands {
+				if let Err(e) = command(self) {
+					println!("Failed to apply command:{}", e);
+					if first_error.is_none() {
+						first_error = Some(e);
+					}
+				}
+			}
+		}
+
+		match first_error {
+			Some(e) => return Err(e),
+			None => return Ok(()),
+		}
+	}
+
+	//Event types have to be registered before they can be sent or read
+	pub fn add_event<EventType: Event>(&mut self) {
+		self.events.entry(TypeId::of::<EventType>()).or_insert_with(|| Box::new(Mutex::new(Events::<EventType>::new())));
+	}
+
+	fn event_queue<EventType: Event>(&self) -> Result<&Mutex<Events<EventType>>, EntitySystemError> {
+		let queue = match self.events.get(&TypeId::of::<EventType>()) {
+			Some(q) => q,
+			None => return Err(EntitySystemError::NoSuchEvent {event: std::any::type_name::<EventType>()}),
 		};
 
-		if let Some(store) = component_hashmap.as_any().downcast_ref::<RefCell<HashMap<u64,ComponentType>>>() {
-			//This will panic if something has already borrowed it. Should probably not panic....
-            match store.borrow_mut().remove(&ent.id) {
-                Some(_) => return Ok(()),
-                None => return Err(format!("No such component for entity!")),
-            }
+		match queue.as_any().downcast_ref::<Mutex<Events<EventType>>>() {
+			Some(q) => return Ok(q),
+			None => return Err(EntitySystemError::DowncastFailed {entity: None, type_name: std::any::type_name::<EventType>()}),
 		}
+	}
 
-		return Err(format!("Unable to downcast ref to expected component type"));
+	pub fn send<EventType: Event>(&self, event: EventType) -> Result<(), EntitySystemError> {
+		self.check_access::<Events<EventType>>(BorrowMode::Exclusive)?;
+		self.event_queue::<EventType>()?.lock().unwrap_or_else(|e| e.into_inner()).send(event);
+		return Ok(());
 	}
 
-    pub fn get_mut_canvas(&self) -> RefMut<Canvas<Window>> {
-        return self.canvas.borrow_mut();
-    }
+	//Every event of the type the running system hasn't read yet. Code outside of a system shares
+	//a single reader.
+	pub fn read<EventType: Event>(&self) -> Result<Vec<EventType>, EntitySystemError> {
+		self.check_access::<Events<EventType>>(BorrowMode::Shared)?;
+		let reader = self.running_system();
+		return Ok(self.event_queue::<EventType>()?.lock().unwrap_or_else(|e| e.into_inner()).read(reader));
+	}
 
-    pub fn get_texture(&self) -> &Texture<'a> {
-        return &self.texture;
-    }
-}
+	//Called once a tick by the schedule, drops events that every reader has had a chance to see
+	pub fn update_events(&mut self) {
+		for (_, queue) in self.events.iter_mut() {
+			queue.update();
+		}
+	}
+
+	//Marks the system as running on this thread until end_system. Borrow errors name it and change
+	//detection compares against the last time it ran. This is per thread so systems running in
+	//parallel each get their own.
+	pub fn begin_system(&self, name: &'static str) {
+		let this_run = self.change_tick.fetch_add(1, Ordering::Relaxed) + 1;
+		let last_run = self.system_ticks.lock().unwrap_or_else(|e| e.into_inner()).get(name).copied().unwrap_or(0);
+
+		RUNNING_SYSTEM.with(|r| r.set(Some(name)));
+		RUNNING_TICKS.with(|t| t.set(Some(SystemTicks {last_run, this_run})));
+	}
+
+	pub fn end_system(&self) {
+		if let (Some(name), Some(ticks)) = (self.running_system(), RUNNING_TICKS.with(|t| t.get())) {
+			self.system_ticks.lock().unwrap_or_else(|e| e.into_inner()).insert(name, ticks.this_run);
+		}
+
+		RUNNING_SYSTEM.with(|r| r.set(None));
+		RUNNING_TICKS.with(|t| t.set(None));
+		RUNNING_ACCESS.with(|a| *a.borrow_mut() = None);
+		RUNNING_LANE.with(|l| l.set(None));
+	}
+
+	//Which lane the running system reserves entities from, see Reservations
+	pub(crate) fn set_running_lane(&self, lane: usize) {
+		RUNNING_LANE.with(|l| l.set(Some(lane)));
+	}
+
+	//Limits the running system to borrowing what it declared until end_system. Systems that
+	//never call this (or code outside of a system) can borrow anything.
+	pub fn restrict_access(&self, access: DeclaredAccess) {
+		RUNNING_ACCESS.with(|a| *a.borrow_mut() = Some(access));
+	}
+
+	fn check_access<T: 'static>(&self, mode: BorrowMode) -> Result<(), EntitySystemError> {
+		let allowed = RUNNING_ACCESS.with(|a| match &*a.borrow() {
+			Some(access) => access.allows(TypeId::of::<T>(), mode),
+			None => true,
+		});
+
+		if !allowed {
+			return Err(EntitySystemError::UndeclaredAccess {entity: None, component: std::any::type_name::<T>(), mode, system: self.running_system().unwrap_or("Unknown")});
+		}
+
+		return Ok(());
+	}
+
+	pub fn running_system(&self) -> Option<&'static str> {
+		return RUNNING_SYSTEM.with(|r| r.get());
+	}
+
+	//Code outside of a system sees everything as new and its changes are stamped after the last
+	//tick handed out, so systems that already ran still pick them up next time. Only reading
+	//doesn't move the tick on.
+	pub fn system_ticks(&self) -> SystemTicks {
+		match RUNNING_TICKS.with(|t| t.get()) {
+			Some(ticks) => return ticks,
+			None => return SystemTicks {last_run: 0, this_run: self.change_tick.load(Ordering::Relaxed) + 1},
+		}
+	}
+
+	//What to stamp a change made right now with, outside of a system every change gets its own
+	fn change_tick(&self) -> u64 {
+		match RUNNING_TICKS.with(|t| t.get()) {
+			Some(ticks) => return ticks.this_run,
+			None => return self.change_tick.fetch_add(1, Ordering::Relaxed) + 1,
+		}
+	}
 
+	//Entities that had a T removed (or were removed) since the running system last ran. Removals
+	//are kept until the end of the schedule run after the one they happened in.
+	pub fn removed<ComponentType: Component>(&self) -> Vec<Entity> {
+		let last_run = self.system_ticks().last_run;
+		match self.removed.get(&TypeId::of::<ComponentType>()) {
+			Some(removed) => return removed.iter().filter(|(_, tick)| *tick > last_run).map(|(ent, _)| *ent).collect(),
+			None => return Vec::new(),
+		}
+	}
+
+	//Called once a tick by the schedule, drops removals from before the last call
+	pub fn clear_trackers(&mut self) {
+		let cleared_at = self.removed_cleared_at;
+		for (_, removed) in self.removed.iter_mut() {
+			removed.retain(|(_, tick)| *tick > cleared_at);
+		}
+
+		self.removed_cleared_at = *self.change_tick.get_mut();
+	}
+
+	fn component_store<ComponentType: Component>(&self) -> Result<&ComponentStore<ComponentType>, EntitySystemError> {
+		let component_hashmap = match self.components.get(&TypeId::of::<ComponentType>()) {
+			Some(chm) => chm,
+			None => return Err(EntitySystemError::NoSuchComponent {entity: None, component: std::any::type_name::<ComponentType>().to_string()}),
+		};
+
+		match component_hashmap.as_any().downcast_ref::<ComponentStore<ComponentType>>() {
+			Some(store) => return Ok(store),
+			None => return Err(EntitySystemError::DowncastFailed {entity: None, type_name: std::any::type_name::<ComponentType>()}),
+		}
+	}
+
+	fn component_store_mut<ComponentType: Component>(&mut self) -> Result<&mut ComponentStore<ComponentType>, EntitySystemError> {
+		let component_hashmap = match self.components.get_mut(&TypeId::of::<ComponentType>()) {
+			Some(chm) => chm,
+			None => return Err(EntitySystemError::NoSuchComponent {entity: None, component: std::any::type_name::<ComponentType>().to_string()}),
+		};
+
+		match component_hashmap.as_any_mut().downcast_mut::<ComponentStore<ComponentType>>() {
+			Some(store) => return Ok(store),
+			None => return Err(EntitySystemError::DowncastFailed {entity: None, type_name: std::any::type_name::<ComponentType>()}),
+		}
+	}
+
+	//None drops the store entirely, used to put back snapshots
+	pub(crate) fn replace_component_store<ComponentType: Component>(&mut self, store: Option<SparseSet<ComponentType>>) {
+		match store {
+			Some(s) => {
+				self.components.insert(TypeId::of::<ComponentType>(), Box::new(ComponentStore::<ComponentType>::new(s)));
+			},
+			None => {
+				self.components.remove(&TypeId::of::<ComponentType>());
+			},
+		}
+	}
+
+	pub fn borrow_all_components_of_type<ComponentType: Component>(&self) -> Result<RwLockReadGuard<'_, SparseSet<ComponentType>>, EntitySystemError> {
+		self.check_access::<ComponentType>(BorrowMode::Shared)?;
+		return self.component_store::<ComponentType>()?.try_borrow(std::any::type_name::<ComponentType>(), self.running_system());
+	}
+
+	pub fn borrow_all_components_of_type_mut<ComponentType: Component>(&self) -> Result<RwLockWriteGuard<'_, SparseSet<ComponentType>>, EntitySystemError> {
+		self.check_access::<ComponentType>(BorrowMode::Exclusive)?;
+		return self.component_store::<ComponentType>()?.try_borrow_mut(std::any::type_name::<ComponentType>(), self.running_system());
+	}
+
+    //Runs f on the entity's T and hands back whatever f returns
+    pub fn with_component<ComponentType: Component, R, F>(&self, ent: Entity, f: F) -> Result<R, EntitySystemError> where
+        F: FnOnce(&ComponentType) -> R {
+		let comp = self.get::<ComponentType>(ent)?;
+        return Ok(f(&comp));
+    }
+
+    //There's no telling if f actually changes the component so it's always marked as changed
+    pub fn with_component_mut<ComponentType: Component, R, F>(&self, ent: Entity, f: F) -> Result<R, EntitySystemError> where
+        F: FnOnce(&mut ComponentType) -> R {
+		let mut comp = self.get_mut::<ComponentType>(ent)?;
+        return Ok(f(&mut comp));
+    }
+
+    pub fn component_for_entity<ComponentType: Component, F>(&self, ent: Entity, cb: F) -> Result<(), EntitySystemError> where
+        F: Fn(&ComponentType) -> Result<(), EntitySystemError> {
+        return self.with_component(ent, cb)?;
+    }
+
+    pub fn component_for_entity_mut<ComponentType: Component, F>(&self, ent: Entity, cb: F) -> Result<(), EntitySystemError> where
+        F: Fn(&mut ComponentType) -> Result<(), EntitySystemError> {
+        return self.with_component_mut(ent, cb)?;
+    }
+
+	//Borrows the whole store of T for as long as the Ref is alive
+	pub fn get<ComponentType: Component>(&self, ent: Entity) -> Result<query::Ref<'_, ComponentType>, EntitySystemError> {
+		self.validate_entity(ent)?;
+
+		let store = self.borrow_all_components_of_type::<ComponentType>().map_err(|e| e.for_entity(ent))?;
+		if !store.contains(ent.index) {
+			return Err(EntitySystemError::NoSuchComponent {entity: Some(ent), component: std::any::type_name::<ComponentType>().to_string()});
+		}
+
+		return Ok(query::Ref::new(store, ent.index));
+	}
+
+	pub fn get_mut<ComponentType: Component>(&self, ent: Entity) -> Result<query::RefMut<'_, ComponentType>, EntitySystemError> {
+		self.validate_entity(ent)?;
+
+		let store = self.borrow_all_components_of_type_mut::<ComponentType>().map_err(|e| e.for_entity(ent))?;
+		if !store.contains(ent.index) {
+			return Err(EntitySystemError::NoSuchComponent {entity: Some(ent), component: std::any::type_name::<ComponentType>().to_string()});
+		}
+
+		return Ok(query::RefMut::new(store, ent.index, self.change_tick()));
+	}
+
+	//Creates the store for T if there isn't one yet and checks it really holds T's
+	pub(crate) fn prepare_component_store<ComponentType: Component>(&mut self) -> Result<(), EntitySystemError> {
+		if !self.components.contains_key(&TypeId::of::<ComponentType>()) {
+			println!("Store doesn't contain component, creating");
+			self.components.insert(TypeId::of::<ComponentType>(), Box::new(ComponentStore::<ComponentType>::new(SparseSet::new())));
+		}
+
+		self.component_store_mut::<ComponentType>()?;
+		return Ok(());
+	}
+
+	#[cfg(test)]
+	pub fn spawn<B: Bundle>(&mut self, bundle: B) -> Result<Entity, EntitySystemError> {
+		return self.spawn_with_name("Unknown".to_string(), bundle);
+	}
+
+	//Nothing is created if the bundle can't be added, the hooks only run once all of it is in
+	pub fn spawn_with_name<B: Bundle>(&mut self, name: String, bundle: B) -> Result<Entity, EntitySystemError> {
+		bundle.prepare(self)?;
+
+		let ent = self.new_entity_with_name(name)?;
+		self.insert_new(ent, bundle)?;
+		return Ok(ent);
+	}
+
+	//Puts the bundle on an entity that was only just created, getting rid of the entity again if
+	//it fails. No hooks ran for it so nothing else knows it was ever there.
+	fn insert_new<B: Bundle>(&mut self, ent: Entity, bundle: B) -> Result<(), EntitySystemError> {
+		let mut inserted = Inserted::new();
+		match bundle.insert(self, ent, &mut inserted) {
+			Ok(()) => {
+				self.run_add_hooks(ent, inserted.added());
+				return Ok(());
+			},
+			Err(e) => {
+				self.despawn_one(ent, false);
+				return Err(e);
+			},
+		}
+	}
+
+	//Adds every component in the bundle, replacing any the entity already had. Either all of them
+	//are added or, if it returns an error, the entity is left as it was, with any components the
+	//bundle replaced put back.
+	pub fn insert<B: Bundle>(&mut self, ent: Entity, bundle: B) -> Result<(), EntitySystemError> {
+		self.validate_entity(ent)?;
+		bundle.prepare(self).map_err(|e| e.for_entity(ent))?;
+
+		let mut inserted = Inserted::new();
+		match bundle.insert(self, ent, &mut inserted) {
+			Ok(()) => {
+				self.run_add_hooks(ent, inserted.added());
+				return Ok(());
+			},
+			Err(e) => {
+				inserted.undo(self, ent);
+				return Err(e);
+			},
+		}
+	}
+
+	pub fn add_component_to_entity<ComponentType: Component>(&mut self, ent: Entity, component: ComponentType) -> Result<(), EntitySystemError> {
+		if self.store_component(ent, component)?.is_none() {
+			self.run_hook::<ComponentType>(ent, |hooks| &hooks.on_add);
+		}
+
+		return Ok(());
+	}
+
+	//add_component_to_entity without the hook. Hands back the component it replaced, if the
+	//entity already had one, along with its ticks so unstore_component can put it back.
+	pub(crate) fn store_component<ComponentType: Component>(&mut self, ent: Entity, component: ComponentType) -> Result<Option<(ComponentType, ComponentTicks)>, EntitySystemError> {
+		self.validate_entity(ent)?;
+
+		self.prepare_component_store::<ComponentType>()?;
+
+		let tick = self.change_tick();
+		let store = self.component_store_mut::<ComponentType>()?.get_mut();
+		let ticks = store.ticks(ent.index);
+		let replaced = store.insert(ent.index, component, tick);
+		return Ok(replaced.zip(ticks));
+	}
+
+	//Undoes store_component, either putting back what it replaced as it was or taking the
+	//component away again as if it had never been there
+	pub(crate) fn unstore_component<ComponentType: Component>(&mut self, ent: Entity, replaced: Option<(ComponentType, ComponentTicks)>) {
+		let store = match self.component_store_mut::<ComponentType>() {
+			Ok(s) => s.get_mut(),
+			Err(_) => return,
+		};
+
+		match replaced {
+			Some((component, ticks)) => {
+				store.insert(ent.index, component, ticks.added);
+				if let Some((_, t)) = store.get_mut_with_ticks(ent.index) {
+					*t = ticks;
+				}
+			},
+			None => {
+				store.remove(ent.index);
+			},
+		}
+	}
+
+	fn run_add_hooks(&self, ent: Entity, added: &[TypeId]) {
+		for type_id in added {
+			if let Some(hooks) = self.hooks.get(type_id) {
+				(hooks.on_add)(ent, &self.commands());
+			}
+		}
+	}
+
+	//on_add runs whenever an entity gets a T it didn't already have, on_remove whenever it loses
+	//one including when the entity is removed. Registering again replaces the old hooks.
+	#[cfg(test)]
+	pub fn register_hooks<ComponentType: Component, A, R>(&mut self, on_add: A, on_remove: R) where
+		A: Fn(Entity, &Commands) + Send + Sync + 'static,
+		R: Fn(Entity, &Commands) + Send + Sync + 'static {
+		self.hooks.insert(TypeId::of::<ComponentType>(), ComponentHooks {on_add: Box::new(on_add), on_remove: Box::new(on_remove)});
+	}
+
+	fn run_hook<ComponentType: Component>(&self, ent: Entity, which: fn(&ComponentHooks) -> &ComponentHook) {
+		if let Some(hooks) = self.hooks.get(&TypeId::of::<ComponentType>()) {
+			which(hooks)(ent, &self.commands());
+		}
+	}
+
+	//The one entity with the name, it's an error if there's more than one
+	pub fn get_entity_for_name(&self, name: &str) -> Result<Entity, EntitySystemError> {
+		let entities = self.entities_with_name(name);
+		match entities.len() {
+			0 => return Err(EntitySystemError::NoSuchName {name: name.to_string()}),
+			1 => return Ok(entities[0]),
+			count => return Err(EntitySystemError::DuplicateName {name: name.to_string(), count}),
+		}
+	}
+
+	//Oldest first
+	pub fn entities_with_name(&self, name: &str) -> Vec<Entity> {
+		match self.entity_names.get(name) {
+			Some(indices) => return indices.iter().filter_map(|i| self.entity_for_index(*i)).collect(),
+			None => return Vec::new(),
+		}
+	}
+
+	pub fn name_of(&self, ent: Entity) -> Option<&str> {
+		self.validate_entity(ent).ok()?;
+		return self.entities.get(&ent.index).map(|n| n.as_str());
+	}
+
+	//Lets the component be built from data in prefabs, saved and snapshotted, name is what it's
+	//called in the files
+	pub fn register_component<ComponentType: Component + Clone + Serialize + DeserializeOwned>(&mut self, name: &str) {
+		self.registry.register::<ComponentType>(name);
+	}
+
+	//Same as register_component but building it from data goes through deserialize
+	pub fn register_component_with<ComponentType: Component + Clone + Serialize>(&mut self, name: &str, deserialize: DeserializeFn) {
+		self.registry.register_with::<ComponentType>(name, deserialize);
+	}
+
+	//Makes snapshot copy the resource and restore put it back
+	pub fn register_snapshot_resource<ResourceType: Resource + Clone>(&mut self) {
+		self.registry.register_resource::<ResourceType>();
+	}
+
+	pub fn component_registry(&self) -> &ComponentRegistry {
+		return &self.registry;
+	}
+
+	//Every entity and registered component, components that aren't registered are skipped with a
+	//warning. Resources and events aren't saved.
+	pub fn save(&self) -> Result<WorldSave, EntitySystemError> {
+		let mut saved = BTreeMap::new();
+		for (index, name) in self.entities.iter() {
+			let tags = self.entity_for_index(*index).map_or(Vec::new(), |e| self.tags_of(e).iter().map(|t| t.to_string()).collect());
+			saved.insert(*index, SavedEntity {index: *index, generation: self.generations[*index as usize], name: name.clone(), tags, components: serde_json::Map::new()});
+		}
+
+		for (type_id, store) in self.components.iter() {
+			let name = match self.registry.name_of(*type_id) {
+				Some(n) => n,
+				None => {
+					println!("Skipping unregistered component <{}> while saving", store.type_name());
+					continue;
+				},
+			};
+
+			let registered = match self.registry.get(name) {
+				Some(r) => r,
+				None => continue,
+			};
+
+			for (index, value) in (registered.serialize)(self)? {
+				if let Some(ent) = saved.get_mut(&index) {
+					ent.components.insert(name.to_string(), value);
+				}
+			}
+		}
+
+		return Ok(WorldSave {next_index: self.next_index, generations: self.generations.clone(), free_indices: self.free_indices.clone(), entities: saved.into_values().collect()});
+	}
+
+	pub fn save_world(&self, path: &Path) -> Result<(), EntitySystemError> {
+		return self.save()?.write(path);
+	}
+
+	//Only into an EntitySystem without any entities, it needs the same components registered as
+	//the one that saved. Unregistered components are skipped with a warning. Everything is loaded
+	//into a separate EntitySystem first so if anything's wrong with the save this one is left
+	//empty. The reload hooks run once it's in.
+	pub fn load(&mut self, save: WorldSave) -> Result<(), EntitySystemError> {
+		if !self.entities.is_empty() {
+			return Err(EntitySystemError::InvalidSave {reason: format!("Can only load into an empty EntitySystem, this one has {} entities", self.entities.len())});
+		}
+		validate_save(&save)?;
+
+		let mut loaded = EntitySystem::new();
+		loaded.registry = self.registry.clone();
+		*loaded.change_tick.get_mut() = *self.change_tick.get_mut(); //So systems see everything loaded as added
+		loaded.next_index = save.next_index;
+		loaded.generations = save.generations;
+		loaded.free_indices = save.free_indices;
+
+		let mut entities = Vec::new();
+		for mut saved in save.entities {
+			loaded.entity_names.entry(saved.name.clone()).or_default().push(saved.index);
+			loaded.entities.insert(saved.index, saved.name);
+			let ent = Entity {index: saved.index, generation: saved.generation};
+
+			for tag in saved.tags.iter() {
+				loaded.add_tag(ent, tag)?;
+			}
+
+			for name in saved.components.keys() {
+				if loaded.registry.get(name).is_none() {
+					println!("Skipping unregistered component {} while loading entity {}", name, ent);
+				}
+			}
+			entities.push((ent, std::mem::take(&mut saved.components)));
+		}
+
+		//Components go in once every entity exists so ones referring to other entities are valid,
+		//a type at a time in name order so Children lists are put back in the order they were saved
+		for registered_name in loaded.registry.names().iter().map(|n| n.to_string()).collect::<Vec<String>>() {
+			let registered = match loaded.registry.get(&registered_name) {
+				Some(r) => r,
+				None => continue,
+			};
+
+			for (ent, components) in entities.iter_mut() {
+				if let Some(data) = components.remove(&registered_name) {
+					(registered.deserialize)(&mut loaded, *ent, data)?;
+				}
+			}
+		}
+
+		//Nothing went wrong so swap everything loaded in
+		*self.reserved.get_mut().unwrap_or_else(|e| e.into_inner()) = Reservations::default();
+		self.next_index = loaded.next_index;
+		self.generations = std::mem::take(&mut loaded.generations);
+		self.free_indices = std::mem::take(&mut loaded.free_indices);
+		self.entities = std::mem::take(&mut loaded.entities);
+		self.entity_names = std::mem::take(&mut loaded.entity_names);
+		self.tags = std::mem::take(&mut loaded.tags);
+		self.entity_tags = std::mem::take(&mut loaded.entity_tags);
+		self.components = std::mem::take(&mut loaded.components);
+		*self.change_tick.get_mut() = *loaded.change_tick.get_mut();
+
+		self.run_reload_hooks()?;
+
+		//Hooks couldn't run in the other EntitySystem, every component loaded counts as added
+		let mut loaded_entities = self.entities();
+		loaded_entities.sort_by_key(|e| e.index);
+		for (type_id, hooks) in self.hooks.iter() {
+			let store = match self.components.get(type_id) {
+				Some(s) => s,
+				None => continue,
+			};
+
+			for ent in loaded_entities.iter() {
+				if store.contains(ent.index).map_err(|e| e.for_entity(*ent))? {
+					(hooks.on_add)(*ent, &self.commands());
+				}
+			}
+		}
+
+		return Ok(());
+	}
+
+	pub fn load_world(&mut self, path: &Path) -> Result<(), EntitySystemError> {
+		let save = WorldSave::read(path)?;
+		return self.load(save);
+	}
+
+	//Makes the component show up in components_of and editable by field name
+	pub fn register_reflect<ComponentType: Component + Reflect>(&mut self) {
+		self.registry.register_reflect::<ComponentType>();
+	}
+
+	//Shows up in components_of but reflect_component_mut refuses to edit it, for components that
+	//have to be changed through their own methods
+	pub fn register_reflect_read_only<ComponentType: Component + Reflect>(&mut self) {
+		self.registry.register_reflect_read_only::<ComponentType>();
+	}
+
+	//The full type name of every component the entity has, sorted
+	#[cfg(test)]
+	pub fn component_type_names(&self, ent: Entity) -> Result<Vec<&'static str>, EntitySystemError> {
+		self.validate_entity(ent)?;
+
+		let mut names = Vec::new();
+		for (_, store) in self.components.iter() {
+			if store.contains(ent.index).map_err(|e| e.for_entity(ent))? {
+				names.push(store.type_name());
+			}
+		}
+
+		names.sort_unstable();
+		return Ok(names);
+	}
+
+	//Calls f with every reflected component on the entity, in order of type name. Components
+	//without reflection registered are skipped, component_type_names lists everything.
+	#[cfg(test)]
+	pub fn components_of<F>(&self, ent: Entity, mut f: F) -> Result<(), EntitySystemError> where
+		F: FnMut(&dyn Reflect) {
+		self.validate_entity(ent)?;
+
+		for (_, type_id) in self.sorted_component_types() {
+			if let Some(reflected) = self.registry.reflected(type_id) {
+				(reflected.reflect)(self, ent, &mut f)?;
+			}
+		}
+
+		return Ok(());
+	}
+
+	//Finds the entity's component with the Reflect type name (e.g. "Position") for editing. Errors
+	//if the entity doesn't have one or it was registered read only.
+	pub fn reflect_component_mut<F>(&self, ent: Entity, type_name: &str, mut f: F) -> Result<(), EntitySystemError> where
+		F: FnMut(&mut dyn Reflect) -> Result<(), EntitySystemError> {
+		self.validate_entity(ent)?;
+
+		for (full_type_name, type_id) in self.sorted_component_types() {
+			let reflected = match self.registry.reflected(type_id) {
+				Some(r) => r,
+				None => continue,
+			};
+
+			//Check the name with a shared borrow first so only the matching store gets locked mutably
+			let mut matches = false;
+			(reflected.reflect)(self, ent, &mut |c| matches = c.type_name() == type_name)?;
+			if !matches {
+				continue;
+			}
+
+			let reflect_mut = match reflected.reflect_mut {
+				Some(r) => r,
+				None => return Err(EntitySystemError::ReadOnlyComponent {entity: ent, component: full_type_name}),
+			};
+
+			let mut result = Ok(());
+			reflect_mut(self, ent, &mut |c| result = f(c))?;
+			return result;
+		}
+
+		return Err(EntitySystemError::NoSuchComponent {entity: Some(ent), component: type_name.to_string()});
+	}
+
+	//The entity's name, tags and every component's values, reflected components show their fields
+	//and anything else just its type name e.g.
+	//id:0, gen:0 "Player" [player]
+	//    Position {x: 0.0, y: 0.0}
+	pub fn describe(&self, ent: Entity) -> Result<String, EntitySystemError> {
+		self.validate_entity(ent)?;
+
+		let name = self.entities.get(&ent.index).map_or("", |n| n.as_str());
+		let mut description = format!("{} \"{}\" [{}]\n", ent, name, self.tags_of(ent).join(", "));
+
+		for (type_name, type_id) in self.sorted_component_types() {
+			let reflected = match self.registry.reflected(type_id) {
+				Some(r) => r,
+				None => {
+					if self.components[&type_id].contains(ent.index).map_err(|e| e.for_entity(ent))? {
+						description.push_str(&format!("    {}\n", type_name));
+					}
+					continue;
+				},
+			};
+
+			(reflected.reflect)(self, ent, &mut |c| description.push_str(&format!("    {} {{{}}}\n", c.type_name(), c.describe())))?;
+		}
+
+		return Ok(description);
+	}
+
+	//describe for every entity in index order
+	pub fn dump_world(&self) -> Result<String, EntitySystemError> {
+		let mut indices = self.entities.keys().copied().collect::<Vec<u64>>();
+		indices.sort_unstable();
+
+		let mut dump = format!("{} entities\n", indices.len());
+		for index in indices {
+			if let Some(ent) = self.entity_for_index(index) {
+				dump.push_str(&self.describe(ent)?);
+			}
+		}
+
+		return Ok(dump);
+	}
+
+	fn sorted_component_types(&self) -> Vec<(&'static str, TypeId)> {
+		let mut types = self.components.iter().map(|(type_id, store)| (store.type_name(), *type_id)).collect::<Vec<(&'static str, TypeId)>>();
+		types.sort_unstable_by_key(|(name, _)| *name);
+		return types;
+	}
+
+	//Copies the entities, registered components, resources registered with
+	//register_snapshot_resource, events and change ticks. Queued commands aren't included so take
+	//it between ticks, after they've been applied. Unregistered components and resources aren't
+	//copied either, see restore.
+	pub fn snapshot(&self) -> Result<WorldSnapshot, EntitySystemError> {
+		let mut components = HashMap::new();
+		for registered in self.registry.all() {
+			if let Some(store) = (registered.snapshot)(self)? {
+				components.insert(registered.type_id, store);
+			}
+		}
+
+		let mut resources = HashMap::new();
+		for registered in self.registry.resources() {
+			if let Some(resource) = (registered.snapshot)(self)? {
+				resources.insert(registered.type_id, resource);
+			}
+		}
+
+		return Ok(WorldSnapshot {
+			next_index: self.next_index,
+			generations: self.generations.clone(),
+			free_indices: self.free_indices.clone(),
+			entities: self.entities.clone(),
+			entity_names: self.entity_names.clone(),
+			tags: self.tags.clone(),
+			entity_tags: self.entity_tags.clone(),
+			components,
+			resources,
+			events: self.events.iter().map(|(t, e)| (*t, e.clone_queue())).collect(),
+			removed: self.removed.clone(),
+			removed_cleared_at: self.removed_cleared_at,
+			change_tick: self.change_tick.load(Ordering::Relaxed),
+			system_ticks: self.system_ticks.lock().unwrap_or_else(|e| e.into_inner()).clone(),
+		});
+	}
+
+	//Puts the EntitySystem back how it was when the snapshot was taken so running the same systems
+	//again gives the same results. Component hooks don't run, the reload hooks do. Only what
+	//snapshot copied comes back, everything else is left as it is now: unregistered resources,
+	//commands queued since, event types added since and unregistered components, which are dropped
+	//from entities that didn't exist when the snapshot was taken.
+	pub fn restore(&mut self, snapshot: &WorldSnapshot) -> Result<(), EntitySystemError> {
+		self.flush_reserved();
+
+		for registered in self.registry.all() {
+			(registered.restore)(self, snapshot.components.get(&registered.type_id).map(|s| s.as_ref()))?;
+		}
+
+		for registered in self.registry.resources() {
+			(registered.restore)(self, snapshot.resources.get(&registered.type_id).map(|r| r.as_ref()))?;
+		}
+		for (type_id, events) in snapshot.events.iter() {
+			self.events.insert(*type_id, events.clone_queue());
+		}
+
+		self.next_index = snapshot.next_index;
+		self.generations = snapshot.generations.clone();
+		self.free_indices = snapshot.free_indices.clone();
+		self.entities = snapshot.entities.clone();
+		self.entity_names = snapshot.entity_names.clone();
+		self.tags = snapshot.tags.clone();
+		self.entity_tags = snapshot.entity_tags.clone();
+		self.removed = snapshot.removed.clone();
+		self.removed_cleared_at = snapshot.removed_cleared_at;
+		*self.change_tick.get_mut() = snapshot.change_tick;
+		*self.system_ticks.get_mut().unwrap_or_else(|e| e.into_inner()) = snapshot.system_ticks.clone();
+
+		for (type_id, store) in self.components.iter_mut() {
+			if self.registry.name_of(*type_id).is_some() {
+				continue;
+			}
+
+			println!("Unregistered component <{}> can't be restored from a snapshot", store.type_name());
+			let stale = (0..self.next_index).filter(|i| !self.entities.contains_key(i)).collect::<Vec<u64>>();
+			for index in stale {
+				store.remove(index);
+			}
+		}
+
+		return self.run_reload_hooks();
+	}
+
+	//Runs after every restore and load, in the order they were registered
+	pub fn register_reload_hook<F>(&mut self, hook: F) where
+		F: Fn(&EntitySystem) -> Result<(), EntitySystemError> + Send + Sync + 'static {
+		self.reload_hooks.push(Box::new(hook));
+	}
+
+	fn run_reload_hooks(&self) -> Result<(), EntitySystemError> {
+		for hook in self.reload_hooks.iter() {
+			hook(self)?;
+		}
+		return Ok(());
+	}
+
+	//Tags group entities for gameplay code to find e.g. "wall", "spawn_point"
+	pub fn add_tag(&mut self, ent: Entity, tag: &str) -> Result<(), EntitySystemError> {
+		self.validate_entity(ent)?;
+
+		let tags = self.entity_tags.entry(ent.index).or_default();
+		if tags.iter().any(|t| t == tag) {
+			return Ok(());
+		}
+
+		tags.push(tag.to_string());
+		self.tags.entry(tag.to_string()).or_default().push(ent.index);
+
+		return Ok(());
+	}
+
+	#[cfg(test)]
+	pub fn remove_tag(&mut self, ent: Entity, tag: &str) -> Result<(), EntitySystemError> {
+		self.validate_entity(ent)?;
+
+		if let Some(tags) = self.entity_tags.get_mut(&ent.index) {
+			tags.retain(|t| t != tag);
+			if tags.is_empty() {
+				self.entity_tags.remove(&ent.index);
+			}
+		}
+		self.untag_index(ent.index, tag);
+
+		return Ok(());
+	}
+
+	fn untag_index(&mut self, index: u64, tag: &str) {
+		if let Some(indices) = self.tags.get_mut(tag) {
+			indices.retain(|i| *i != index);
+			if indices.is_empty() {
+				self.tags.remove(tag);
+			}
+		}
+	}
+
+	pub fn has_tag(&self, ent: Entity, tag: &str) -> bool {
+		if !self.is_alive(ent) {
+			return false;
+		}
+
+		match self.entity_tags.get(&ent.index) {
+			Some(tags) => return tags.iter().any(|t| t == tag),
+			None => return false,
+		}
+	}
+
+	//In the order they were tagged
+	#[cfg(test)]
+	pub fn entities_with_tag(&self, tag: &str) -> Vec<Entity> {
+		match self.tags.get(tag) {
+			Some(indices) => return indices.iter().filter_map(|i| self.entity_for_index(*i)).collect(),
+			None => return Vec::new(),
+		}
+	}
+
+	//Sorted so it's the same every time
+	pub fn tags_of(&self, ent: Entity) -> Vec<&str> {
+		if !self.is_alive(ent) {
+			return Vec::new();
+		}
+
+		let mut tags = match self.entity_tags.get(&ent.index) {
+			Some(t) => t.iter().map(|t| t.as_str()).collect::<Vec<&str>>(),
+			None => return Vec::new(),
+		};
+		tags.sort_unstable();
+		return tags;
+	}
+
+	pub fn remove_component_from_entity<ComponentType: Component>(&mut self, ent: Entity) -> Result<(), EntitySystemError> {
+		self.validate_entity(ent)?;
+
+		//We have the EntitySystem mutably so nobody else can be holding a borrow of the store
+        match self.component_store_mut::<ComponentType>().map_err(|e| e.for_entity(ent))?.get_mut().remove(ent.index) {
+            Some(_) => {
+                let tick = self.change_tick();
+                self.removed.entry(TypeId::of::<ComponentType>()).or_default().push((ent, tick));
+                self.run_hook::<ComponentType>(ent, |hooks| &hooks.on_remove);
+                return Ok(());
+            },
+            None => return Err(EntitySystemError::NoSuchComponent {entity: Some(ent), component: std::any::type_name::<ComponentType>().to_string()}),
+        }
+	}
+
+	//Returns the resource of the same type that was already stored, if there was one
+	pub fn insert_resource<ResourceType: Resource>(&mut self, resource: ResourceType) -> Option<ResourceType> {
+		let old = self.resources.insert(TypeId::of::<ResourceType>(), Box::new(TrackedCell::new(resource)))?;
+
+		match old.downcast::<TrackedCell<ResourceType>>() {
+			Ok(cell) => return Some(cell.into_inner()),
+			Err(_) => return None,
+		}
+	}
+
+	pub fn remove_resource<ResourceType: Resource>(&mut self) -> Option<ResourceType> {
+		let old = self.resources.remove(&TypeId::of::<ResourceType>())?;
+
+		match old.downcast::<TrackedCell<ResourceType>>() {
+			Ok(cell) => return Some(cell.into_inner()),
+			Err(_) => return None,
+		}
+	}
+
+	#[cfg(test)]
+	pub fn has_resource<ResourceType: Resource>(&self) -> bool {
+		return self.resources.contains_key(&TypeId::of::<ResourceType>());
+	}
+
+	fn resource_cell<ResourceType: Resource>(&self) -> Result<&TrackedCell<ResourceType>, EntitySystemError> {
+		let cell = match self.resources.get(&TypeId::of::<ResourceType>()) {
+			Some(c) => c,
+			None => return Err(EntitySystemError::NoSuchResource {resource: std::any::type_name::<ResourceType>()}),
+		};
+
+		match cell.downcast_ref::<TrackedCell<ResourceType>>() {
+			Some(c) => return Ok(c),
+			None => return Err(EntitySystemError::DowncastFailed {entity: None, type_name: std::any::type_name::<ResourceType>()}),
+		}
+	}
+
+	pub fn resource<ResourceType: Resource>(&self) -> Result<RwLockReadGuard<'_, ResourceType>, EntitySystemError> {
+		self.check_access::<ResourceType>(BorrowMode::Shared)?;
+		return self.resource_cell::<ResourceType>()?.try_borrow(std::any::type_name::<ResourceType>(), self.running_system());
+	}
+
+	pub fn resource_mut<ResourceType: Resource>(&self) -> Result<RwLockWriteGuard<'_, ResourceType>, EntitySystemError> {
+		self.check_access::<ResourceType>(BorrowMode::Exclusive)?;
+		return self.resource_cell::<ResourceType>()?.try_borrow_mut(std::any::type_name::<ResourceType>(), self.running_system());
+	}
+
+	//For resources that can't be sent between threads (anything holding SDL state). They can
+	//only be used on the thread that created the EntitySystem.
+	pub fn insert_non_send_resource<ResourceType: 'static>(&mut self, resource: ResourceType) -> Result<Option<ResourceType>, EntitySystemError> {
+		self.non_send_resources.check_thread::<ResourceType>()?;
+
+		let old = match self.non_send_resources.resources.insert(TypeId::of::<ResourceType>(), Box::new(RefCell::new(resource))) {
+			Some(o) => o,
+			None => return Ok(None),
+		};
+
+		match old.downcast::<RefCell<ResourceType>>() {
+			Ok(cell) => return Ok(Some(cell.into_inner())),
+			Err(_) => return Ok(None),
+		}
+	}
+
+	pub fn remove_non_send_resource<ResourceType: 'static>(&mut self) -> Result<Option<ResourceType>, EntitySystemError> {
+		self.non_send_resources.check_thread::<ResourceType>()?;
+
+		let old = match self.non_send_resources.resources.remove(&TypeId::of::<ResourceType>()) {
+			Some(o) => o,
+			None => return Ok(None),
+		};
+
+		match old.downcast::<RefCell<ResourceType>>() {
+			Ok(cell) => return Ok(Some(cell.into_inner())),
+			Err(_) => return Ok(None),
+		}
+	}
+
+	fn non_send_resource_cell<ResourceType: 'static>(&self) -> Result<&RefCell<ResourceType>, EntitySystemError> {
+		self.non_send_resources.check_thread::<ResourceType>()?;
+
+		let cell = match self.non_send_resources.resources.get(&TypeId::of::<ResourceType>()) {
+			Some(c) => c,
+			None => return Err(EntitySystemError::NoSuchResource {resource: std::any::type_name::<ResourceType>()}),
+		};
+
+		match cell.downcast_ref::<RefCell<ResourceType>>() {
+			Some(c) => return Ok(c),
+			None => return Err(EntitySystemError::DowncastFailed {entity: None, type_name: std::any::type_name::<ResourceType>()}),
+		}
+	}
+
+	#[cfg(test)]
+	pub fn non_send_resource<ResourceType: 'static>(&self) -> Result<std::cell::Ref<'_, ResourceType>, EntitySystemError> {
+		self.check_access::<ResourceType>(BorrowMode::Shared)?;
+		match self.non_send_resource_cell::<ResourceType>()?.try_borrow() {
+			Ok(r) => return Ok(r),
+			Err(_) => return Err(EntitySystemError::BorrowConflict {entity: None, component: std::any::type_name::<ResourceType>(), mode: BorrowMode::Shared, held_by: None}),
+		}
+	}
+
+	pub fn non_send_resource_mut<ResourceType: 'static>(&self) -> Result<RefMut<'_, ResourceType>, EntitySystemError> {
+		self.check_access::<ResourceType>(BorrowMode::Exclusive)?;
+		match self.non_send_resource_cell::<ResourceType>()?.try_borrow_mut() {
+			Ok(r) => return Ok(r),
+			Err(_) => return Err(EntitySystemError::BorrowConflict {entity: None, component: std::any::type_name::<ResourceType>(), mode: BorrowMode::Exclusive, held_by: None}),
+		}
+	}
+}
+
+//Everything about the entity allocator has to add up, otherwise loading it would hand out indices
+//that are already in use or make handles valid that shouldn't be
+fn validate_save(save: &WorldSave) -> Result<(), EntitySystemError> {
+	let invalid = |reason: String| Err(EntitySystemError::InvalidSave {reason});
+
+	if save.next_index != save.generations.len() as u64 {
+		return invalid(format!("next_index is {} but there are {} generations", save.next_index, save.generations.len()));
+	}
+
+	let mut live = HashSet::new();
+	for saved in save.entities.iter() {
+		if saved.index >= save.next_index {
+			return invalid(format!("Entity index {} was never handed out", saved.index));
+		}
+		if !live.insert(saved.index) {
+			return invalid(format!("Entity index {} is saved more than once", saved.index));
+		}
+		if saved.generation != save.generations[saved.index as usize] {
+			return invalid(format!("Entity index {} is saved at generation {} but the index is at generation {}", saved.index, saved.generation, save.generations[saved.index as usize]));
+		}
+	}
+
+	let mut free = HashSet::new();
+	for index in save.free_indices.iter() {
+		if *index >= save.next_index {
+			return invalid(format!("Free index {} was never handed out", index));
+		}
+		if live.contains(index) {
+			return invalid(format!("Index {} is both free and in use", index));
+		}
+		if !free.insert(*index) {
+			return invalid(format!("Index {} is free more than once", index));
+		}
+	}
+
+	return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::rc::Rc;
+	use std::sync::Arc;
+	use std::sync::atomic::AtomicUsize;
+	use serde_json::Value;
+	use crate::components::{register_components, LocalPosition, Moveable, Position};
+	use crate::query::Changed;
+
+	//What the named system sees as changed, as if it just ran
+	fn changed_for(es: &EntitySystem, system: &'static str) -> Vec<Entity> {
+		es.begin_system(system);
+		let changed = es.query_filtered::<&Position, Changed<Position>>().unwrap().entities();
+		es.end_system();
+		return changed;
+	}
+
+	//The old handle has the same index as the new entity so only the generation tells them apart
+	#[test]
+	fn stale_handles_are_rejected_once_their_index_is_reused() {
+		let mut es = EntitySystem::new();
+		let old = es.new_entity().unwrap();
+		es.add_component_to_entity(old, Position::new(0.0, 0.0)).unwrap();
+		es.remove_entity(old).unwrap();
+
+		let new = es.new_entity().unwrap();
+		assert_eq!(new.index(), old.index());
+		es.add_component_to_entity(new, Position::new(1.0, 2.0)).unwrap();
+
+		let stale = |result: Result<(), EntitySystemError>| {
+			match result {
+				Err(EntitySystemError::StaleEntity {entity, current_generation}) => {
+					assert_eq!(entity, old);
+					assert_eq!(current_generation, new.generation());
+				},
+				_ => panic!("expected {} to be stale", old),
+			}
+		};
+		stale(es.add_component_to_entity(old, Position::new(3.0, 4.0)));
+		stale(es.with_component(old, |_: &Position| ()));
+		stale(es.remove_component_from_entity::<Position>(old));
+
+		let position = es.get::<Position>(new).unwrap();
+		assert_eq!((position.x, position.y), (1.0, 2.0));
+	}
+
+	#[test]
+	fn remove_entity_removes_descendants() {
+		let mut es = EntitySystem::new();
+		let root = es.new_entity().unwrap();
+		let middle = es.new_entity().unwrap();
+		let leaf = es.new_entity().unwrap();
+		let other = es.new_entity().unwrap();
+		es.set_parent(middle, root).unwrap();
+		es.set_parent(leaf, middle).unwrap();
+		es.set_parent(other, root).unwrap();
+
+		es.remove_entity(middle).unwrap();
+		assert!(!es.is_alive(middle) && !es.is_alive(leaf));
+		assert_eq!(es.children_of(root), vec![other]);
+		assert_eq!(es.removed::<Parent>().len(), 2);
+	}
+
+	#[test]
+	fn remove_entity_with_a_broken_hierarchy_removes_nothing() {
+		let mut es = EntitySystem::new();
+		let root = es.new_entity().unwrap();
+		let child = es.new_entity().unwrap();
+		let dead = es.new_entity().unwrap();
+		es.set_parent(child, root).unwrap();
+		es.remove_entity(dead).unwrap();
+		es.add_component_to_entity(child, Children {entities: vec![dead]}).unwrap();
+
+		assert!(matches!(es.remove_entity(root), Err(EntitySystemError::StaleEntity {..})));
+		assert!(es.is_alive(root) && es.is_alive(child));
+		assert_eq!(es.children_of(root), vec![child]);
+
+		//Loops are caught too rather than going round forever
+		es.add_component_to_entity(child, Children {entities: vec![root]}).unwrap();
+		assert!(matches!(es.remove_entity(root), Err(EntitySystemError::InvalidHierarchy {..})));
+		assert!(es.is_alive(root) && es.is_alive(child));
+	}
+
+	#[test]
+	fn tags_follow_their_entity() {
+		let mut es = EntitySystem::new();
+		let wall = es.new_entity().unwrap();
+		let spawn = es.new_entity().unwrap();
+		es.add_tag(wall, "wall").unwrap();
+		es.add_tag(wall, "solid").unwrap();
+		es.add_tag(wall, "wall").unwrap(); //Already has it
+		es.add_tag(spawn, "solid").unwrap();
+
+		assert_eq!(es.tags_of(wall), vec!["solid", "wall"]);
+		assert_eq!(es.entities_with_tag("solid"), vec![wall, spawn]);
+
+		es.remove_tag(spawn, "solid").unwrap();
+		assert!(!es.has_tag(spawn, "solid"));
+		assert_eq!(es.entities_with_tag("solid"), vec![wall]);
+
+		//A new entity reusing the index starts with no tags
+		es.remove_entity(wall).unwrap();
+		let reused = es.new_entity().unwrap();
+		assert_eq!(reused.index(), wall.index());
+		assert!(es.tags_of(reused).is_empty());
+		assert!(es.entities_with_tag("wall").is_empty());
+		assert!(es.entities_with_tag("solid").is_empty());
+	}
+
+	fn registered_world() -> EntitySystem {
+		let mut es = EntitySystem::new();
+		crate::components::register_components(&mut es);
+		return es;
+	}
+
+	#[test]
+	fn save_and_load_keep_the_hierarchy() {
+		let mut es = registered_world();
+		let first = es.new_entity().unwrap();
+		let second = es.new_entity().unwrap();
+		let parent = es.spawn_with_name("Parent".to_string(), (Position::new(1.0, 1.0),)).unwrap();
+		es.set_parent(second, parent).unwrap();
+		es.set_parent(first, parent).unwrap();
+		es.add_tag(first, "first").unwrap();
+
+		let mut loaded = registered_world();
+		loaded.load(es.save().unwrap()).unwrap();
+
+		assert_eq!(loaded.children_of(parent), vec![second, first]);
+		assert_eq!(loaded.parent_of(first), Some(parent));
+		assert_eq!(loaded.get_entity_for_name("Parent").unwrap(), parent);
+		assert!(loaded.has_tag(first, "first"));
+		assert_eq!(loaded.get::<Position>(parent).unwrap().x, 1.0);
+	}
+
+	fn saved(index: u64, generation: u32, components: Value) -> SavedEntity {
+		let components = match components {
+			Value::Object(map) => map,
+			_ => serde_json::Map::new(),
+		};
+		return SavedEntity {index, generation, name: "Saved".to_string(), tags: vec!["tag".to_string()], components};
+	}
+
+	#[test]
+	fn load_rejects_bad_saves_and_stays_empty() {
+		let bad_saves = [
+			WorldSave {next_index: 2, generations: vec![0, 0], free_indices: vec![], entities: vec![saved(0, 0, Value::Null), saved(0, 0, Value::Null)]}, //Same index twice
+			WorldSave {next_index: 2, generations: vec![0, 1], free_indices: vec![1], entities: vec![saved(0, 0, Value::Null), saved(1, 1, Value::Null)]}, //Live and free
+			WorldSave {next_index: 1, generations: vec![2], free_indices: vec![], entities: vec![saved(0, 1, Value::Null)]}, //Wrong generation
+			WorldSave {next_index: 2, generations: vec![0, 0], free_indices: vec![], entities: vec![
+				saved(0, 0, serde_json::json!({"Position": {"x": 0.0, "y": 0.0}, "Parent": {"entity": {"index": 1, "generation": 0}}})),
+				saved(1, 0, serde_json::json!({"Parent": {"entity": {"index": 0, "generation": 0}}})),
+			]}, //Each the other's parent
+		];
+
+		let mut es = registered_world();
+		for save in bad_saves {
+			assert!(es.load(save).is_err());
+			assert!(es.entities().is_empty());
+			assert!(es.entities_with_tag("tag").is_empty());
+			assert!(es.borrow_all_components_of_type::<Position>().map_or(true, |p| p.is_empty()));
+		}
+
+		//Still empty so a good save goes in fine
+		es.load(WorldSave {next_index: 1, generations: vec![0], free_indices: vec![], entities: vec![saved(0, 0, Value::Null)]}).unwrap();
+		assert_eq!(es.entities().len(), 1);
+	}
+
+	#[test]
+	fn reading_outside_a_system_keeps_the_tick() {
+		let mut es = EntitySystem::new();
+		es.spawn((Position::new(0.0, 0.0),)).unwrap();
+
+		let ticks = es.system_ticks();
+		es.query::<&Position>().unwrap().entities();
+		es.removed::<Position>();
+		es.get::<Position>(es.entities()[0]).unwrap();
+		assert_eq!(es.system_ticks(), ticks);
+	}
+
+	#[test]
+	fn changes_outside_a_system_are_seen_by_systems() {
+		let mut es = EntitySystem::new();
+		let ent = es.spawn((Position::new(0.0, 0.0),)).unwrap();
+		assert_eq!(changed_for(&es, "watcher"), vec![ent]);
+		assert_eq!(changed_for(&es, "watcher"), vec![]);
+
+		es.query::<&mut Position>().unwrap().for_each(|_, mut p| p.x = 1.0);
+		assert_eq!(changed_for(&es, "watcher"), vec![ent]);
+
+		es.get_mut::<Position>(ent).unwrap().y = 1.0;
+		assert_eq!(changed_for(&es, "watcher"), vec![ent]);
+		assert_eq!(changed_for(&es, "watcher"), vec![]);
+	}
+
+	#[test]
+	fn borrow_conflicts_name_the_entity() {
+		let mut es = EntitySystem::new();
+		let ent = es.spawn((Position::new(0.0, 0.0),)).unwrap();
+
+		let _held = es.borrow_all_components_of_type::<Position>().unwrap();
+		match es.get_mut::<Position>(ent) {
+			Err(EntitySystemError::BorrowConflict {entity, mode, ..}) => {
+				assert_eq!(entity, Some(ent));
+				assert_eq!(mode, BorrowMode::Exclusive);
+			},
+			_ => panic!("expected a BorrowConflict"),
+		};
+	}
+
+	//Puts its Position and LocalPosition in and then fails, like a bundle that runs into trouble
+	//part way through
+	struct FailingBundle;
+
+	impl Bundle for FailingBundle {
+		fn prepare(&self, es: &mut EntitySystem) -> Result<(), EntitySystemError> {
+			es.prepare_component_store::<Position>()?;
+			return es.prepare_component_store::<LocalPosition>();
+		}
+
+		fn insert(self, es: &mut EntitySystem, ent: Entity, inserted: &mut Inserted) -> Result<(), EntitySystemError> {
+			crate::bundle::insert_component(Position::new(0.0, 0.0), es, ent, inserted)?;
+			crate::bundle::insert_component(LocalPosition::new(0.0, 0.0), es, ent, inserted)?;
+			return Err(EntitySystemError::NoSuchName {name: "failing".to_string()});
+		}
+	}
+
+	fn count_adds(es: &mut EntitySystem) -> Arc<AtomicUsize> {
+		let added = Arc::new(AtomicUsize::new(0));
+		let counter = added.clone();
+		es.register_hooks::<Position, _, _>(move |_, _| { counter.fetch_add(1, Ordering::Relaxed); }, |_, _| ());
+		return added;
+	}
+
+	#[test]
+	fn hooks_run_once_the_bundle_is_in() {
+		let mut es = EntitySystem::new();
+		let added = count_adds(&mut es);
+
+		let ent = es.spawn((Position::new(0.0, 0.0), LocalPosition::new(1.0, 1.0))).unwrap();
+		assert_eq!(added.load(Ordering::Relaxed), 1);
+
+		//Replacing doesn't count as adding
+		es.insert(ent, (Position::new(2.0, 2.0),)).unwrap();
+		assert_eq!(added.load(Ordering::Relaxed), 1);
+	}
+
+	#[test]
+	fn failed_spawn_runs_no_hooks_and_keeps_its_error() {
+		let mut es = EntitySystem::new();
+		let added = count_adds(&mut es);
+
+		match es.spawn(FailingBundle) {
+			Err(EntitySystemError::NoSuchName {name}) => assert_eq!(name, "failing"),
+			_ => panic!("expected the bundle's error"),
+		}
+
+		assert_eq!(added.load(Ordering::Relaxed), 0);
+		assert!(es.entities().is_empty());
+		assert!(es.borrow_all_components_of_type::<Position>().unwrap().is_empty());
+		assert!(es.removed::<Position>().is_empty());
+	}
+
+	#[test]
+	fn failed_insert_leaves_the_entity_as_it_was() {
+		let mut es = EntitySystem::new();
+		let ent = es.spawn((Position::new(5.0, 5.0),)).unwrap();
+		let before = es.borrow_all_components_of_type::<Position>().unwrap().ticks(ent.index);
+		let added = count_adds(&mut es);
+
+		match es.insert(ent, FailingBundle) {
+			Err(EntitySystemError::NoSuchName {name}) => assert_eq!(name, "failing"),
+			_ => panic!("expected the bundle's error"),
+		}
+
+		assert_eq!(added.load(Ordering::Relaxed), 0);
+		let position = es.get::<Position>(ent).unwrap();
+		assert_eq!((position.x, position.y), (5.0, 5.0));
+		assert_eq!(es.borrow_all_components_of_type::<Position>().unwrap().ticks(ent.index), before);
+		assert!(es.get::<LocalPosition>(ent).is_err());
+		assert!(es.removed::<LocalPosition>().is_empty());
+	}
+
+	struct Marker;
+
+	#[test]
+	fn dump_world_describes_every_entity() {
+		let mut es = EntitySystem::new();
+		register_components(&mut es);
+		let player = es.spawn_with_name("Player".to_string(), (Position::new(1.0, 2.0), Moveable::new(0.0, -1.0), Marker)).unwrap();
+		es.add_tag(player, "player").unwrap();
+		es.add_tag(player, "alive").unwrap();
+		let rock = es.new_entity_with_name("Rock".to_string()).unwrap();
+
+		//Reflected components show their fields, anything else only its type
+		let described = format!("{} \"Player\" [alive, player]\n    Moveable {{dx: 0.0, dy: -1.0}}\n    Position {{x: 1.0, y: 2.0}}\n    {}\n", player, std::any::type_name::<Marker>());
+		assert_eq!(es.describe(player).unwrap(), described);
+		assert_eq!(es.dump_world().unwrap(), format!("2 entities\n{}{} \"Rock\" []\n", described, rock));
+	}
+
+	#[derive(Clone)]
+	struct Ping(u32);
+
+	#[derive(Clone)]
+	struct Pong;
+
+	//An Rc can't be sent between threads so it can't be a normal resource
+	#[test]
+	fn non_send_resources_borrow_like_resources() {
+		let mut es = EntitySystem::new();
+		es.insert_non_send_resource(Rc::new(5u32)).unwrap();
+
+		{
+			let shared = es.non_send_resource::<Rc<u32>>().unwrap();
+			assert_eq!(**shared, 5);
+			match es.non_send_resource_mut::<Rc<u32>>() {
+				Err(EntitySystemError::BorrowConflict {mode: BorrowMode::Exclusive, ..}) => (),
+				_ => panic!("expected a borrow conflict"),
+			}
+		}
+
+		*es.non_send_resource_mut::<Rc<u32>>().unwrap() = Rc::new(6);
+		assert_eq!(**es.non_send_resource::<Rc<u32>>().unwrap(), 6);
+	}
+
+	#[test]
+	fn restore_keeps_events_added_after_the_snapshot() {
+		let mut es = EntitySystem::new();
+		es.add_event::<Ping>();
+		es.send(Ping(1)).unwrap();
+		let snapshot = es.snapshot().unwrap();
+
+		es.read::<Ping>().unwrap();
+		es.add_event::<Pong>();
+		es.send(Pong).unwrap();
+
+		es.restore(&snapshot).unwrap();
+		assert_eq!(es.read::<Ping>().unwrap().iter().map(|p| p.0).collect::<Vec<u32>>(), vec![1]);
+		assert_eq!(es.read::<Pong>().unwrap().len(), 1);
+	}
+}
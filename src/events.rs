@@ -0,0 +1,84 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::entity_system::Entity;
+
+//Anything sent between systems. Every reader gets its own copy hence Clone.
+pub trait Event: Clone + Send + Sync + 'static {}
+impl<T: Clone + Send + Sync + 'static> Event for T {}
+
+//Double buffered queue of one event type. Events stay around for the tick they were sent in and
+//the one after, so a system that runs before the sender in the schedule still gets them next
+//tick. Each reader has a cursor so it sees every event exactly once.
+#[derive(Clone)]
+pub struct Events<E> {
+	previous: Vec<(u64, E)>, //Sent last tick
+	current: Vec<(u64, E)>, //Sent this tick
+	next_id: u64,
+	cursors: HashMap<Option<&'static str>, u64>, //Per reader, the id of the first event it hasn't seen
+}
+
+impl<E: Event> Events<E> {
+	pub fn new() -> Self {
+		return Events {previous: Vec::new(), current: Vec::new(), next_id: 0, cursors: HashMap::new()};
+	}
+
+	pub fn send(&mut self, event: E) {
+		self.current.push((self.next_id, event));
+		self.next_id += 1;
+	}
+
+	//Everything still buffered that this reader hasn't seen yet, oldest first
+	pub fn read(&mut self, reader: Option<&'static str>) -> Vec<E> {
+		let cursor = self.cursors.get(&reader).copied().unwrap_or(0);
+		let unread = self.previous.iter().chain(self.current.iter())
+			.filter(|(id, _)| *id >= cursor)
+			.map(|(_, e)| e.clone())
+			.collect();
+
+		self.cursors.insert(reader, self.next_id);
+		return unread;
+	}
+
+	//Drops last tick's events and makes this tick's the previous ones
+	pub fn update(&mut self) {
+		self.previous = std::mem::take(&mut self.current);
+	}
+}
+
+//Type erased so the EntitySystem can update every queue at the end of a tick
+pub trait EventQueue: Send + Sync {
+	fn as_any(&self) -> &dyn Any;
+
+	fn update(&mut self);
+
+	//For snapshots, a copy of the buffers and the readers' cursors
+	fn clone_queue(&self) -> Box<dyn EventQueue>;
+}
+
+impl<E: Event> EventQueue for Mutex<Events<E>> {
+	fn as_any(&self) -> &dyn Any {
+		return self as &dyn Any;
+	}
+
+	fn update(&mut self) {
+		self.get_mut().unwrap_or_else(|e| e.into_inner()).update();
+	}
+
+	fn clone_queue(&self) -> Box<dyn EventQueue> {
+		return Box::new(Mutex::new(self.lock().unwrap_or_else(|e| e.into_inner()).clone()));
+	}
+}
+
+#[derive(Clone, Debug)]
+pub struct BombExploded {
+	pub bomb: Entity,
+	pub x: f64,
+	pub y: f64,
+}
+
+#[derive(Clone, Debug)]
+pub struct PlayerDied {
+	pub player: Entity,
+}
@@ -2,25 +2,39 @@ use std::path::Path;
 use std::time::Duration;
 use std::fmt;
 use std::error::Error;
-use std::collections::HashMap;
 use std::time::Instant;
 
 //sdl2
 extern crate sdl2;
 use sdl2::pixels::Color;
-use sdl2::keyboard::Keycode;
 use sdl2::rect::Rect;
 use sdl2::rect::Point;
 
-//sdl2_image
-use sdl2::image::LoadTexture;
-
+mod bundle;
+mod commands;
 mod components;
 mod entity_system;
+mod events;
+mod prefabs;
+mod query;
+mod reflect;
+mod registry;
+mod renderer;
+mod resources;
+mod save;
+mod scene;
+mod scenes;
+mod schedule;
+mod snapshot;
+mod sparse_set;
+mod spatial;
 mod systems;
-use components::{Position, Moveable, Drawable, Animations, Animation, AnimationType, Direction, Collidable};
-use entity_system::{Entity, EntitySystem, EntitySystemError};
-use systems::{system_moveable, system_animation, system_drawable, system_direction, SystemsError};
+use entity_system::EntitySystemError;
+use renderer::Renderer;
+use prefabs::Prefabs;
+use scene::SceneManager;
+use scenes::MainMenu;
+use systems::SystemsError;
 
 #[derive(Debug)]
 pub enum GameError {
@@ -31,17 +45,35 @@ pub enum GameError {
 impl fmt::Display for GameError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            GameError::EntitySystemError(ese) => {
-                write!(f, "GameError:EntitySystemError:{}", ese)
+            GameError::EntitySystemError(_) => {
+                write!(f, "GameError:EntitySystemError")
             },
-            GameError::SystemsError(se) => {
-                write!(f, "GameError:SystemsError:{}", se)
+            GameError::SystemsError(_) => {
+                write!(f, "GameError:SystemsError")
             },
         }
     }
 }
 
-impl Error for GameError {}
+impl Error for GameError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            GameError::EntitySystemError(ese) => return Some(ese),
+            GameError::SystemsError(se) => return Some(se),
+        }
+    }
+}
+
+//Display on the wrapping errors only says where it failed, the sources say what went wrong
+pub fn error_chain(err: &dyn Error) -> String {
+    let mut chain = err.to_string();
+    let mut source = err.source();
+    while let Some(e) = source {
+        chain.push_str(&format!(":{}", e));
+        source = e.source();
+    }
+    return chain;
+}
 
 impl From<EntitySystemError> for GameError {
     fn from(err: EntitySystemError) -> Self {
@@ -55,174 +87,6 @@ impl From<SystemsError> for GameError {
     }
 }
 
-fn create_tile(es: &mut EntitySystem, pos: Position, rect: Drawable, collidable: Option<Collidable>) -> Result<Entity, String> {
-	let tile = match es.new_entity_with_name("Player".to_string()) {
-		Ok(t) => {
-			println!("tile:{}", t);
-			t
-		},
-		Err(e) => panic!("Failed to create tile:{}", e),
-	};
-
-	match es.add_component_to_entity(tile, pos) {
-		Ok(_) => println!("Added position component to tile"),
-		Err(e) => panic!("Failed to add position component to tile:{}", e),
-	}
-
-    match es.add_component_to_entity(tile, rect) {
-        Ok(_) => println!("Added drawable to tile"),
-        Err(e) => panic!("Failed to add drawable to tile:{}", e),
-    }
-
-    if let coll = collidable {
-        match es.add_component_to_entity(tile, coll) {
-            Ok(_) => println!("Added collidable to tile"),
-            Err(e) => panic!("Failed to add collidable to tile:{}", e),
-        }
-    }
-
-    return Ok(tile);
-}
-
-fn create_grass_tile(es: &mut EntitySystem) -> Result<Entity, String> {
-    return create_tile(es, Position::new(400.0, 400.0), Drawable::new(16, 208, 16, 16, 0), None)
-}
-
-fn create_layer1_grass_tile(es: &mut EntitySystem) -> Result<Entity, String> {
-    return create_tile(es, Position::new(450.0, 400.0), Drawable::new(16, 208, 16, 16, 1), None)
-}
-
-fn create_layer2_grass_tile(es: &mut EntitySystem) -> Result<Entity, String> {
-    return create_tile(es, Position::new(500.0, 400.0), Drawable::new(16, 208, 16, 16, 2), None)
-}
-
-fn create_player_entity(es: &mut EntitySystem) -> Result<Entity, String> {
-	let player = match es.new_entity_with_name("Player".to_string()) {
-		Ok(p) => {
-			println!("player:{}", p);
-			p
-		},
-		Err(e) => panic!("Failed to create player:{}", e),
-	};
-
-	match es.add_component_to_entity(player, Position::new(0.0, 0.0)) {
-		Ok(_) => println!("Added position component to player"),
-		Err(e) => panic!("Failed to add position component to player:{}", e),
-	}
-
-	match es.add_component_to_entity(player, Moveable::new(0.0, 0.0)) {
-		Ok(_) => println!("Added moveable component to player"),
-		Err(e) => panic!("Failed to add moveable component to player:{}", e),
-	}
-
-    /*
-	match es.add_component_to_entity(player, Drawable::new(17, 272, 15, 15)) {
-		Ok(_) => println!("Added Drawable component to player"),
-		Err(e) => panic!("Failed to add drawable component to player:{}", e),
-	}
-    */
-
-	match es.add_component_to_entity(player, Direction::Down) {
-		Ok(_) => println!("Added Direction component to player"),
-		Err(e) => panic!("Failed to add Direction component to player:{}", e),
-	}
-
-    let layer = 1;
-
-    let animation_standing_down = Animation::new_with_frames(
-            vec![
-                Drawable::new(16, 272, 15, 15, layer),
-            ],
-            0.0,
-            false,
-            false,
-        );
-
-    let animation_standing_up = Animation::new_with_frames(
-            vec![
-                Drawable::new(0, 272, 15, 15, layer),
-            ],
-            0.0,
-            false,
-            false,
-        );
-
-    let animation_standing_right = Animation::new_with_frames(
-            vec![
-                Drawable::new(64, 272, 15, 15, layer),
-            ],
-            0.0,
-            false,
-            false,
-        );
-
-    let animation_standing_left = Animation::new_with_frames(
-            vec![
-                Drawable::new(64, 272, 15, 15, layer),
-            ],
-            0.0,
-            true,
-            false,
-        );
-
-    let animation_walking_up = Animation::new_with_frames(
-            vec![
-                Drawable::new(0, 272, 15, 15, layer),
-                Drawable::new(128, 272, 15, 15, layer),
-                Drawable::new(144, 272, 15, 15, layer),
-            ],
-            5.0,
-            false,
-            false,
-        );
-
-    let animation_walking_down = Animation::new_with_frames(
-            vec![
-                Drawable::new(16, 272, 15, 15, layer),
-                Drawable::new(32, 272, 15, 15, layer),
-                Drawable::new(48, 272, 15, 15, layer),
-            ],
-            5.0,
-            false,
-            false,
-        );
-
-    let animation_walking_right = Animation::new_with_frames(
-            vec![
-                Drawable::new(64, 272, 15, 15, layer),
-                Drawable::new(80, 272, 15, 15, layer),
-                Drawable::new(96, 272, 15, 15, layer),
-                Drawable::new(112, 272, 15, 15, layer),
-            ],
-            5.0,
-            false,
-            false,
-        );
-
-    let animation_walking_left = Animation::new_with_frames(
-            vec![
-                Drawable::new(64, 272, 15, 15, layer),
-                Drawable::new(80, 272, 15, 15, layer),
-                Drawable::new(96, 272, 15, 15, layer),
-                Drawable::new(112, 272, 15, 15, layer),
-            ],
-            5.0,
-            true,
-            false,
-        );
-
-    let player_animations = Animations::new(AnimationType::StandingDown, HashMap::from_iter([(AnimationType::WalkingUp, animation_walking_up), (AnimationType::WalkingDown, animation_walking_down), (AnimationType::WalkingRight, animation_walking_right), (AnimationType::WalkingLeft, animation_walking_left),
-        (AnimationType::StandingDown, animation_standing_down), (AnimationType::StandingUp, animation_standing_up), (AnimationType::StandingRight, animation_standing_right), (AnimationType::StandingLeft, animation_standing_left),
-    ]));
-
-    match es.add_component_to_entity(player, player_animations) {
-        Ok(_) => println!("Added animations to player"),
-        Err(e) => panic!("Failed to added animations to player"),
-    };
-
-    return Ok(player);
-}
-
 fn main() {
     //SDL2 setup
     let sdl_context = match sdl2::init() {
@@ -274,50 +138,48 @@ fn main() {
         },
     };
 
-    let texture_creator = canvas.texture_creator();
-
-    let game_texture = match texture_creator.load_texture(Path::new("assets/bomb_party_v4.png")) {
-        Ok(gt) => gt,
+    let renderer = match Renderer::new(canvas, Path::new("assets/bomb_party_v4.png")) {
+        Ok(r) => r,
         Err(e) => {
             println!("Unable to load game texture:{}", e);
             return;
         },
     };
 
-    //Entity System setup
-	let mut es = EntitySystem::new(canvas, game_texture);
-
-    let player = match create_player_entity(&mut es) {
-        Ok(p) => p,
+    //Scenes, each one gets its own world but they all draw with the same Renderer and spawn from
+    //the same prefabs
+    let mut scenes = SceneManager::new();
+    match scenes.share_non_send_resource(renderer) {
+        Ok(_) => (),
         Err(e) => {
-            println!("Failed to create player:{}", e);
+            println!("Failed to add renderer:{}", e);
             return;
         },
-    };
+    }
 
-    let grass = match create_grass_tile(&mut es) {
-        Ok(g) => g,
+    match Prefabs::load_dir(Path::new("assets/prefabs")) {
+        Ok(p) => scenes.share_resource(p),
         Err(e) => {
-            println!("Failed to create grass:{}", e);
+            println!("Failed to load prefabs:{}", e);
             return;
         },
     };
 
-    let grass1 = match create_layer1_grass_tile(&mut es) {
-        Ok(g) => g,
+    let main_menu = match MainMenu::new() {
+        Ok(m) => m,
         Err(e) => {
-            println!("Failed to create grass:{}", e);
+            println!("Failed to build main menu:{}", error_chain(&e));
             return;
         },
     };
 
-    let grass2 = match create_layer2_grass_tile(&mut es) {
-        Ok(g) => g,
+    match scenes.push(Box::new(main_menu)) {
+        Ok(_) => (),
         Err(e) => {
-            println!("Failed to create grass:{}", e);
+            println!("Failed to enter main menu:{}", error_chain(&e));
             return;
         },
-    };
+    }
 
     let mut event_pump = match sdl_context.event_pump() {
         Ok(ep) => ep,
@@ -327,9 +189,6 @@ fn main() {
         },
     };
 
-    //Systems
-    let systems: Vec<&dyn Fn(&mut EntitySystem, f64) -> Result<(), GameError>> = vec![&system_moveable, &system_animation, &system_drawable, &system_direction];
-
     let mut previous_frame_time = Instant::now();
     let mut frame: usize = 0;
     //Main game loop
@@ -338,131 +197,30 @@ fn main() {
         for event in event_pump.poll_iter() {
             use sdl2::event::Event;
             match event {
-                Event::KeyDown {keycode: Some(Keycode::Left), repeat: false, ..} => { 
-                    match es.component_for_entity_mut::<Moveable, _>(player, |mv: &mut Moveable| -> Result<(), String> {
-                        if mv.dx > 0.0 {
-                            mv.dx = 0.0;
-                        } else {
-                            mv.dx = -1.0;
-                        }
-                        return Ok(());
-                    }) {
-                        Ok(_) => (),
-                        Err(_) => println!("Failed to up date moveable for player"),
-                    }
-                },
-                Event::KeyDown {keycode: Some(Keycode::Right), repeat: false, ..} => {
-                    match es.component_for_entity_mut::<Moveable, _>(player, |mv: &mut Moveable| -> Result<(), String> {
-                        if mv.dx < 0.0 {
-                            mv.dx = 0.0;
-                        } else {
-                            mv.dx = 1.0;
-                        }
-                        return Ok(());
-                    }) {
-                        Ok(_) => (),
-                        Err(_) => println!("Failed to up date moveable for player"),
-                    }
-                },
-                Event::KeyDown {keycode: Some(Keycode::Up), repeat: false, ..} => {
-                    match es.component_for_entity_mut::<Moveable, _>(player, |mv: &mut Moveable| -> Result<(), String> {
-                        if mv.dy > 0.0 {
-                            mv.dy = 0.0;
-                        } else {
-                            mv.dy = -1.0;
-                        }
-                        return Ok(());
-                    }) {
-                        Ok(_) => (),
-                        Err(_) => println!("Failed to up date moveable for player"),
-                    }
-                },
-                Event::KeyDown {keycode: Some(Keycode::Down), repeat: false, ..} => {
-                    match es.component_for_entity_mut::<Moveable, _>(player, |mv: &mut Moveable| -> Result<(), String> {
-                        if mv.dy < 0.0 {
-                            mv.dy = 0.0;
-                        } else {
-                            mv.dy = 1.0;
-                        }
-                        return Ok(());
-                    }) {
-                        Ok(_) => (),
-                        Err(_) => println!("Failed to up date moveable for player"),
-                    }
-                },
-                Event::KeyDown {keycode: Some(Keycode::Escape), ..} => {
+                Event::Quit {..} => {
                     println!("Quiting");
                     break 'running;
                 },
-                Event::KeyUp {keycode: Some(Keycode::Left), repeat: false, ..} => {
-                    match es.component_for_entity_mut::<Moveable, _>(player, |mv: &mut Moveable| -> Result<(), String> {
-                        mv.dx += 1.0;
-                        return Ok(());
-                    }) {
-                        Ok(_) => (),
-                        Err(_) => println!("Failed to up date moveable for player"),
-                    }
-                },
-                Event::KeyUp {keycode: Some(Keycode::Right), repeat: false, ..} => {
-                    match es.component_for_entity_mut::<Moveable, _>(player, |mv: &mut Moveable| -> Result<(), String> {
-                        mv.dx -= 1.0;
-                        return Ok(());
-                    }) {
-                        Ok(_) => (),
-                        Err(_) => println!("Failed to up date moveable for player"),
-                    }
-                },
-                Event::KeyUp {keycode: Some(Keycode::Up), repeat: false, ..} => {
-                    match es.component_for_entity_mut::<Moveable, _>(player, |mv: &mut Moveable| -> Result<(), String> {
-                        mv.dy += 1.0;
-                        return Ok(());
-                    }) {
-                        Ok(_) => (),
-                        Err(_) => println!("Failed to up date moveable for player"),
-                    }
-                },
-                Event::KeyUp {keycode: Some(Keycode::Down), repeat: false, ..} => {
-                    match es.component_for_entity_mut::<Moveable, _>(player, |mv: &mut Moveable| -> Result<(), String> {
-                        mv.dy -= 1.0;
-                        return Ok(());
-                    }) {
+                _ => {
+                    match scenes.handle_event(&event) {
                         Ok(_) => (),
-                        Err(_) => println!("Failed to up date moveable for player"),
+                        Err(e) => panic!("Scene failed:{}", error_chain(&e)),
                     }
                 },
-                Event::Quit {..} => {
-                    println!("Quiting");
-                    break 'running;
-                },
-                _ => (),
             }
         }
 
-        //Game update
-		/* Need to fix this when I'm not so tired
-		match es.borrow_component_for_entity::<Position>(player) {
-			Ok(position) => println!("Frame:{} Player position x:{} y:{}", i, position.x, position.y),
-			Err(e) => println!("Failed to player position:{}", e),
-		}
-		match es.borrow_all_components_of_type::<Position>() {
-			Ok(positions) => { 
-				match positions.get(&player.id) {
-					Some(position) => println!("Frame:{} Player position x:{} y:{}", frame, position.x, position.y),
-					None => println!("Failed to player position"),
-				}
-			},
-			Err(e) => println!("Unable to borrow positions:{}", e),
-		};
-		*/
+        //The last scene has exited
+        if scenes.is_empty() {
+            break 'running;
+        }
 
         let current_frame_time = Instant::now();
         let dt = (current_frame_time - previous_frame_time).as_secs_f64();
         //Do the game things
-        for system in systems.iter() {
-            match system(&mut es, dt) {
-                Ok(_) => (),
-                Err(e) => panic!("System failed:{}", e),
-            }
+        match scenes.update(dt) {
+            Ok(_) => (),
+            Err(e) => panic!("Scene failed:{}", error_chain(&e)),
         }
 
         let systems_frame_time = Instant::now();
@@ -482,4 +240,10 @@ fn main() {
         frame += 1;
     }
 
+    //Closing the window skips straight here so give every scene left its exit
+    match scenes.clear() {
+        Ok(_) => (),
+        Err(e) => println!("Failed to exit scenes:{}", error_chain(&e)),
+    }
+
 }
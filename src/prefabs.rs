@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use crate::entity_system::{Entity, EntitySystem, EntitySystemError};
+
+//Describes an entity in data, loaded from assets/prefabs/<name>.json. Components are keyed by the
+//name they were registered with, see components::register_components.
+#[derive(Deserialize, Clone)]
+pub struct Prefab {
+	pub name: String,
+	#[serde(default)]
+	pub unique: bool, //Only one entity with the name may exist, for things like the player
+	#[serde(default)]
+	pub tags: Vec<String>,
+	#[serde(default)]
+	pub components: Map<String, Value>,
+}
+
+impl Prefab {
+	pub fn parse(json: &str) -> Result<Prefab, EntitySystemError> {
+		match serde_json::from_str::<Prefab>(json) {
+			Ok(p) => return Ok(p),
+			Err(e) => return Err(EntitySystemError::InvalidPrefab {reason: format!("Unable to parse prefab: {}", e)}),
+		}
+	}
+
+	//overrides is an object of component name -> data that's merged over the prefab's, components
+	//the prefab doesn't have are added. If any component fails the entity is removed again.
+	pub fn spawn(&self, es: &mut EntitySystem, overrides: Value) -> Result<Entity, EntitySystemError> {
+		let mut components = Value::Object(self.components.clone());
+		merge(&mut components, overrides);
+
+		let components = match components {
+			Value::Object(c) => c,
+			_ => return Err(EntitySystemError::InvalidPrefab {reason: format!("Overrides for prefab {} have to be an object of components", self.name)}),
+		};
+
+		let ent = if self.unique {
+			es.new_entity_with_unique_name(self.name.clone())?
+		} else {
+			es.new_entity_with_name(self.name.clone())?
+		};
+
+		match spawn_components(es, ent, &self.tags, components) {
+			Ok(_) => return Ok(ent),
+			Err(e) => {
+				if let Err(remove_error) = es.remove_entity(ent) {
+					println!("Unable to remove {} after it failed to spawn:{}", ent, remove_error);
+				}
+				return Err(e);
+			},
+		}
+	}
+}
+
+fn spawn_components(es: &mut EntitySystem, ent: Entity, tags: &[String], components: Map<String, Value>) -> Result<(), EntitySystemError> {
+	for tag in tags {
+		es.add_tag(ent, tag)?;
+	}
+
+	for (name, data) in components {
+		let registered = match es.component_registry().get(&name) {
+			Some(r) => r,
+			None => return Err(EntitySystemError::InvalidPrefab {reason: format!("Unknown component {}, it has to be registered first", name)}),
+		};
+
+		(registered.deserialize)(es, ent, data)?;
+	}
+
+	return Ok(());
+}
+
+//Objects are merged field by field so {"Position": {"x": 10.0}} only changes x, anything else is
+//replaced outright
+fn merge(base: &mut Value, overrides: Value) {
+	match (base, overrides) {
+		(Value::Object(base), Value::Object(overrides)) => {
+			for (key, value) in overrides {
+				match base.get_mut(&key) {
+					Some(existing) => merge(existing, value),
+					None => {
+						base.insert(key, value);
+					},
+				}
+			}
+		},
+		(_, Value::Null) => (), //Nothing to override
+		(base, overrides) => *base = overrides,
+	}
+}
+
+//Spawns one of the prefabs in the Prefabs resource, overrides are merged over its components
+//e.g. spawn_prefab(es, "grass", json!({"Position": {"x": 450.0}}))
+pub fn spawn_prefab(es: &mut EntitySystem, name: &str, overrides: Value) -> Result<Entity, EntitySystemError> {
+	let prefab = match es.resource::<Prefabs>()?.get(name) {
+		Some(p) => p.clone(),
+		None => return Err(EntitySystemError::NoSuchPrefab {name: name.to_string()}),
+	};
+
+	return prefab.spawn(es, overrides);
+}
+
+//Every prefab the game knows about, stored as a resource and keyed by file name without the .json
+pub struct Prefabs {
+	prefabs: HashMap<String, Prefab>,
+}
+
+impl Prefabs {
+	pub fn new() -> Self {
+		return Prefabs {prefabs: HashMap::new()};
+	}
+
+	pub fn load_dir(dir: &Path) -> Result<Prefabs, EntitySystemError> {
+		let entries = match fs::read_dir(dir) {
+			Ok(e) => e,
+			Err(e) => return Err(EntitySystemError::InvalidPrefab {reason: format!("Unable to read prefab directory {}: {}", dir.display(), e)}),
+		};
+
+		let mut prefabs = Prefabs::new();
+		for entry in entries.flatten() {
+			let path = entry.path();
+			if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+				continue;
+			}
+
+			let name = match path.file_stem() {
+				Some(n) => n.to_string_lossy().to_string(),
+				None => continue,
+			};
+
+			let json = match fs::read_to_string(&path) {
+				Ok(j) => j,
+				Err(e) => return Err(EntitySystemError::InvalidPrefab {reason: format!("Unable to read prefab {}: {}", path.display(), e)}),
+			};
+
+			match Prefab::parse(&json) {
+				Ok(p) => prefabs.insert(&name, p),
+				Err(e) => return Err(EntitySystemError::InvalidPrefab {reason: format!("{}: {}", path.display(), e)}),
+			}
+		}
+
+		return Ok(prefabs);
+	}
+
+	pub fn insert(&mut self, name: &str, prefab: Prefab) {
+		self.prefabs.insert(name.to_string(), prefab);
+	}
+
+	pub fn get(&self, name: &str) -> Option<&Prefab> {
+		return self.prefabs.get(name);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use serde_json::json;
+
+	use super::*;
+	use crate::components::{register_components, Direction, Moveable, Position};
+
+	fn world() -> EntitySystem {
+		let mut es = EntitySystem::new();
+		register_components(&mut es);
+		return es;
+	}
+
+	fn walker() -> Prefab {
+		return Prefab::parse(r#"{
+			"name": "Walker",
+			"tags": ["walker"],
+			"components": {
+				"Position": {"x": 1.0, "y": 2.0},
+				"Moveable": {"dx": 0.0, "dy": 0.0}
+			}
+		}"#).unwrap();
+	}
+
+	#[test]
+	fn overrides_are_merged_over_the_prefab() {
+		let mut es = world();
+		let ent = walker().spawn(&mut es, json!({"Position": {"x": 10.0}, "Direction": "Left"})).unwrap();
+
+		let position = es.get::<Position>(ent).unwrap();
+		assert_eq!((position.x, position.y), (10.0, 2.0));
+		assert_eq!(*es.get::<Direction>(ent).unwrap(), Direction::Left);
+		assert!(es.get::<Moveable>(ent).is_ok());
+		assert!(es.has_tag(ent, "walker"));
+		assert_eq!(es.name_of(ent), Some("Walker"));
+	}
+
+	#[test]
+	fn unknown_components_are_an_error() {
+		let mut es = world();
+		match walker().spawn(&mut es, json!({"Wings": {}})) {
+			Err(EntitySystemError::InvalidPrefab {reason}) => assert!(reason.contains("Wings")),
+			_ => panic!("expected an invalid prefab"),
+		}
+
+		assert!(es.entities().is_empty());
+	}
+
+	//Moveable goes in before Position fails, neither it nor the tag should be left behind
+	#[test]
+	fn failed_spawn_removes_the_entity() {
+		let mut es = world();
+		assert!(walker().spawn(&mut es, json!({"Position": {"x": "left a bit"}})).is_err());
+
+		assert!(es.entities().is_empty());
+		assert!(es.entities_with_name("Walker").is_empty());
+		assert!(es.entities_with_tag("walker").is_empty());
+		assert!(es.borrow_all_components_of_type::<Moveable>().unwrap().is_empty());
+	}
+}
@@ -0,0 +1,487 @@
+use std::sync::{RwLockReadGuard, RwLockWriteGuard};
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+
+use crate::entity_system::{Component, Entity, EntitySystem, EntitySystemError, SystemTicks};
+use crate::sparse_set::{ComponentTicks, SparseSet};
+
+//Something that can be fetched for an entity by a query, e.g. &Position, &mut Moveable,
+//Option<&Direction> or a tuple of them. The state is whatever borrows of the component stores
+//are needed and lives as long as the Query does. ticks are the running system's, for change
+//detection.
+pub trait QueryData {
+	type State<'w>;
+	type Item<'s>;
+
+	fn borrow<'w>(es: &'w EntitySystem, ticks: SystemTicks) -> Result<Self::State<'w>, EntitySystemError>;
+
+	//The ids of every entity that could possibly match, None if this part of the query doesn't
+	//restrict which entities match (Option<&T> for example)
+	fn candidates(state: &Self::State<'_>) -> Option<Vec<u64>>;
+
+	//None if the entity doesn't have everything the query requires
+	fn fetch<'s>(state: &'s mut Self::State<'_>, id: u64) -> Option<Self::Item<'s>>;
+}
+
+//Restricts which entities a query matches without fetching anything
+pub trait QueryFilter {
+	type State<'w>;
+
+	fn borrow<'w>(es: &'w EntitySystem, ticks: SystemTicks) -> Result<Self::State<'w>, EntitySystemError>;
+
+	fn matches(state: &Self::State<'_>, id: u64) -> bool;
+}
+
+//Only match entities that have a T. Filters borrow the store of T so don't filter on a type the
+//query also fetches mutably.
+pub struct With<T>(PhantomData<T>);
+
+//Only match entities that don't have a T
+pub struct Without<T>(PhantomData<T>);
+
+//Only match entities that got a T since the running system last ran
+#[cfg(test)]
+pub struct Added<T>(PhantomData<T>);
+
+//Only match entities whose T was added or written to since the running system last ran. Like
+//With this borrows the store of T, so to find changes to something the query also writes to
+//check the ticks yourself rather than using this filter.
+pub struct Changed<T>(PhantomData<T>);
+
+//What a query hands out for &mut T. Reading through it is free, writing through it marks the
+//component as changed, so Changed<T> only picks up components that were actually written to.
+pub struct Mut<'s, T> {
+	value: &'s mut T,
+	ticks: &'s mut ComponentTicks,
+	this_run: u64,
+}
+
+impl<'s, T> Mut<'s, T> {
+	#[cfg(test)]
+	pub fn is_added(&self) -> bool {
+		return self.ticks.added == self.this_run;
+	}
+
+	#[cfg(test)]
+	pub fn is_changed(&self) -> bool {
+		return self.ticks.changed == self.this_run;
+	}
+
+	//Writes without marking the component changed, for bookkeeping nothing watching for changes
+	//needs to know about
+	pub fn bypass_change_detection(&mut self) -> &mut T {
+		return self.value;
+	}
+}
+
+impl<'s, T> Deref for Mut<'s, T> {
+	type Target = T;
+
+	fn deref(&self) -> &T {
+		return self.value;
+	}
+}
+
+impl<'s, T> DerefMut for Mut<'s, T> {
+	fn deref_mut(&mut self) -> &mut T {
+		self.ticks.changed = self.this_run;
+		return self.value;
+	}
+}
+
+//A single component borrowed out of the EntitySystem by EntitySystem::get. Holds the read borrow
+//of the whole store, so drop it before trying to get the same type mutably.
+pub struct Ref<'w, T> {
+	store: RwLockReadGuard<'w, SparseSet<T>>,
+	id: u64,
+}
+
+impl<'w, T> Ref<'w, T> {
+	//Only made once we know the entity has a T
+	pub(crate) fn new(store: RwLockReadGuard<'w, SparseSet<T>>, id: u64) -> Self {
+		return Ref {store, id};
+	}
+}
+
+impl<'w, T> Deref for Ref<'w, T> {
+	type Target = T;
+
+	fn deref(&self) -> &T {
+		return self.store.get(self.id).expect("Ref to a component that was removed");
+	}
+}
+
+//Same as Ref but from EntitySystem::get_mut. Like Mut it only marks the component as changed
+//once it's written to.
+pub struct RefMut<'w, T> {
+	store: RwLockWriteGuard<'w, SparseSet<T>>,
+	id: u64,
+	tick: u64,
+}
+
+impl<'w, T> RefMut<'w, T> {
+	pub(crate) fn new(store: RwLockWriteGuard<'w, SparseSet<T>>, id: u64, tick: u64) -> Self {
+		return RefMut {store, id, tick};
+	}
+}
+
+impl<'w, T> Deref for RefMut<'w, T> {
+	type Target = T;
+
+	fn deref(&self) -> &T {
+		return self.store.get(self.id).expect("RefMut to a component that was removed");
+	}
+}
+
+impl<'w, T> DerefMut for RefMut<'w, T> {
+	fn deref_mut(&mut self) -> &mut T {
+		self.store.set_changed(self.id, self.tick);
+		return self.store.get_mut(self.id).expect("RefMut to a component that was removed");
+	}
+}
+
+//Not having a store for a component type just means no entity has one, so the borrows
+//below treat NoSuchComponent as an empty store rather than an error
+fn borrow_store<'w, T: Component>(es: &'w EntitySystem) -> Result<Option<RwLockReadGuard<'w, SparseSet<T>>>, EntitySystemError> {
+	match es.borrow_all_components_of_type::<T>() {
+		Ok(s) => return Ok(Some(s)),
+		Err(EntitySystemError::NoSuchComponent {..}) => return Ok(None),
+		Err(e) => return Err(e),
+	}
+}
+
+fn borrow_store_mut<'w, T: Component>(es: &'w EntitySystem) -> Result<Option<RwLockWriteGuard<'w, SparseSet<T>>>, EntitySystemError> {
+	match es.borrow_all_components_of_type_mut::<T>() {
+		Ok(s) => return Ok(Some(s)),
+		Err(EntitySystemError::NoSuchComponent {..}) => return Ok(None),
+		Err(e) => return Err(e),
+	}
+}
+
+impl<T: Component> QueryData for &T {
+	type State<'w> = Option<RwLockReadGuard<'w, SparseSet<T>>>;
+	type Item<'s> = &'s T;
+
+	fn borrow<'w>(es: &'w EntitySystem, _ticks: SystemTicks) -> Result<Self::State<'w>, EntitySystemError> {
+		return borrow_store::<T>(es);
+	}
+
+	fn candidates(state: &Self::State<'_>) -> Option<Vec<u64>> {
+		match state {
+			Some(store) => return Some(store.ids().to_vec()),
+			None => return Some(Vec::new()),
+		}
+	}
+
+	fn fetch<'s>(state: &'s mut Self::State<'_>, id: u64) -> Option<Self::Item<'s>> {
+		return state.as_ref()?.get(id);
+	}
+}
+
+impl<T: Component> QueryData for &mut T {
+	type State<'w> = (Option<RwLockWriteGuard<'w, SparseSet<T>>>, u64);
+	type Item<'s> = Mut<'s, T>;
+
+	fn borrow<'w>(es: &'w EntitySystem, ticks: SystemTicks) -> Result<Self::State<'w>, EntitySystemError> {
+		return Ok((borrow_store_mut::<T>(es)?, ticks.this_run));
+	}
+
+	fn candidates(state: &Self::State<'_>) -> Option<Vec<u64>> {
+		match &state.0 {
+			Some(store) => return Some(store.ids().to_vec()),
+			None => return Some(Vec::new()),
+		}
+	}
+
+	fn fetch<'s>(state: &'s mut Self::State<'_>, id: u64) -> Option<Self::Item<'s>> {
+		let (store, this_run) = state;
+		let (value, ticks) = store.as_mut()?.get_mut_with_ticks(id)?;
+		return Some(Mut {value, ticks, this_run: *this_run});
+	}
+}
+
+impl<Q: QueryData> QueryData for Option<Q> {
+	type State<'w> = Q::State<'w>;
+	type Item<'s> = Option<Q::Item<'s>>;
+
+	fn borrow<'w>(es: &'w EntitySystem, ticks: SystemTicks) -> Result<Self::State<'w>, EntitySystemError> {
+		return Q::borrow(es, ticks);
+	}
+
+	fn candidates(_state: &Self::State<'_>) -> Option<Vec<u64>> {
+		return None;
+	}
+
+	fn fetch<'s>(state: &'s mut Self::State<'_>, id: u64) -> Option<Self::Item<'s>> {
+		return Some(Q::fetch(state, id));
+	}
+}
+
+impl<T: Component> QueryFilter for With<T> {
+	type State<'w> = Option<RwLockReadGuard<'w, SparseSet<T>>>;
+
+	fn borrow<'w>(es: &'w EntitySystem, _ticks: SystemTicks) -> Result<Self::State<'w>, EntitySystemError> {
+		return borrow_store::<T>(es);
+	}
+
+	fn matches(state: &Self::State<'_>, id: u64) -> bool {
+		match state {
+			Some(store) => return store.contains(id),
+			None => return false,
+		}
+	}
+}
+
+impl<T: Component> QueryFilter for Without<T> {
+	type State<'w> = Option<RwLockReadGuard<'w, SparseSet<T>>>;
+
+	fn borrow<'w>(es: &'w EntitySystem, _ticks: SystemTicks) -> Result<Self::State<'w>, EntitySystemError> {
+		return borrow_store::<T>(es);
+	}
+
+	fn matches(state: &Self::State<'_>, id: u64) -> bool {
+		match state {
+			Some(store) => return !store.contains(id),
+			None => return true,
+		}
+	}
+}
+
+#[cfg(test)]
+impl<T: Component> QueryFilter for Added<T> {
+	type State<'w> = (Option<RwLockReadGuard<'w, SparseSet<T>>>, u64);
+
+	fn borrow<'w>(es: &'w EntitySystem, ticks: SystemTicks) -> Result<Self::State<'w>, EntitySystemError> {
+		return Ok((borrow_store::<T>(es)?, ticks.last_run));
+	}
+
+	fn matches(state: &Self::State<'_>, id: u64) -> bool {
+		let (store, last_run) = state;
+		match store.as_ref().and_then(|s| s.ticks(id)) {
+			Some(ticks) => return ticks.added > *last_run,
+			None => return false,
+		}
+	}
+}
+
+impl<T: Component> QueryFilter for Changed<T> {
+	type State<'w> = (Option<RwLockReadGuard<'w, SparseSet<T>>>, u64);
+
+	fn borrow<'w>(es: &'w EntitySystem, ticks: SystemTicks) -> Result<Self::State<'w>, EntitySystemError> {
+		return Ok((borrow_store::<T>(es)?, ticks.last_run));
+	}
+
+	fn matches(state: &Self::State<'_>, id: u64) -> bool {
+		let (store, last_run) = state;
+		match store.as_ref().and_then(|s| s.ticks(id)) {
+			Some(ticks) => return ticks.changed > *last_run,
+			None => return false,
+		}
+	}
+}
+
+impl QueryFilter for () {
+	type State<'w> = ();
+
+	fn borrow<'w>(_es: &'w EntitySystem, _ticks: SystemTicks) -> Result<Self::State<'w>, EntitySystemError> {
+		return Ok(());
+	}
+
+	fn matches(_state: &Self::State<'_>, _id: u64) -> bool {
+		return true;
+	}
+}
+
+macro_rules! impl_query_tuple {
+	($($name:ident),*) => {
+		#[allow(non_snake_case)]
+		impl<$($name: QueryData),*> QueryData for ($($name,)*) {
+			type State<'w> = ($($name::State<'w>,)*);
+			type Item<'s> = ($($name::Item<'s>,)*);
+
+			fn borrow<'w>(es: &'w EntitySystem, ticks: SystemTicks) -> Result<Self::State<'w>, EntitySystemError> {
+				return Ok(($($name::borrow(es, ticks)?,)*));
+			}
+
+			//Drive the query from the smallest store to keep the number of lookups down
+			fn candidates(state: &Self::State<'_>) -> Option<Vec<u64>> {
+				let ($($name,)*) = state;
+				let mut smallest: Option<Vec<u64>> = None;
+				$(
+					if let Some(ids) = $name::candidates($name) {
+						if smallest.as_ref().map_or(true, |s| ids.len() < s.len()) {
+							smallest = Some(ids);
+						}
+					}
+				)*
+				return smallest;
+			}
+
+			fn fetch<'s>(state: &'s mut Self::State<'_>, id: u64) -> Option<Self::Item<'s>> {
+				let ($($name,)*) = state;
+				return Some(($($name::fetch($name, id)?,)*));
+			}
+		}
+
+		#[allow(non_snake_case)]
+		impl<$($name: QueryFilter),*> QueryFilter for ($($name,)*) {
+			type State<'w> = ($($name::State<'w>,)*);
+
+			fn borrow<'w>(es: &'w EntitySystem, ticks: SystemTicks) -> Result<Self::State<'w>, EntitySystemError> {
+				return Ok(($($name::borrow(es, ticks)?,)*));
+			}
+
+			fn matches(state: &Self::State<'_>, id: u64) -> bool {
+				let ($($name,)*) = state;
+				return true $(&& $name::matches($name, id))*;
+			}
+		}
+	};
+}
+
+impl_query_tuple!(A);
+impl_query_tuple!(A, B);
+impl_query_tuple!(A, B, C);
+impl_query_tuple!(A, B, C, D);
+impl_query_tuple!(A, B, C, D, E);
+impl_query_tuple!(A, B, C, D, E, F);
+impl_query_tuple!(A, B, C, D, E, F, G);
+impl_query_tuple!(A, B, C, D, E, F, G, H);
+
+//Holds the borrows of every store the query touches until it's dropped. Build one with
+//EntitySystem::query or EntitySystem::query_filtered.
+pub struct Query<'w, Q: QueryData, F: QueryFilter = ()> {
+	es: &'w EntitySystem,
+	data: Q::State<'w>,
+	filter: F::State<'w>,
+}
+
+impl<'w, Q: QueryData, F: QueryFilter> Query<'w, Q, F> {
+	pub fn new(es: &'w EntitySystem) -> Result<Self, EntitySystemError> {
+		let ticks = es.system_ticks();
+		let data = Q::borrow(es, ticks)?;
+		let filter = F::borrow(es, ticks)?;
+		return Ok(Query {es, data, filter});
+	}
+
+	//Ids are sorted so iteration order doesn't depend on how the stores happen to be laid out
+	fn candidates(&self) -> Vec<u64> {
+		let mut ids = match Q::candidates(&self.data) {
+			Some(ids) => ids,
+			None => self.es.entities().iter().map(|e| e.index()).collect(),
+		};
+		ids.sort_unstable();
+		return ids;
+	}
+
+	pub fn for_each<Func>(&mut self, mut f: Func) where
+		Func: FnMut(Entity, Q::Item<'_>) {
+		for id in self.candidates() {
+			if !F::matches(&self.filter, id) {
+				continue;
+			}
+
+			let ent = match self.es.entity_for_index(id) {
+				Some(e) => e,
+				None => continue,
+			};
+
+			if let Some(item) = Q::fetch(&mut self.data, id) {
+				f(ent, item);
+			}
+		}
+	}
+
+	//Stops at the first error returned by the callback
+	pub fn try_for_each<E, Func>(&mut self, mut f: Func) -> Result<(), E> where
+		Func: FnMut(Entity, Q::Item<'_>) -> Result<(), E> {
+		for id in self.candidates() {
+			if !F::matches(&self.filter, id) {
+				continue;
+			}
+
+			let ent = match self.es.entity_for_index(id) {
+				Some(e) => e,
+				None => continue,
+			};
+
+			if let Some(item) = Q::fetch(&mut self.data, id) {
+				f(ent, item)?;
+			}
+		}
+
+		return Ok(());
+	}
+
+	pub fn get(&mut self, ent: Entity) -> Option<Q::Item<'_>> {
+		if !self.es.is_alive(ent) || !F::matches(&self.filter, ent.index()) {
+			return None;
+		}
+
+		return Q::fetch(&mut self.data, ent.index());
+	}
+
+	//Every entity the query currently matches
+	pub fn entities(&mut self) -> Vec<Entity> {
+		let mut matched = Vec::new();
+		self.for_each(|ent, _| matched.push(ent));
+		return matched;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::components::{Direction, Moveable, Position};
+
+	#[test]
+	fn with_and_without_together() {
+		let mut es = EntitySystem::new();
+		let walking = es.spawn((Position::new(0.0, 0.0), Moveable::new(1.0, 0.0))).unwrap();
+		let turned = es.spawn((Position::new(1.0, 0.0), Moveable::new(1.0, 0.0), Direction::Right)).unwrap();
+		es.spawn((Position::new(2.0, 0.0),)).unwrap();
+		es.spawn((Moveable::new(1.0, 0.0),)).unwrap();
+
+		let matched = es.query_filtered::<&Position, (With<Moveable>, Without<Direction>)>().unwrap().entities();
+		assert_eq!(matched, vec![walking]);
+
+		let matched = es.query_filtered::<&Position, (With<Moveable>, With<Direction>)>().unwrap().entities();
+		assert_eq!(matched, vec![turned]);
+	}
+
+	//What the named system sees as added, as if it just ran
+	fn added_for(es: &EntitySystem, system: &'static str) -> Vec<Entity> {
+		es.begin_system(system);
+		let added = es.query_filtered::<&Position, Added<Position>>().unwrap().entities();
+		es.end_system();
+		return added;
+	}
+
+	#[test]
+	fn added_skips_components_the_system_has_seen() {
+		let mut es = EntitySystem::new();
+		let first = es.spawn((Position::new(0.0, 0.0),)).unwrap();
+		assert_eq!(added_for(&es, "watcher"), vec![first]);
+
+		let second = es.spawn((Position::new(1.0, 0.0),)).unwrap();
+		es.with_component_mut(first, |position: &mut Position| position.x = 2.0).unwrap();
+		assert_eq!(added_for(&es, "watcher"), vec![second]);
+		assert!(added_for(&es, "watcher").is_empty());
+		assert_eq!(added_for(&es, "late"), vec![first, second]);
+	}
+
+	#[test]
+	fn mut_is_changed_once_written() {
+		let mut es = EntitySystem::new();
+		es.spawn((Position::new(0.0, 0.0),)).unwrap();
+
+		es.begin_system("mover");
+		es.query::<&mut Position>().unwrap().for_each(|_, mut position| {
+			assert!(!position.is_changed());
+			position.x += 1.0;
+			assert!(position.is_changed());
+			assert!(!position.is_added());
+		});
+		es.end_system();
+	}
+}
@@ -0,0 +1,201 @@
+use std::any::Any;
+
+use crate::entity_system::EntitySystemError;
+
+//Lets tools (inspectors, the console etc) look inside a component and edit it by field name
+//without knowing its type. Implement it for plain structs with reflect_struct!.
+pub trait Reflect: Any {
+	fn type_name(&self) -> &'static str;
+
+	fn field_names(&self) -> &'static [&'static str];
+
+	fn field(&self, name: &str) -> Option<&dyn Any>;
+
+	fn field_mut(&mut self, name: &str) -> Option<&mut dyn Any>;
+
+	//For showing the value to people, it's not meant to be parsed back
+	fn field_to_string(&self, name: &str) -> Option<String>;
+
+	#[cfg(test)]
+	fn as_any(&self) -> &dyn Any;
+
+	#[cfg(test)]
+	fn as_any_mut(&mut self) -> &mut dyn Any;
+
+	//Every field and its value on one line e.g. "x: 1.0, y: 2.0"
+	fn describe(&self) -> String {
+		return self.field_names().iter()
+			.map(|name| format!("{}: {}", name, self.field_to_string(name).unwrap_or_default()))
+			.collect::<Vec<String>>()
+			.join(", ");
+	}
+}
+
+impl dyn Reflect {
+	pub fn get<T: 'static>(&self, name: &str) -> Result<&T, EntitySystemError> {
+		let field = match self.field(name) {
+			Some(f) => f,
+			None => return Err(EntitySystemError::NoSuchField {component: self.type_name(), field: name.to_string()}),
+		};
+
+		match field.downcast_ref::<T>() {
+			Some(f) => return Ok(f),
+			None => return Err(EntitySystemError::DowncastFailed {entity: None, type_name: std::any::type_name::<T>()}),
+		}
+	}
+
+	pub fn set<T: 'static>(&mut self, name: &str, value: T) -> Result<(), EntitySystemError> {
+		let type_name = self.type_name();
+		let field = match self.field_mut(name) {
+			Some(f) => f,
+			None => return Err(EntitySystemError::NoSuchField {component: type_name, field: name.to_string()}),
+		};
+
+		match field.downcast_mut::<T>() {
+			Some(f) => *f = value,
+			None => return Err(EntitySystemError::DowncastFailed {entity: None, type_name: std::any::type_name::<T>()}),
+		}
+
+		return Ok(());
+	}
+}
+
+//reflect_struct!(Position { x, y }) implements Reflect for a struct with the listed fields, every
+//field has to implement Debug
+macro_rules! reflect_struct {
+	($type:ident { $($field:ident),* }) => {
+		impl $crate::reflect::Reflect for $type {
+			fn type_name(&self) -> &'static str {
+				return stringify!($type);
+			}
+
+			fn field_names(&self) -> &'static [&'static str] {
+				return &[$(stringify!($field)),*];
+			}
+
+			fn field(&self, name: &str) -> Option<&dyn std::any::Any> {
+				match name {
+					$(stringify!($field) => return Some(&self.$field),)*
+					_ => return None,
+				}
+			}
+
+			fn field_mut(&mut self, name: &str) -> Option<&mut dyn std::any::Any> {
+				match name {
+					$(stringify!($field) => return Some(&mut self.$field),)*
+					_ => return None,
+				}
+			}
+
+			fn field_to_string(&self, name: &str) -> Option<String> {
+				match name {
+					$(stringify!($field) => return Some(format!("{:?}", self.$field)),)*
+					_ => return None,
+				}
+			}
+
+			#[cfg(test)]
+			fn as_any(&self) -> &dyn std::any::Any {
+				return self;
+			}
+
+			#[cfg(test)]
+			fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+				return self;
+			}
+		}
+	};
+}
+
+pub(crate) use reflect_struct;
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::components::{register_components, Direction, Moveable, Parent, Position};
+	use crate::entity_system::EntitySystem;
+
+	#[test]
+	fn fields_by_name() {
+		let mut position = Position::new(1.0, 2.0);
+		let reflected: &mut dyn Reflect = &mut position;
+
+		assert_eq!(*reflected.get::<f64>("x").unwrap(), 1.0);
+		reflected.set("y", 3.0).unwrap();
+		assert_eq!(reflected.describe(), "x: 1.0, y: 3.0");
+
+		match reflected.set("x", 1) {
+			Err(EntitySystemError::DowncastFailed {..}) => (),
+			_ => panic!("an i32 isn't an f64"),
+		}
+		match reflected.get::<f64>("z") {
+			Err(EntitySystemError::NoSuchField {component, field}) => assert_eq!((component, field.as_str()), ("Position", "z")),
+			_ => panic!("expected no such field"),
+		}
+	}
+
+	struct Unreflected;
+
+	#[test]
+	fn components_of_skips_unreflected_components() {
+		let mut es = EntitySystem::new();
+		register_components(&mut es);
+		let ent = es.spawn((Position::new(1.0, 2.0), Unreflected, Moveable::new(0.0, 1.0))).unwrap();
+
+		let mut seen = Vec::new();
+		es.components_of(ent, |c| seen.push(format!("{} {{{}}}", c.type_name(), c.describe()))).unwrap();
+		assert_eq!(seen, vec!["Moveable {dx: 0.0, dy: 1.0}", "Position {x: 1.0, y: 2.0}"]);
+
+		let mut names = vec![std::any::type_name::<Position>(), std::any::type_name::<Unreflected>(), std::any::type_name::<Moveable>()];
+		names.sort_unstable();
+		assert_eq!(es.component_type_names(ent).unwrap(), names);
+	}
+
+	//Direction doesn't have any fields so it's edited as a whole
+	#[test]
+	fn components_without_fields_go_through_as_any() {
+		let mut es = EntitySystem::new();
+		register_components(&mut es);
+		let ent = es.spawn((Direction::Down,)).unwrap();
+
+		es.reflect_component_mut(ent, "Direction", |c| {
+			match c.as_any_mut().downcast_mut::<Direction>() {
+				Some(direction) => *direction = Direction::Left,
+				None => panic!("expected a Direction"),
+			}
+			return Ok(());
+		}).unwrap();
+
+		let mut seen = None;
+		es.components_of(ent, |c| seen = c.as_any().downcast_ref::<Direction>().cloned()).unwrap();
+		assert_eq!(seen, Some(Direction::Left));
+	}
+
+	#[test]
+	fn reflect_component_mut_edits_by_type_name() {
+		let mut es = EntitySystem::new();
+		register_components(&mut es);
+		let parent = es.spawn((Position::new(0.0, 0.0),)).unwrap();
+		let child = es.spawn((Position::new(1.0, 2.0),)).unwrap();
+		let other = es.new_entity().unwrap();
+		es.set_parent(child, parent).unwrap();
+
+		es.reflect_component_mut(child, "Position", |c| c.set("x", 5.0)).unwrap();
+		assert_eq!(es.get::<Position>(child).unwrap().x, 5.0);
+
+		match es.reflect_component_mut(child, "Moveable", |c| c.set("dx", 1.0)) {
+			Err(EntitySystemError::NoSuchComponent {entity, ..}) => assert_eq!(entity, Some(child)),
+			_ => panic!("the child doesn't have a Moveable"),
+		}
+
+		//Only set_parent can change the hierarchy
+		match es.reflect_component_mut(child, "Parent", |c| c.set("entity", other)) {
+			Err(EntitySystemError::ReadOnlyComponent {entity, component}) => {
+				assert_eq!(entity, child);
+				assert_eq!(component, std::any::type_name::<Parent>());
+			},
+			_ => panic!("Parent should be read only"),
+		}
+		assert_eq!(es.parent_of(child), Some(parent));
+	}
+}
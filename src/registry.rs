@@ -0,0 +1,220 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::entity_system::{Component, Entity, EntitySystem, EntitySystemError, Resource};
+use crate::sparse_set::SparseSet;
+use crate::reflect::Reflect;
+
+pub type DeserializeFn = fn(&mut EntitySystem, Entity, Value) -> Result<(), EntitySystemError>;
+type SerializeFn = fn(&EntitySystem) -> Result<Vec<(u64, Value)>, EntitySystemError>;
+type SnapshotFn = fn(&EntitySystem) -> Result<Option<Box<dyn Any + Send + Sync>>, EntitySystemError>;
+type RestoreFn = fn(&mut EntitySystem, Option<&(dyn Any + Send + Sync)>) -> Result<(), EntitySystemError>;
+type ResourceSnapshotFn = fn(&EntitySystem) -> Result<Option<Box<dyn Any + Send + Sync>>, EntitySystemError>;
+type ResourceRestoreFn = fn(&mut EntitySystem, Option<&(dyn Any + Send + Sync)>) -> Result<(), EntitySystemError>;
+type ReflectFn = fn(&EntitySystem, Entity, &mut dyn FnMut(&dyn Reflect)) -> Result<bool, EntitySystemError>;
+type ReflectMutFn = fn(&EntitySystem, Entity, &mut dyn FnMut(&mut dyn Reflect)) -> Result<bool, EntitySystemError>;
+
+//What the EntitySystem needs to build a component from data, or turn one into data, without
+//knowing its type
+#[derive(Copy, Clone)]
+pub struct RegisteredComponent {
+	pub type_id: TypeId,
+	pub deserialize: DeserializeFn,
+	pub serialize: SerializeFn, //Every component of the type, keyed by entity index
+	pub snapshot: SnapshotFn, //A copy of the whole store, None if there isn't one
+	pub restore: RestoreFn, //Puts back what snapshot returned
+}
+
+//A resource that goes into snapshots, works like the snapshot and restore of a component store
+#[derive(Copy, Clone)]
+pub struct RegisteredResource {
+	pub type_id: TypeId,
+	pub snapshot: ResourceSnapshotFn, //A copy of the resource, None if it isn't there
+	pub restore: ResourceRestoreFn,
+}
+
+//Hands an entity's component to the callback as a Reflect, false if the entity doesn't have one
+#[derive(Copy, Clone)]
+pub struct ReflectedComponent {
+	pub reflect: ReflectFn,
+	pub reflect_mut: Option<ReflectMutFn>, //None if it's read only
+}
+
+//Components that can be described by data, keyed by the name used for them in files e.g. "Position".
+//Reflection is registered separately since not everything that can be saved has to be inspectable.
+#[derive(Clone)]
+pub struct ComponentRegistry {
+	components: HashMap<String, RegisteredComponent>,
+	names: HashMap<TypeId, String>,
+	reflected: HashMap<TypeId, ReflectedComponent>,
+	resources: HashMap<TypeId, RegisteredResource>,
+}
+
+impl ComponentRegistry {
+	pub fn new() -> Self {
+		return ComponentRegistry {components: HashMap::new(), names: HashMap::new(), reflected: HashMap::new(), resources: HashMap::new()};
+	}
+
+	pub fn register<ComponentType: Component + Clone + Serialize + DeserializeOwned>(&mut self, name: &str) {
+		self.register_with::<ComponentType>(name, deserialize_component::<ComponentType>);
+	}
+
+	//For components that can't just be added as they are when they're read in
+	pub fn register_with<ComponentType: Component + Clone + Serialize>(&mut self, name: &str, deserialize: DeserializeFn) {
+		self.components.insert(name.to_string(), RegisteredComponent {
+			type_id: TypeId::of::<ComponentType>(),
+			deserialize,
+			serialize: serialize_components::<ComponentType>,
+			snapshot: snapshot_components::<ComponentType>,
+			restore: restore_components::<ComponentType>,
+		});
+		self.names.insert(TypeId::of::<ComponentType>(), name.to_string());
+	}
+
+	pub fn register_reflect<ComponentType: Component + Reflect>(&mut self) {
+		self.reflected.insert(TypeId::of::<ComponentType>(), ReflectedComponent {reflect: reflect_component::<ComponentType>, reflect_mut: Some(reflect_component_mut::<ComponentType>)});
+	}
+
+	pub fn register_reflect_read_only<ComponentType: Component + Reflect>(&mut self) {
+		self.reflected.insert(TypeId::of::<ComponentType>(), ReflectedComponent {reflect: reflect_component::<ComponentType>, reflect_mut: None});
+	}
+
+	//Resources aren't saved, they only need to be registered to be part of snapshots
+	pub fn register_resource<ResourceType: Resource + Clone>(&mut self) {
+		self.resources.insert(TypeId::of::<ResourceType>(), RegisteredResource {type_id: TypeId::of::<ResourceType>(), snapshot: snapshot_resource::<ResourceType>, restore: restore_resource::<ResourceType>});
+	}
+
+	pub fn resources(&self) -> Vec<RegisteredResource> {
+		return self.resources.values().copied().collect();
+	}
+
+	pub fn reflected(&self, type_id: TypeId) -> Option<ReflectedComponent> {
+		return self.reflected.get(&type_id).copied();
+	}
+
+	pub fn get(&self, name: &str) -> Option<RegisteredComponent> {
+		return self.components.get(name).copied();
+	}
+
+	//The name a component type was registered under
+	pub fn name_of(&self, type_id: TypeId) -> Option<&str> {
+		return self.names.get(&type_id).map(|n| n.as_str());
+	}
+
+	//Sorted by name so anything done to every registered component happens in the same order
+	pub fn all(&self) -> Vec<RegisteredComponent> {
+		return self.names().iter().filter_map(|n| self.get(n)).collect();
+	}
+
+	pub fn names(&self) -> Vec<&str> {
+		let mut names = self.components.keys().map(|n| n.as_str()).collect::<Vec<&str>>();
+		names.sort_unstable();
+		return names;
+	}
+}
+
+fn deserialize_component<ComponentType: Component + DeserializeOwned>(es: &mut EntitySystem, ent: Entity, data: Value) -> Result<(), EntitySystemError> {
+	let component = match serde_json::from_value::<ComponentType>(data) {
+		Ok(c) => c,
+		Err(e) => return Err(EntitySystemError::InvalidComponentData {entity: Some(ent), component: std::any::type_name::<ComponentType>(), reason: e.to_string()}),
+	};
+
+	return es.add_component_to_entity(ent, component);
+}
+
+fn serialize_components<ComponentType: Component + Serialize>(es: &EntitySystem) -> Result<Vec<(u64, Value)>, EntitySystemError> {
+	let store = match es.borrow_all_components_of_type::<ComponentType>() {
+		Ok(s) => s,
+		Err(EntitySystemError::NoSuchComponent {..}) => return Ok(Vec::new()),
+		Err(e) => return Err(e),
+	};
+
+	let mut serialized = Vec::new();
+	for (id, component) in store.iter() {
+		match serde_json::to_value(component) {
+			Ok(v) => serialized.push((id, v)),
+			Err(e) => return Err(EntitySystemError::InvalidComponentData {entity: es.entity_for_index(id), component: std::any::type_name::<ComponentType>(), reason: e.to_string()}),
+		}
+	}
+
+	return Ok(serialized);
+}
+
+fn snapshot_components<ComponentType: Component + Clone>(es: &EntitySystem) -> Result<Option<Box<dyn Any + Send + Sync>>, EntitySystemError> {
+	match es.borrow_all_components_of_type::<ComponentType>() {
+		Ok(store) => return Ok(Some(Box::new(store.clone()))),
+		Err(EntitySystemError::NoSuchComponent {..}) => return Ok(None),
+		Err(e) => return Err(e),
+	}
+}
+
+fn restore_components<ComponentType: Component + Clone>(es: &mut EntitySystem, snapshot: Option<&(dyn Any + Send + Sync)>) -> Result<(), EntitySystemError> {
+	let store = match snapshot {
+		Some(s) => s,
+		None => {
+			es.replace_component_store::<ComponentType>(None);
+			return Ok(());
+		},
+	};
+
+	match store.downcast_ref::<SparseSet<ComponentType>>() {
+		Some(s) => es.replace_component_store(Some(s.clone())),
+		None => return Err(EntitySystemError::DowncastFailed {entity: None, type_name: std::any::type_name::<ComponentType>()}),
+	}
+
+	return Ok(());
+}
+
+fn snapshot_resource<ResourceType: Resource + Clone>(es: &EntitySystem) -> Result<Option<Box<dyn Any + Send + Sync>>, EntitySystemError> {
+	match es.resource::<ResourceType>() {
+		Ok(resource) => return Ok(Some(Box::new(resource.clone()))),
+		Err(EntitySystemError::NoSuchResource {..}) => return Ok(None),
+		Err(e) => return Err(e),
+	}
+}
+
+fn restore_resource<ResourceType: Resource + Clone>(es: &mut EntitySystem, snapshot: Option<&(dyn Any + Send + Sync)>) -> Result<(), EntitySystemError> {
+	let resource = match snapshot {
+		Some(s) => s,
+		None => {
+			es.remove_resource::<ResourceType>();
+			return Ok(());
+		},
+	};
+
+	match resource.downcast_ref::<ResourceType>() {
+		Some(r) => es.insert_resource(r.clone()),
+		None => return Err(EntitySystemError::DowncastFailed {entity: None, type_name: std::any::type_name::<ResourceType>()}),
+	};
+
+	return Ok(());
+}
+
+fn reflect_component<ComponentType: Component + Reflect>(es: &EntitySystem, ent: Entity, f: &mut dyn FnMut(&dyn Reflect)) -> Result<bool, EntitySystemError> {
+	let store = es.borrow_all_components_of_type::<ComponentType>()?;
+	match store.get(ent.index()) {
+		Some(c) => f(c),
+		None => return Ok(false),
+	}
+
+	return Ok(true);
+}
+
+//Assumes the callback changes the component
+fn reflect_component_mut<ComponentType: Component + Reflect>(es: &EntitySystem, ent: Entity, f: &mut dyn FnMut(&mut dyn Reflect)) -> Result<bool, EntitySystemError> {
+	let tick = es.system_ticks().this_run;
+	let mut store = es.borrow_all_components_of_type_mut::<ComponentType>()?;
+	match store.get_mut_with_ticks(ent.index()) {
+		Some((c, ticks)) => {
+			ticks.changed = tick;
+			f(c);
+		},
+		None => return Ok(false),
+	}
+
+	return Ok(true);
+}
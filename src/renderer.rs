@@ -0,0 +1,23 @@
+use std::path::Path;
+
+use sdl2::image::LoadTexture;
+use sdl2::render::{Canvas, Texture};
+use sdl2::video::Window;
+
+//Everything needed to draw the world, stored as a resource in the EntitySystem. Without one the
+//world is just data and can be run headless (tests, servers etc).
+pub struct Renderer {
+    //With unsafe_textures a texture isn't tied to its creator's lifetime and has no Drop, SDL frees
+    //it along with the canvas it belongs to
+    pub texture: Texture,
+    pub canvas: Canvas<Window>,
+}
+
+impl Renderer {
+    pub fn new(canvas: Canvas<Window>, texture_path: &Path) -> Result<Renderer, String> {
+        let texture_creator = canvas.texture_creator();
+        let texture = texture_creator.load_texture(texture_path)?;
+
+        return Ok(Renderer {texture, canvas});
+    }
+}
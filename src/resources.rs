@@ -0,0 +1,35 @@
+//Global state stored in the EntitySystem with insert_resource rather than attached to an entity
+
+//Frame timing, updated by the game loop before the systems run
+#[derive(Clone)]
+pub struct Time {
+    pub delta: f64, //Seconds since the previous frame
+    pub elapsed: f64, //Seconds since the game started
+    pub frame: usize,
+}
+
+impl Time {
+    pub fn new() -> Self {
+        return Time {delta: 0.0, elapsed: 0.0, frame: 0};
+    }
+
+    pub fn advance(&mut self, delta: f64) {
+        self.delta = delta;
+        self.elapsed += delta;
+        self.frame += 1;
+    }
+}
+
+//Tunables for the match
+#[derive(Clone)]
+pub struct GameRules {
+    pub max_speed: f64, //Pixels per second a moveable travels at full speed
+    pub bomb_range: i32, //How many tiles the flames reach in each direction
+    pub flame_time: f64, //Seconds the flames burn for
+}
+
+impl GameRules {
+    pub fn new() -> Self {
+        return GameRules {max_speed: 400.0, bomb_range: 2, flame_time: 0.5};
+    }
+}
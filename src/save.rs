@@ -0,0 +1,55 @@
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::entity_system::EntitySystemError;
+
+//A whole EntitySystem as data, see EntitySystem::save and EntitySystem::load. The entity
+//allocator is saved as is so entities keep their index and generation and any handles stored in
+//components (Parent, Children) still point at the right entity after loading.
+#[derive(Serialize, Deserialize)]
+pub struct WorldSave {
+	pub next_index: u64,
+	pub generations: Vec<u32>,
+	pub free_indices: Vec<u64>,
+	pub entities: Vec<SavedEntity>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SavedEntity {
+	pub index: u64,
+	pub generation: u32,
+	pub name: String,
+	#[serde(default)]
+	pub tags: Vec<String>,
+	#[serde(default)]
+	pub components: Map<String, Value>, //Keyed by the name the component was registered under
+}
+
+impl WorldSave {
+	pub fn write(&self, path: &Path) -> Result<(), EntitySystemError> {
+		let json = match serde_json::to_string_pretty(self) {
+			Ok(j) => j,
+			Err(e) => return Err(EntitySystemError::InvalidSave {reason: format!("Unable to serialize world: {}", e)}),
+		};
+
+		match fs::write(path, json) {
+			Ok(_) => return Ok(()),
+			Err(e) => return Err(EntitySystemError::InvalidSave {reason: format!("Unable to write {}: {}", path.display(), e)}),
+		}
+	}
+
+	pub fn read(path: &Path) -> Result<WorldSave, EntitySystemError> {
+		let json = match fs::read_to_string(path) {
+			Ok(j) => j,
+			Err(e) => return Err(EntitySystemError::InvalidSave {reason: format!("Unable to read {}: {}", path.display(), e)}),
+		};
+
+		match serde_json::from_str::<WorldSave>(&json) {
+			Ok(s) => return Ok(s),
+			Err(e) => return Err(EntitySystemError::InvalidSave {reason: format!("Unable to parse {}: {}", path.display(), e)}),
+		}
+	}
+}
@@ -0,0 +1,332 @@
+use std::error::Error;
+use std::fmt;
+
+use sdl2::event::Event;
+
+use crate::entity_system::{EntitySystem, EntitySystemError, Resource};
+use crate::schedule::ScheduleError;
+
+#[derive(Debug)]
+pub enum SceneError {
+    EntitySystemError(EntitySystemError),
+    ScheduleError(ScheduleError),
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SceneError::EntitySystemError(_) => {
+                write!(f, "SceneError:EntitySystemError")
+            },
+            SceneError::ScheduleError(_) => {
+                write!(f, "SceneError:ScheduleError")
+            },
+        }
+    }
+}
+
+impl Error for SceneError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SceneError::EntitySystemError(ese) => return Some(ese),
+            SceneError::ScheduleError(se) => return Some(se),
+        }
+    }
+}
+
+impl From<EntitySystemError> for SceneError {
+    fn from(err: EntitySystemError) -> Self {
+        SceneError::EntitySystemError(err)
+    }
+}
+
+impl From<ScheduleError> for SceneError {
+    fn from(err: ScheduleError) -> Self {
+        SceneError::ScheduleError(err)
+    }
+}
+
+//What a scene wants the SceneManager to do next
+pub enum Transition {
+    None,
+    Push(Box<dyn Scene>), //Pause this scene and run the new one on top of it
+    Pop, //Exit this scene and resume the one under it
+    Switch(Box<dyn Scene>), //Exit this scene and run the new one in its place
+    Quit, //Exit every scene
+}
+
+//Each scene gets its own world, made fresh when the scene is pushed and dropped when it exits.
+//Only the scene on top of the stack gets events and updates, the ones under it are paused.
+pub trait Scene {
+    fn name(&self) -> &'static str;
+
+    //Set up the world, the shared resources are already in it
+    fn enter(&mut self, es: &mut EntitySystem) -> Result<(), SceneError>;
+
+    //Called before the world is dropped, the shared resources are still in it
+    fn exit(&mut self, _es: &mut EntitySystem) -> Result<(), SceneError> {
+        return Ok(());
+    }
+
+    //Another scene was pushed on top, the shared resources are taken out of the world after this
+    fn pause(&mut self, _es: &mut EntitySystem) -> Result<(), SceneError> {
+        return Ok(());
+    }
+
+    //The scene on top was popped and the shared resources are back
+    fn resume(&mut self, _es: &mut EntitySystem) -> Result<(), SceneError> {
+        return Ok(());
+    }
+
+    fn handle_event(&mut self, _es: &mut EntitySystem, _event: &Event) -> Result<Transition, SceneError> {
+        return Ok(Transition::None);
+    }
+
+    fn update(&mut self, es: &mut EntitySystem, dt: f64) -> Result<Transition, SceneError>;
+}
+
+//Moves one shared resource from one world to another, if the first one has it
+type ShareFn = fn(&mut EntitySystem, &mut EntitySystem) -> Result<(), EntitySystemError>;
+
+fn move_resource<ResourceType: Resource>(from: &mut EntitySystem, to: &mut EntitySystem) -> Result<(), EntitySystemError> {
+    if let Some(r) = from.remove_resource::<ResourceType>() {
+        to.insert_resource(r);
+    }
+    return Ok(());
+}
+
+fn move_non_send_resource<ResourceType: 'static>(from: &mut EntitySystem, to: &mut EntitySystem) -> Result<(), EntitySystemError> {
+    if let Some(r) = from.remove_non_send_resource::<ResourceType>()? {
+        to.insert_non_send_resource(r)?;
+    }
+    return Ok(());
+}
+
+//Hands every shared resource from one world to the other. One failing doesn't stop the rest from
+//being moved, the first error is returned once they've all been tried.
+fn move_shared(shares: &[ShareFn], from: &mut EntitySystem, to: &mut EntitySystem) -> Result<(), EntitySystemError> {
+    let mut first_error = None;
+    for share in shares {
+        if let Err(e) = share(from, to) {
+            first_error.get_or_insert(e);
+        }
+    }
+
+    match first_error {
+        Some(e) => return Err(e),
+        None => return Ok(()),
+    }
+}
+
+struct ActiveScene {
+    scene: Box<dyn Scene>,
+    world: EntitySystem,
+}
+
+//Owns a stack of scenes and their worlds. Resources that outlive any one scene, like the
+//Renderer, are shared: they live in the world of the scene on top of the stack so systems find
+//them like any other resource, and are handed on whenever the top scene changes.
+pub struct SceneManager {
+    stack: Vec<ActiveScene>,
+    shared: EntitySystem, //Holds the shared resources while there's no scene to give them to
+    shares: Vec<ShareFn>,
+}
+
+impl SceneManager {
+    pub fn new() -> Self {
+        return SceneManager {stack: Vec::new(), shared: EntitySystem::new(), shares: Vec::new()};
+    }
+
+    pub fn share_resource<ResourceType: Resource>(&mut self, resource: ResourceType) {
+        match self.stack.last_mut() {
+            Some(top) => top.world.insert_resource(resource),
+            None => self.shared.insert_resource(resource),
+        };
+        self.shares.push(move_resource::<ResourceType>);
+    }
+
+    pub fn share_non_send_resource<ResourceType: 'static>(&mut self, resource: ResourceType) -> Result<(), EntitySystemError> {
+        match self.stack.last_mut() {
+            Some(top) => top.world.insert_non_send_resource(resource)?,
+            None => self.shared.insert_non_send_resource(resource)?,
+        };
+        self.shares.push(move_non_send_resource::<ResourceType>);
+        return Ok(());
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.stack.is_empty();
+    }
+
+    pub fn push(&mut self, scene: Box<dyn Scene>) -> Result<(), SceneError> {
+        if let Some(top) = self.stack.last_mut() {
+            top.scene.pause(&mut top.world)?;
+            move_shared(&self.shares, &mut top.world, &mut self.shared)?;
+        }
+
+        //The new scene never started so carry on with the old one
+        if let Err(e) = self.enter(scene) {
+            if let Some(top) = self.stack.last_mut() {
+                move_shared(&self.shares, &mut self.shared, &mut top.world)?;
+                top.scene.resume(&mut top.world)?;
+            }
+            return Err(e);
+        }
+
+        return Ok(());
+    }
+
+    //Exits the top scene and resumes the one under it. Does nothing if there aren't any scenes.
+    pub fn pop(&mut self) -> Result<(), SceneError> {
+        self.exit_top()?;
+
+        if let Some(top) = self.stack.last_mut() {
+            move_shared(&self.shares, &mut self.shared, &mut top.world)?;
+            top.scene.resume(&mut top.world)?;
+        }
+
+        return Ok(());
+    }
+
+    //Exits the top scene and enters the new one without resuming anything in between
+    pub fn switch(&mut self, scene: Box<dyn Scene>) -> Result<(), SceneError> {
+        self.exit_top()?;
+        return self.enter(scene);
+    }
+
+    //Exits every scene from the top down. The ones underneath aren't resumed on the way but do get
+    //the shared resources back for their exit. A scene failing to exit doesn't stop the rest, the
+    //first error is returned once they've all gone.
+    pub fn clear(&mut self) -> Result<(), SceneError> {
+        let mut first_error = None;
+        while !self.stack.is_empty() {
+            if let Err(e) = self.exit_top() {
+                first_error.get_or_insert(e);
+            }
+
+            if let Some(top) = self.stack.last_mut() {
+                if let Err(e) = move_shared(&self.shares, &mut self.shared, &mut top.world) {
+                    first_error.get_or_insert(SceneError::from(e));
+                }
+            }
+        }
+
+        match first_error {
+            Some(e) => return Err(e),
+            None => return Ok(()),
+        }
+    }
+
+    fn enter(&mut self, mut scene: Box<dyn Scene>) -> Result<(), SceneError> {
+        println!("Entering scene {}", scene.name());
+        let mut world = EntitySystem::new();
+        move_shared(&self.shares, &mut self.shared, &mut world)?;
+
+        if let Err(e) = scene.enter(&mut world) {
+            move_shared(&self.shares, &mut world, &mut self.shared)?;
+            return Err(e);
+        }
+
+        self.stack.push(ActiveScene {scene, world});
+        return Ok(());
+    }
+
+    fn exit_top(&mut self) -> Result<(), SceneError> {
+        let mut top = match self.stack.pop() {
+            Some(t) => t,
+            None => return Ok(()),
+        };
+
+        println!("Exiting scene {}", top.scene.name());
+        let exited = top.scene.exit(&mut top.world);
+        //Whatever happened the shared resources have to come back before the world is dropped
+        let moved = move_shared(&self.shares, &mut top.world, &mut self.shared);
+        exited?;
+        moved?;
+        return Ok(());
+    }
+
+    pub fn apply(&mut self, transition: Transition) -> Result<(), SceneError> {
+        match transition {
+            Transition::None => return Ok(()),
+            Transition::Push(scene) => return self.push(scene),
+            Transition::Pop => return self.pop(),
+            Transition::Switch(scene) => return self.switch(scene),
+            Transition::Quit => return self.clear(),
+        }
+    }
+
+    pub fn handle_event(&mut self, event: &Event) -> Result<(), SceneError> {
+        let transition = match self.stack.last_mut() {
+            Some(top) => top.scene.handle_event(&mut top.world, event)?,
+            None => return Ok(()),
+        };
+
+        return self.apply(transition);
+    }
+
+    pub fn update(&mut self, dt: f64) -> Result<(), SceneError> {
+        let transition = match self.stack.last_mut() {
+            Some(top) => top.scene.update(&mut top.world, dt)?,
+            None => return Ok(()),
+        };
+
+        return self.apply(transition);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    //Stands in for the Renderer
+    struct Screen;
+
+    //Writes down whether it had the Screen when it exited
+    struct TestScene {
+        name: &'static str,
+        fail_exit: bool,
+        exits: Arc<Mutex<Vec<(&'static str, bool)>>>,
+    }
+
+    impl Scene for TestScene {
+        fn name(&self) -> &'static str {
+            return self.name;
+        }
+
+        fn enter(&mut self, _es: &mut EntitySystem) -> Result<(), SceneError> {
+            return Ok(());
+        }
+
+        fn exit(&mut self, es: &mut EntitySystem) -> Result<(), SceneError> {
+            self.exits.lock().unwrap().push((self.name, es.has_resource::<Screen>()));
+            if self.fail_exit {
+                return Err(SceneError::EntitySystemError(EntitySystemError::NoSuchName {name: self.name.to_string()}));
+            }
+            return Ok(());
+        }
+
+        fn update(&mut self, _es: &mut EntitySystem, _dt: f64) -> Result<Transition, SceneError> {
+            return Ok(Transition::None);
+        }
+    }
+
+    #[test]
+    fn clear_exits_every_scene_when_one_fails() {
+        let exits = Arc::new(Mutex::new(Vec::new()));
+        let mut scenes = SceneManager::new();
+        scenes.share_resource(Screen);
+        for (name, fail_exit) in [("bottom", false), ("middle", true), ("top", true)] {
+            scenes.push(Box::new(TestScene {name, fail_exit, exits: exits.clone()})).unwrap();
+        }
+
+        match scenes.clear() {
+            Err(SceneError::EntitySystemError(EntitySystemError::NoSuchName {name})) => assert_eq!(name, "top"),
+            _ => panic!("expected the top scene's error"),
+        }
+
+        assert!(scenes.is_empty());
+        assert_eq!(*exits.lock().unwrap(), vec![("top", true), ("middle", true), ("bottom", true)]);
+    }
+}
@@ -0,0 +1,745 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
+
+use serde_json::json;
+
+use crate::components::{register_components, Animation, AnimationType, Position, Moveable, Drawable, Animations, Direction, LocalPosition, Parent, Children, PlayerBundle, Fuse, Flame};
+use crate::entity_system::{Entity, EntitySystem, EntitySystemError};
+use crate::events::{BombExploded, PlayerDied};
+use crate::renderer::Renderer;
+use crate::resources::{Time, GameRules};
+use crate::scene::{Scene, SceneError, Transition};
+use crate::snapshot::WorldSnapshot;
+use crate::schedule::{Executor, Schedule, ScheduleBuilder, ScheduleError, SystemDescriptor, Stage};
+use crate::prefabs::spawn_prefab;
+use crate::spatial::{rebuild_spatial_grid, GridCell, SpatialGrid};
+use crate::systems::{system_moveable, system_animation, system_drawable, system_direction, system_transform, system_spatial_grid, system_fuse, system_explosion, system_flames};
+
+//Tiles are 16 pixels in the texture and drawn at twice the size
+const TILE_SIZE: f64 = 32.0;
+
+//Where F5 saves the arena to and F9 loads it back from
+const SAVE_PATH: &str = "world_save.json";
+
+//Everything a fresh world needs before anything can be spawned into it. Prefabs and the Renderer
+//are shared so they're already there.
+fn setup_world(es: &mut EntitySystem) {
+    es.insert_resource(Time::new());
+    es.insert_resource(GameRules::new());
+    es.register_snapshot_resource::<Time>();
+    es.register_snapshot_resource::<GameRules>();
+    es.add_event::<BombExploded>();
+    es.add_event::<PlayerDied>();
+    es.register_reload_hook(rebuild_spatial_grid);
+    register_components(es);
+}
+
+fn advance_time(es: &EntitySystem, dt: f64) {
+    match es.resource_mut::<Time>() {
+        Ok(mut time) => time.advance(dt),
+        Err(e) => println!("Unable to update frame time:{}", e),
+    }
+}
+
+//The menu screens don't have anything moving about, they only need to animate and draw
+fn menu_schedule() -> Result<Schedule, ScheduleError> {
+    return ScheduleBuilder::new()
+        .add_system(SystemDescriptor::new("system_animation", Stage::PostUpdate, system_animation)
+            .reads::<Moveable>().reads::<Direction>().writes::<Animations>())
+        .add_system(SystemDescriptor::new("system_drawable", Stage::Render, system_drawable)
+            .after("system_animation")
+            .main_thread()
+            .reads::<Drawable>().reads::<Animations>().reads::<Position>().writes::<Renderer>())
+        .strict()
+        .build();
+}
+
+//The player standing on some grass in the middle of the screen
+fn spawn_menu_scenery(es: &mut EntitySystem) -> Result<(), SceneError> {
+    spawn_prefab(es, "grass", json!({"Position": {"x": 640.0, "y": 360.0}}))?;
+
+    //Only ever stands there so it just needs the one animation
+    let standing = Animation::new_with_frames(vec![Drawable::new(16, 272, 15, 15, 1)], 0.0, false, false);
+    es.spawn_with_name("Player".to_string(), PlayerBundle {
+        position: Position::new(640.0, 360.0),
+        moveable: Moveable::new(0.0, 0.0),
+        direction: Direction::Down,
+        animations: Animations::new(AnimationType::StandingDown, HashMap::from([(AnimationType::StandingDown, standing)])),
+    })?;
+    return Ok(());
+}
+
+//Title screen, just the player standing on some grass until someone presses a key
+pub struct MainMenu {
+    schedule: Schedule,
+}
+
+impl MainMenu {
+    pub fn new() -> Result<Self, ScheduleError> {
+        return Ok(MainMenu {schedule: menu_schedule()?});
+    }
+
+    fn print_help(&self) {
+        println!("Main menu: Return to play, Escape to quit");
+    }
+}
+
+impl Scene for MainMenu {
+    fn name(&self) -> &'static str {
+        return "MainMenu";
+    }
+
+    fn enter(&mut self, es: &mut EntitySystem) -> Result<(), SceneError> {
+        setup_world(es);
+        spawn_menu_scenery(es)?;
+
+        self.print_help();
+        return Ok(());
+    }
+
+    fn resume(&mut self, _es: &mut EntitySystem) -> Result<(), SceneError> {
+        self.print_help();
+        return Ok(());
+    }
+
+    fn handle_event(&mut self, _es: &mut EntitySystem, event: &Event) -> Result<Transition, SceneError> {
+        match event {
+            Event::KeyDown {keycode: Some(Keycode::Return), repeat: false, ..} => {
+                return Ok(Transition::Push(Box::new(Lobby::new()?)));
+            },
+            Event::KeyDown {keycode: Some(Keycode::Escape), ..} => {
+                println!("Quiting");
+                return Ok(Transition::Quit);
+            },
+            _ => return Ok(Transition::None),
+        }
+    }
+
+    fn update(&mut self, es: &mut EntitySystem, dt: f64) -> Result<Transition, SceneError> {
+        advance_time(es, dt);
+        self.schedule.run(es, dt)?;
+        return Ok(Transition::None);
+    }
+}
+
+//Waiting to start the match, the arena takes its place once it does
+pub struct Lobby {
+    schedule: Schedule,
+}
+
+impl Lobby {
+    pub fn new() -> Result<Self, ScheduleError> {
+        return Ok(Lobby {schedule: menu_schedule()?});
+    }
+}
+
+impl Scene for Lobby {
+    fn name(&self) -> &'static str {
+        return "Lobby";
+    }
+
+    fn enter(&mut self, es: &mut EntitySystem) -> Result<(), SceneError> {
+        setup_world(es);
+        spawn_menu_scenery(es)?;
+
+        println!("Lobby: Return to start the match, Escape to go back to the menu");
+        return Ok(());
+    }
+
+    fn handle_event(&mut self, _es: &mut EntitySystem, event: &Event) -> Result<Transition, SceneError> {
+        match event {
+            Event::KeyDown {keycode: Some(Keycode::Return), repeat: false, ..} => {
+                return Ok(Transition::Switch(Box::new(Arena::new()?)));
+            },
+            Event::KeyDown {keycode: Some(Keycode::Escape), ..} => return Ok(Transition::Pop),
+            _ => return Ok(Transition::None),
+        }
+    }
+
+    fn update(&mut self, es: &mut EntitySystem, dt: f64) -> Result<Transition, SceneError> {
+        advance_time(es, dt);
+        self.schedule.run(es, dt)?;
+        return Ok(Transition::None);
+    }
+}
+
+//After the match, replaces the arena and goes back to the menu under it
+pub struct Results {
+    schedule: Schedule,
+    duration: f64, //Seconds the match lasted
+}
+
+impl Results {
+    pub fn new(duration: f64) -> Result<Self, ScheduleError> {
+        return Ok(Results {schedule: menu_schedule()?, duration});
+    }
+}
+
+impl Scene for Results {
+    fn name(&self) -> &'static str {
+        return "Results";
+    }
+
+    fn enter(&mut self, es: &mut EntitySystem) -> Result<(), SceneError> {
+        setup_world(es);
+        spawn_menu_scenery(es)?;
+
+        println!("Results: the match lasted {:.1} seconds, Return to go back to the menu", self.duration);
+        return Ok(());
+    }
+
+    fn handle_event(&mut self, _es: &mut EntitySystem, event: &Event) -> Result<Transition, SceneError> {
+        match event {
+            Event::KeyDown {keycode: Some(Keycode::Return | Keycode::Escape), repeat: false, ..} => return Ok(Transition::Pop),
+            _ => return Ok(Transition::None),
+        }
+    }
+
+    fn update(&mut self, es: &mut EntitySystem, dt: f64) -> Result<Transition, SceneError> {
+        advance_time(es, dt);
+        self.schedule.run(es, dt)?;
+        return Ok(Transition::None);
+    }
+}
+
+//Input handling only ever nudges the player's Moveable, a failure there isn't worth stopping for
+fn update_player_moveable<F: FnOnce(&mut Moveable)>(es: &EntitySystem, player: Entity, f: F) {
+    match es.with_component_mut::<Moveable, _, _>(player, f) {
+        Ok(_) => (),
+        Err(e) => println!("Failed to update moveable for player:{}", e),
+    }
+}
+
+//Bombs sit in the middle of the tile the player's in so the flames line up with the tiles
+fn drop_bomb(es: &mut EntitySystem, player: Entity) -> Result<Entity, EntitySystemError> {
+    let (x, y) = {
+        let grid = es.resource::<SpatialGrid>()?;
+        let cell = es.with_component(player, |position: &Position| grid.cell_for(position.x, position.y))?;
+        ((cell.x as f64 + 0.5) * grid.cell_size(), (cell.y as f64 + 0.5) * grid.cell_size())
+    };
+
+    return spawn_prefab(es, "bomb", json!({"Position": {"x": x, "y": y}}));
+}
+
+//Everything in the tiles touching the player's and the closest thing drawn from a Drawable, which
+//the player isn't as it's drawn from its Animations
+fn describe_surroundings(es: &EntitySystem, player: Entity) -> Result<String, EntitySystemError> {
+    let grid = es.resource::<SpatialGrid>()?;
+    let cell = match grid.cell_of(player) {
+        Some(c) => c,
+        None => return Ok(format!("{} isn't in the grid yet", player)),
+    };
+
+    let around = grid.entities_in_rect(GridCell::new(cell.x - 1, cell.y - 1), GridCell::new(cell.x + 1, cell.y + 1));
+    let neighbours = around.iter().filter(|e| **e != player).map(|e| format!("{} \"{}\"", e, es.name_of(*e).unwrap_or(""))).collect::<Vec<String>>();
+    let nearest = match grid.nearest_with::<Drawable>(es, cell, 20)? {
+        Some(e) => e.to_string(),
+        None => "nothing".to_string(),
+    };
+
+    return Ok(format!("{} is in tile {:?}, next to [{}], nearest drawable {}", player, cell, neighbours.join(", "), nearest));
+}
+
+//The systems don't depend on each other beyond the orderings here so they could run in parallel,
+//the tests check that gives the same world as running them one at a time. The game runs them
+//serially unless it's started with --parallel, they're far too small to be worth starting
+//threads for every frame.
+fn arena_schedule(executor: Executor) -> Result<Schedule, ScheduleError> {
+    return arena_systems()
+        .executor(executor)
+        .strict()
+        .build();
+}
+
+fn arena_systems() -> ScheduleBuilder {
+    return ScheduleBuilder::new()
+        .add_system(SystemDescriptor::new("system_moveable", Stage::Update, system_moveable)
+            .reads::<Moveable>().reads::<GameRules>().writes::<Position>())
+        .add_system(SystemDescriptor::new("system_direction", Stage::Update, system_direction)
+            .reads::<Moveable>().writes::<Direction>())
+        .add_system(SystemDescriptor::new("system_fuse", Stage::Update, system_fuse)
+            .after("system_moveable")
+            .reads::<Position>().writes::<Fuse>().writes_events::<BombExploded>())
+        .add_system(SystemDescriptor::new("system_flames", Stage::Update, system_flames)
+            .writes::<Flame>())
+        .add_system(SystemDescriptor::new("system_transform", Stage::PostUpdate, system_transform)
+            .reads::<Children>().reads::<Parent>().reads::<LocalPosition>().writes::<Position>())
+        .add_system(SystemDescriptor::new("system_spatial_grid", Stage::PostUpdate, system_spatial_grid)
+            .after("system_transform")
+            .reads::<Position>().writes::<SpatialGrid>())
+        .add_system(SystemDescriptor::new("system_explosion", Stage::PostUpdate, system_explosion)
+            .after("system_spatial_grid")
+            .reads_events::<BombExploded>().reads::<GameRules>().reads::<SpatialGrid>().reads::<Position>().reads::<Fuse>().writes_events::<PlayerDied>())
+        .add_system(SystemDescriptor::new("system_animation", Stage::PostUpdate, system_animation)
+            .after("system_direction")
+            .reads::<Moveable>().reads::<Direction>().writes::<Animations>())
+        .add_system(SystemDescriptor::new("system_drawable", Stage::Render, system_drawable)
+            .after("system_animation")
+            .main_thread()
+            .reads::<Drawable>().reads::<Animations>().reads::<Position>().writes::<Renderer>());
+}
+
+//The match itself
+pub struct Arena {
+    schedule: Schedule,
+    player: Option<Entity>, //Spawned on enter
+    load_from: Option<PathBuf>, //A save to start from instead of a fresh arena
+    quicksave: Option<WorldSnapshot>, //Taken with F6 and gone back to with F7
+}
+
+impl Arena {
+    pub fn new() -> Result<Self, ScheduleError> {
+        let executor = match std::env::args().any(|arg| arg == "--parallel") {
+            true => Executor::Parallel,
+            false => Executor::Serial,
+        };
+        return Ok(Arena {schedule: arena_schedule(executor)?, player: None, load_from: None, quicksave: None});
+    }
+
+    pub fn from_save(path: &Path) -> Result<Self, ScheduleError> {
+        let mut arena = Arena::new()?;
+        arena.load_from = Some(path.to_path_buf());
+        return Ok(arena);
+    }
+
+    fn spawn_fresh(&mut self, es: &mut EntitySystem) -> Result<(), SceneError> {
+        self.player = Some(spawn_prefab(es, "player", json!({}))?);
+        spawn_prefab(es, "grass", json!({}))?;
+        spawn_prefab(es, "grass", json!({"Position": {"x": 450.0}, "Drawable": {"layer": 1}}))?;
+        spawn_prefab(es, "grass", json!({"Position": {"x": 500.0}, "Drawable": {"layer": 2}}))?;
+        return Ok(());
+    }
+}
+
+impl Scene for Arena {
+    fn name(&self) -> &'static str {
+        return "Arena";
+    }
+
+    fn enter(&mut self, es: &mut EntitySystem) -> Result<(), SceneError> {
+        setup_world(es);
+        es.insert_resource(SpatialGrid::new(TILE_SIZE));
+
+        //A bad save leaves the world empty so there's still a match to play
+        match self.load_from.take() {
+            Some(path) => {
+                match es.load_world(&path) {
+                    Ok(_) => self.player = Some(es.get_entity_for_name("Player")?),
+                    Err(e) => {
+                        println!("Failed to load {}, starting a new match:{}", path.display(), e);
+                        self.spawn_fresh(es)?;
+                    },
+                }
+            },
+            None => self.spawn_fresh(es)?,
+        }
+
+        println!("Arena: arrow keys to move, space to drop a bomb, F3 to look around, F4 to go back to the start, F5 to save, F9 to load, F6 to take a snapshot, F7 to go back to it, Escape to end the match");
+        return Ok(());
+    }
+
+    //Key ups that happen while something else is on top never get here, so stop the player
+    //rather than have them carry on walking when the arena comes back
+    fn pause(&mut self, es: &mut EntitySystem) -> Result<(), SceneError> {
+        if let Some(player) = self.player {
+            es.insert(player, (Moveable::new(0.0, 0.0),))?;
+        }
+        return Ok(());
+    }
+
+    fn handle_event(&mut self, es: &mut EntitySystem, event: &Event) -> Result<Transition, SceneError> {
+        let player = match self.player {
+            Some(p) => p,
+            None => return Ok(Transition::None),
+        };
+
+        match event {
+            Event::KeyDown {keycode: Some(Keycode::Left), repeat: false, ..} => {
+                update_player_moveable(es, player, |mv| {
+                    if mv.dx > 0.0 {
+                        mv.dx = 0.0;
+                    } else {
+                        mv.dx = -1.0;
+                    }
+                });
+            },
+            Event::KeyDown {keycode: Some(Keycode::Right), repeat: false, ..} => {
+                update_player_moveable(es, player, |mv| {
+                    if mv.dx < 0.0 {
+                        mv.dx = 0.0;
+                    } else {
+                        mv.dx = 1.0;
+                    }
+                });
+            },
+            Event::KeyDown {keycode: Some(Keycode::Up), repeat: false, ..} => {
+                update_player_moveable(es, player, |mv| {
+                    if mv.dy > 0.0 {
+                        mv.dy = 0.0;
+                    } else {
+                        mv.dy = -1.0;
+                    }
+                });
+            },
+            Event::KeyDown {keycode: Some(Keycode::Down), repeat: false, ..} => {
+                update_player_moveable(es, player, |mv| {
+                    if mv.dy < 0.0 {
+                        mv.dy = 0.0;
+                    } else {
+                        mv.dy = 1.0;
+                    }
+                });
+            },
+            Event::KeyDown {keycode: Some(Keycode::Space), repeat: false, ..} => {
+                drop_bomb(es, player)?;
+            },
+            //Debug dumps of every entity, F1 to the terminal and F2 to a file
+            Event::KeyDown {keycode: Some(Keycode::F1), repeat: false, ..} => {
+                match es.dump_world() {
+                    Ok(dump) => println!("{}", dump),
+                    Err(e) => println!("Failed to dump world:{}", e),
+                }
+            },
+            Event::KeyDown {keycode: Some(Keycode::F2), repeat: false, ..} => {
+                match es.dump_world() {
+                    Ok(dump) => {
+                        match std::fs::write("world_dump.txt", dump) {
+                            Ok(_) => println!("Dumped world to world_dump.txt"),
+                            Err(e) => println!("Failed to write world dump:{}", e),
+                        }
+                    },
+                    Err(e) => println!("Failed to dump world:{}", e),
+                }
+            },
+            //What the spatial grid has around the player
+            Event::KeyDown {keycode: Some(Keycode::F3), repeat: false, ..} => {
+                match describe_surroundings(es, player) {
+                    Ok(description) => println!("{}", description),
+                    Err(e) => println!("Failed to look around the player:{}", e),
+                }
+            },
+            Event::KeyDown {keycode: Some(Keycode::F5), repeat: false, ..} => {
+                match es.save_world(Path::new(SAVE_PATH)) {
+                    Ok(_) => println!("Saved the arena to {}", SAVE_PATH),
+                    Err(e) => println!("Failed to save the arena:{}", e),
+                }
+            },
+            Event::KeyDown {keycode: Some(Keycode::F6), repeat: false, ..} => {
+                match es.snapshot() {
+                    Ok(snapshot) => {
+                        println!("Took a snapshot of {} entities", snapshot.entity_count());
+                        self.quicksave = Some(snapshot);
+                    },
+                    Err(e) => println!("Failed to take a snapshot:{}", e),
+                }
+            },
+            Event::KeyDown {keycode: Some(Keycode::F7), repeat: false, ..} => {
+                match &self.quicksave {
+                    Some(snapshot) => {
+                        match es.restore(snapshot) {
+                            Ok(_) => println!("Went back to the snapshot"),
+                            Err(e) => println!("Failed to restore the snapshot:{}", e),
+                        }
+                    },
+                    None => println!("No snapshot to go back to, take one with F6"),
+                }
+            },
+            //Loading needs an empty world so start the arena again from the save
+            Event::KeyDown {keycode: Some(Keycode::F9), repeat: false, ..} => {
+                return Ok(Transition::Switch(Box::new(Arena::from_save(Path::new(SAVE_PATH))?)));
+            },
+            //Puts the player back where the prefab starts them if they've wandered off the screen,
+            //through reflection the way an editor would
+            Event::KeyDown {keycode: Some(Keycode::F4), repeat: false, ..} => {
+                let result = es.reflect_component_mut(player, "Position", |position| {
+                    println!("Moving the player back from ({}, {})", position.get::<f64>("x")?, position.get::<f64>("y")?);
+                    position.set("x", 0.0)?;
+                    return position.set("y", 0.0);
+                });
+                match result {
+                    Ok(_) => (),
+                    Err(e) => println!("Failed to move the player back:{}", e),
+                }
+            },
+            //Leaving ends the match
+            Event::KeyDown {keycode: Some(Keycode::Escape), ..} => {
+                let duration = es.resource::<Time>()?.elapsed;
+                return Ok(Transition::Switch(Box::new(Results::new(duration)?)));
+            },
+            Event::KeyUp {keycode: Some(Keycode::Left), repeat: false, ..} => {
+                update_player_moveable(es, player, |mv| mv.dx += 1.0);
+            },
+            Event::KeyUp {keycode: Some(Keycode::Right), repeat: false, ..} => {
+                update_player_moveable(es, player, |mv| mv.dx -= 1.0);
+            },
+            Event::KeyUp {keycode: Some(Keycode::Up), repeat: false, ..} => {
+                update_player_moveable(es, player, |mv| mv.dy += 1.0);
+            },
+            Event::KeyUp {keycode: Some(Keycode::Down), repeat: false, ..} => {
+                update_player_moveable(es, player, |mv| mv.dy -= 1.0);
+            },
+            _ => (),
+        }
+
+        return Ok(Transition::None);
+    }
+
+    //Getting caught in an explosion ends the match
+    fn update(&mut self, es: &mut EntitySystem, dt: f64) -> Result<Transition, SceneError> {
+        advance_time(es, dt);
+        self.schedule.run(es, dt)?;
+
+        for died in es.read::<PlayerDied>()? {
+            if Some(died.player) == self.player {
+                println!("Player {} was caught in an explosion", died.player);
+                let duration = es.resource::<Time>()?.elapsed;
+                return Ok(Transition::Switch(Box::new(Results::new(duration)?)));
+            }
+        }
+
+        return Ok(Transition::None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prefabs::Prefabs;
+
+    //Both are built in strict mode so this fails on any ambiguity between their systems
+    #[test]
+    fn schedules_have_no_ambiguities() {
+        assert!(MainMenu::new().unwrap().schedule.ambiguities().is_empty());
+        assert!(Lobby::new().unwrap().schedule.ambiguities().is_empty());
+        assert!(Results::new(0.0).unwrap().schedule.ambiguities().is_empty());
+        assert!(Arena::new().unwrap().schedule.ambiguities().is_empty());
+    }
+
+    //A bit of everything the arena systems touch: things walking every which way, a parent
+    //dragging its children along and animations switching as they turn
+    fn arena_world() -> EntitySystem {
+        let mut es = EntitySystem::new();
+        setup_world(&mut es);
+        es.insert_resource(SpatialGrid::new(TILE_SIZE));
+
+        let frame = Drawable::new(0, 0, 16, 16, 0);
+        let mut animations = HashMap::new();
+        for animation_type in [AnimationType::StandingDown, AnimationType::WalkingLeft, AnimationType::WalkingRight, AnimationType::WalkingUp, AnimationType::WalkingDown] {
+            animations.insert(animation_type, Animation::new_with_frames(vec![frame.clone(), frame.clone(), frame.clone()], 7.0, false, false));
+        }
+
+        let mut parent = None;
+        for i in 0..12 {
+            let (dx, dy) = ((i % 3) as f64 - 1.0, ((i / 3) % 3) as f64 - 1.0);
+            let ent = es.spawn((Position::new(i as f64 * 10.0, i as f64 * -7.0), Moveable::new(dx, dy), Direction::Down, Animations::new(AnimationType::StandingDown, animations.clone()))).unwrap();
+            if i % 4 == 0 {
+                parent = Some(ent);
+            } else if let Some(p) = parent {
+                es.insert(ent, (LocalPosition::new(i as f64, 16.0),)).unwrap();
+                es.set_parent(ent, p).unwrap();
+            }
+        }
+
+        return es;
+    }
+
+    //dump_world would do but Animations keeps its animations in a HashMap, which prints in a
+    //different order in each world
+    fn arena_state(es: &EntitySystem) -> Vec<String> {
+        let mut entities = es.entities();
+        entities.sort_by_key(|e| e.index());
+
+        //Flames from the bombs only have a Position
+        return entities.iter().map(|ent| {
+            let position = es.get::<Position>(*ent).unwrap();
+            let direction = match es.get::<Direction>(*ent) {
+                Ok(d) => format!("{:?}", *d),
+                Err(_) => String::new(),
+            };
+            let animation = match es.get::<Animations>(*ent) {
+                Ok(a) => format!("{:?} {} {}", a.current_animation, a.current_frame, a.last_frame_time),
+                Err(_) => String::new(),
+            };
+            return format!("{} {:?} {} {} {}", ent, es.name_of(*ent), *position, direction, animation);
+        }).collect();
+    }
+
+    //Two systems that don't depend on each other both spawning through Commands, so in parallel
+    //they race for the entities they reserve. Each also despawns the oldest of its own sparks to
+    //leave holes in the indices.
+    fn spawn_sparks(es: &EntitySystem, name: &str) -> Result<(), crate::GameError> {
+        let commands = es.commands();
+        for i in 0..5 {
+            commands.spawn(name)?
+                .insert(Position::new(i as f64 * 3.0, 40.0))
+                .insert(Moveable::new(1.0, 0.0))
+                .insert(Direction::Right)
+                .insert(Animations::new(AnimationType::Empty, HashMap::new()));
+        }
+
+        if let Some(oldest) = es.entities_with_name(name).into_iter().min_by_key(|e| e.index()) {
+            commands.despawn(oldest);
+        }
+
+        return Ok(());
+    }
+
+    fn spawn_left_sparks(es: &EntitySystem, _dt: f64) -> Result<(), crate::GameError> {
+        return spawn_sparks(es, "LeftSpark");
+    }
+
+    fn spawn_right_sparks(es: &EntitySystem, _dt: f64) -> Result<(), crate::GameError> {
+        return spawn_sparks(es, "RightSpark");
+    }
+
+    fn sparking_arena_schedule(executor: Executor) -> Schedule {
+        return arena_systems()
+            .add_system(SystemDescriptor::new("spawn_left_sparks", Stage::Update, spawn_left_sparks))
+            .add_system(SystemDescriptor::new("spawn_right_sparks", Stage::Update, spawn_right_sparks))
+            .executor(executor)
+            .strict()
+            .build()
+            .unwrap();
+    }
+
+    #[test]
+    fn parallel_arena_matches_serial() {
+        let serial = sparking_arena_schedule(Executor::Serial);
+        let parallel = sparking_arena_schedule(Executor::Parallel);
+        let mut serial_world = arena_world();
+        let mut parallel_world = arena_world();
+
+        for _ in 0..100 {
+            serial.run(&mut serial_world, 0.1).unwrap();
+            parallel.run(&mut parallel_world, 0.1).unwrap();
+        }
+
+        assert_eq!(arena_state(&serial_world), arena_state(&parallel_world));
+        for ent in serial_world.entities() {
+            assert_eq!(serial_world.name_of(ent), parallel_world.name_of(ent));
+        }
+
+        let serial_grid = serial_world.resource::<SpatialGrid>().unwrap();
+        let parallel_grid = parallel_world.resource::<SpatialGrid>().unwrap();
+        for ent in serial_world.entities() {
+            assert!(serial_grid.cell_of(ent).is_some());
+            assert_eq!(serial_grid.cell_of(ent), parallel_grid.cell_of(ent));
+        }
+    }
+
+    //Everything the arena reads and writes as it runs, the grid and the frame timing included
+    fn rollback_state(es: &EntitySystem) -> (Vec<String>, Vec<Option<GridCell>>, f64, usize) {
+        let grid = es.resource::<SpatialGrid>().unwrap();
+        let time = es.resource::<Time>().unwrap();
+        let mut entities = es.entities();
+        entities.sort_by_key(|e| e.index());
+        return (arena_state(es), entities.iter().map(|e| grid.cell_of(*e)).collect(), time.elapsed, time.frame);
+    }
+
+    #[test]
+    fn restore_then_rerun_matches_the_first_run() {
+        let schedule = arena_schedule(Executor::Serial).unwrap();
+        let mut es = arena_world();
+        for _ in 0..5 {
+            advance_time(&es, 0.1);
+            schedule.run(&mut es, 0.1).unwrap();
+        }
+
+        es.send(BombExploded {bomb: es.entities()[0], x: 1.0, y: 2.0}).unwrap();
+        let snapshot = es.snapshot().unwrap();
+
+        let mut first = Vec::new();
+        for _ in 0..10 {
+            advance_time(&es, 0.1);
+            schedule.run(&mut es, 0.1).unwrap();
+            first.push(rollback_state(&es));
+        }
+        assert!(es.read::<BombExploded>().unwrap().is_empty());
+
+        es.restore(&snapshot).unwrap();
+        assert_eq!(es.read::<BombExploded>().unwrap().len(), 1);
+
+        for expected in first {
+            advance_time(&es, 0.1);
+            schedule.run(&mut es, 0.1).unwrap();
+            assert_eq!(rollback_state(&es), expected);
+        }
+    }
+
+    fn key_down(keycode: Keycode) -> Event {
+        return Event::KeyDown {timestamp: 0, window_id: 0, keycode: Some(keycode), scancode: None, keymod: sdl2::keyboard::Mod::NOMOD, repeat: false};
+    }
+
+    //The arena needs the player prefab
+    fn with_prefabs() -> EntitySystem {
+        let mut es = EntitySystem::new();
+        es.insert_resource(Prefabs::load_dir(std::path::Path::new("assets/prefabs")).unwrap());
+        return es;
+    }
+
+    #[test]
+    fn lobby_switches_to_the_arena_and_the_arena_to_the_results() {
+        let mut lobby = Lobby::new().unwrap();
+        let mut es = with_prefabs();
+        lobby.enter(&mut es).unwrap();
+        let mut arena = match lobby.handle_event(&mut es, &key_down(Keycode::Return)).unwrap() {
+            Transition::Switch(scene) => scene,
+            _ => panic!("expected the lobby to switch"),
+        };
+        assert_eq!(arena.name(), "Arena");
+
+        let mut es = with_prefabs();
+        arena.enter(&mut es).unwrap();
+        match arena.handle_event(&mut es, &key_down(Keycode::Escape)).unwrap() {
+            Transition::Switch(scene) => assert_eq!(scene.name(), "Results"),
+            _ => panic!("expected the arena to switch"),
+        }
+    }
+
+    #[test]
+    fn standing_on_a_bomb_ends_the_match() {
+        let mut arena = Arena::new().unwrap();
+        let mut es = with_prefabs();
+        arena.enter(&mut es).unwrap();
+        arena.handle_event(&mut es, &key_down(Keycode::Space)).unwrap();
+        assert_eq!(es.entities_with_name("Bomb").len(), 1);
+
+        match arena.update(&mut es, 1.0).unwrap() {
+            Transition::None => (),
+            _ => panic!("the fuse hasn't run out yet"),
+        }
+        assert!(es.entities_with_name("Flame").is_empty());
+
+        match arena.update(&mut es, 1.5).unwrap() {
+            Transition::Switch(scene) => assert_eq!(scene.name(), "Results"),
+            _ => panic!("expected the player to be caught in the explosion"),
+        }
+        assert!(es.entities_with_name("Bomb").is_empty());
+        assert_eq!(es.entities_with_name("Flame").len(), 1 + 4 * GameRules::new().bomb_range as usize);
+
+        arena.update(&mut es, 1.0).unwrap();
+        assert!(es.entities_with_name("Flame").is_empty());
+    }
+
+    #[test]
+    fn bombs_in_the_flames_go_off_next() {
+        let mut arena = Arena::new().unwrap();
+        let mut es = with_prefabs();
+        arena.enter(&mut es).unwrap();
+
+        let first = spawn_prefab(&mut es, "bomb", json!({"Position": {"x": 320.0, "y": 320.0}, "Fuse": {"remaining": 0.1}})).unwrap();
+        let next = spawn_prefab(&mut es, "bomb", json!({"Position": {"x": 352.0, "y": 320.0}, "Fuse": {"remaining": 10.0}})).unwrap();
+        let out_of_range = spawn_prefab(&mut es, "bomb", json!({"Position": {"x": 480.0, "y": 320.0}, "Fuse": {"remaining": 10.0}})).unwrap();
+
+        arena.update(&mut es, 0.2).unwrap();
+        assert!(!es.is_alive(first));
+        assert!(es.is_alive(next));
+
+        arena.update(&mut es, 0.01).unwrap();
+        assert!(!es.is_alive(next));
+        assert!(es.is_alive(out_of_range));
+    }
+}
@@ -0,0 +1,719 @@
+use std::any::TypeId;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::thread;
+
+use crate::GameError;
+use crate::entity_system::{DeclaredAccess, EntitySystem, EntitySystemError};
+use crate::events::{Event, Events};
+
+//Stages run in this order every frame, systems within a stage run in an order worked out from
+//their before/after constraints
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum Stage {
+    Input,
+    Update,
+    PostUpdate,
+    Render,
+}
+
+const STAGES: [Stage; 4] = [Stage::Input, Stage::Update, Stage::PostUpdate, Stage::Render];
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stage::Input => write!(f, "input"),
+            Stage::Update => write!(f, "update"),
+            Stage::PostUpdate => write!(f, "post-update"),
+            Stage::Render => write!(f, "render"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ScheduleError {
+    DuplicateSystem(String),
+    UnknownSystem(String),
+    StageOrder(String),
+    Cycle(String),
+    Ambiguous(Vec<Ambiguity>),
+    SystemFailed {system: &'static str, error: GameError},
+    CommandsFailed {stage: Stage, error: EntitySystemError},
+}
+
+impl fmt::Display for ScheduleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScheduleError::DuplicateSystem(msg) => {
+                write!(f, "ScheduleError::DuplicateSystem::{}", msg)
+            },
+            ScheduleError::UnknownSystem(msg) => {
+                write!(f, "ScheduleError::UnknownSystem::{}", msg)
+            },
+            ScheduleError::StageOrder(msg) => {
+                write!(f, "ScheduleError::StageOrder::{}", msg)
+            },
+            ScheduleError::Cycle(msg) => {
+                write!(f, "ScheduleError::Cycle::{}", msg)
+            },
+            ScheduleError::Ambiguous(ambiguities) => {
+                let pairs: Vec<String> = ambiguities.iter().map(|a| a.to_string()).collect();
+                write!(f, "ScheduleError::Ambiguous::{}", pairs.join(", "))
+            },
+            ScheduleError::SystemFailed {system, ..} => {
+                write!(f, "ScheduleError::SystemFailed::{}", system)
+            },
+            ScheduleError::CommandsFailed {stage, ..} => {
+                write!(f, "ScheduleError::CommandsFailed::{}", stage)
+            },
+        }
+    }
+}
+
+impl Error for ScheduleError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ScheduleError::SystemFailed {error, ..} => return Some(error),
+            ScheduleError::CommandsFailed {error, ..} => return Some(error),
+            _ => return None,
+        }
+    }
+}
+
+//Systems only get shared access to the EntitySystem so the ones that don't conflict can run at
+//the same time
+pub type SystemFn = Box<dyn Fn(&EntitySystem, f64) -> Result<(), GameError> + Send + Sync>;
+
+//How a schedule runs the systems within a stage
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Executor {
+    Serial, //One after the other on the calling thread
+    //Systems with no ordering between them and no conflicting access run on their own threads.
+    //The threads are started for each batch every run, so it only pays off for heavy systems.
+    Parallel,
+}
+
+//A component or resource type a system touches
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Access {
+    type_id: TypeId,
+    name: &'static str,
+}
+
+impl Access {
+    fn of<T: 'static>() -> Self {
+        return Access {type_id: TypeId::of::<T>(), name: std::any::type_name::<T>()};
+    }
+}
+
+//A system plus everything the schedule needs to know to place it. While it runs, borrowing a
+//component or resource it didn't declare in reads or writes fails.
+pub struct SystemDescriptor {
+    name: &'static str,
+    stage: Stage,
+    run: SystemFn,
+    before: Vec<&'static str>,
+    after: Vec<&'static str>,
+    reads: Vec<Access>,
+    writes: Vec<Access>,
+    main_thread: bool,
+}
+
+impl SystemDescriptor {
+    pub fn new<F>(name: &'static str, stage: Stage, run: F) -> Self where
+        F: Fn(&EntitySystem, f64) -> Result<(), GameError> + Send + Sync + 'static {
+        return SystemDescriptor {name, stage, run: Box::new(run), before: Vec::new(), after: Vec::new(), reads: Vec::new(), writes: Vec::new(), main_thread: false};
+    }
+
+    //The system uses non-send resources so it always runs on the thread running the schedule
+    pub fn main_thread(mut self) -> Self {
+        self.main_thread = true;
+        return self;
+    }
+
+    //This system has to run before the named one
+    #[cfg(test)]
+    pub fn before(mut self, name: &'static str) -> Self {
+        self.before.push(name);
+        return self;
+    }
+
+    //This system has to run after the named one
+    pub fn after(mut self, name: &'static str) -> Self {
+        self.after.push(name);
+        return self;
+    }
+
+    //Declares the system reads the component or resource T
+    pub fn reads<T: 'static>(mut self) -> Self {
+        self.reads.push(Access::of::<T>());
+        return self;
+    }
+
+    //Declares the system writes the component or resource T
+    pub fn writes<T: 'static>(mut self) -> Self {
+        self.writes.push(Access::of::<T>());
+        return self;
+    }
+
+    //Declares the system reads events of type E. Readers each have their own place in the queue
+    //so they only have to be ordered against the systems sending them.
+    pub fn reads_events<E: Event>(mut self) -> Self {
+        self.reads.push(Access::of::<Events<E>>());
+        return self;
+    }
+
+    //Declares the system sends events of type E
+    pub fn writes_events<E: Event>(mut self) -> Self {
+        self.writes.push(Access::of::<Events<E>>());
+        return self;
+    }
+
+    fn declared_access(&self) -> DeclaredAccess {
+        return DeclaredAccess {reads: self.reads.iter().map(|a| a.type_id).collect(), writes: self.writes.iter().map(|a| a.type_id).collect()};
+    }
+
+    //The first type both systems touch where at least one of them writes it
+    fn conflict_with(&self, other: &SystemDescriptor) -> Option<&'static str> {
+        for w in self.writes.iter() {
+            if other.writes.contains(w) || other.reads.contains(w) {
+                return Some(w.name);
+            }
+        }
+
+        for r in self.reads.iter() {
+            if other.writes.contains(r) {
+                return Some(r.name);
+            }
+        }
+
+        return None;
+    }
+}
+
+//Two systems touching the same data with nothing saying which goes first. They run in the order
+//they were added, which is probably not something anyone decided on purpose.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Ambiguity {
+    pub first: &'static str, //The one that runs first
+    pub second: &'static str,
+    pub access: &'static str, //The type they both touch
+}
+
+impl fmt::Display for Ambiguity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} and {} both access {}", self.first, self.second, self.access)
+    }
+}
+
+pub struct ScheduleBuilder {
+    systems: Vec<SystemDescriptor>,
+    executor: Executor,
+    strict: bool,
+}
+
+impl ScheduleBuilder {
+    pub fn new() -> Self {
+        return ScheduleBuilder {systems: Vec::new(), executor: Executor::Serial, strict: false};
+    }
+
+    //Makes any ambiguity an error from build rather than a warning
+    pub fn strict(mut self) -> Self {
+        self.strict = true;
+        return self;
+    }
+
+    pub fn executor(mut self, executor: Executor) -> Self {
+        self.executor = executor;
+        return self;
+    }
+
+    pub fn add_system(mut self, system: SystemDescriptor) -> Self {
+        self.systems.push(system);
+        return self;
+    }
+
+    //Works out the run order of every stage. Anything contradictory (cycles, a system having to
+    //run before one in an earlier stage, constraints naming systems that don't exist) is an
+    //error here rather than a frame late bug at runtime. Ambiguities are printed and kept on the
+    //Schedule, or fail the build in strict mode.
+    pub fn build(self) -> Result<Schedule, ScheduleError> {
+        let mut index_of: HashMap<&'static str, usize> = HashMap::new();
+        for (i, system) in self.systems.iter().enumerate() {
+            if index_of.insert(system.name, i).is_some() {
+                return Err(ScheduleError::DuplicateSystem(format!("System {} was added more than once", system.name)));
+            }
+        }
+
+        //Every constraint as "first runs before second"
+        let mut edges: Vec<(usize, usize)> = Vec::new();
+        for (i, system) in self.systems.iter().enumerate() {
+            for other in system.before.iter() {
+                match index_of.get(other) {
+                    Some(j) => edges.push((i, *j)),
+                    None => return Err(ScheduleError::UnknownSystem(format!("{} has to run before {} which isn't in the schedule", system.name, other))),
+                }
+            }
+
+            for other in system.after.iter() {
+                match index_of.get(other) {
+                    Some(j) => edges.push((*j, i)),
+                    None => return Err(ScheduleError::UnknownSystem(format!("{} has to run after {} which isn't in the schedule", system.name, other))),
+                }
+            }
+        }
+
+        for (first, second) in edges.iter() {
+            let (first, second) = (&self.systems[*first], &self.systems[*second]);
+            if first.stage > second.stage {
+                return Err(ScheduleError::StageOrder(format!("{} in the {} stage has to run before {} in the earlier {} stage", first.name, first.stage, second.name, second.stage)));
+            }
+        }
+
+        let mut order: Vec<usize> = Vec::new();
+        let mut batches: Vec<Vec<usize>> = Vec::new();
+        let mut ambiguities: Vec<Ambiguity> = Vec::new();
+        for stage in STAGES.iter() {
+            let members: Vec<usize> = (0..self.systems.len()).filter(|i| self.systems[*i].stage == *stage).collect();
+            let stage_edges: Vec<(usize, usize)> = edges.iter().copied().filter(|(a, b)| self.systems[*a].stage == *stage && self.systems[*b].stage == *stage).collect();
+
+            let stage_order = topological_order(&self.systems, &members, &stage_edges)?;
+            ambiguities.extend(find_ambiguities(&self.systems, &stage_order, &stage_edges));
+
+            //Batches hold positions in the final run order rather than indices into self.systems
+            for batch in parallel_batches(&self.systems, &stage_order, &stage_edges) {
+                batches.push(batch.iter().map(|n| order.len() + n).collect());
+            }
+            order.extend(stage_order);
+        }
+
+        if self.strict && !ambiguities.is_empty() {
+            return Err(ScheduleError::Ambiguous(ambiguities));
+        }
+
+        for ambiguity in ambiguities.iter() {
+            println!("Warning: {} with no ordering between them, {} will run first", ambiguity, ambiguity.first);
+        }
+
+        //Pull the systems out in run order
+        let mut slots: Vec<Option<SystemDescriptor>> = self.systems.into_iter().map(Some).collect();
+        let systems = order.iter().map(|i| slots[*i].take().unwrap()).collect();
+
+        return Ok(Schedule {systems, batches, executor: self.executor, #[cfg(test)] ambiguities});
+    }
+}
+
+//Kahn's algorithm, when several systems are ready the one added first goes first so the order
+//only changes when the constraints do
+fn topological_order(systems: &[SystemDescriptor], members: &[usize], edges: &[(usize, usize)]) -> Result<Vec<usize>, ScheduleError> {
+    let mut incoming: HashMap<usize, usize> = members.iter().map(|i| (*i, 0)).collect();
+    for (_, second) in edges.iter() {
+        *incoming.get_mut(second).unwrap() += 1;
+    }
+
+    let mut order = Vec::new();
+    let mut done: HashSet<usize> = HashSet::new();
+    while order.len() < members.len() {
+        let next = members.iter().copied().find(|i| !done.contains(i) && incoming[i] == 0);
+        let next = match next {
+            Some(n) => n,
+            None => {
+                let stuck: Vec<&str> = members.iter().filter(|i| !done.contains(i)).map(|i| systems[*i].name).collect();
+                return Err(ScheduleError::Cycle(format!("The ordering constraints between {} form a cycle", stuck.join(", "))));
+            },
+        };
+
+        done.insert(next);
+        order.push(next);
+        for (first, second) in edges.iter() {
+            if *first == next {
+                *incoming.get_mut(second).unwrap() -= 1;
+            }
+        }
+    }
+
+    return Ok(order);
+}
+
+//Every pair of systems in a stage that conflict with nothing ordering them, in run order
+fn find_ambiguities(systems: &[SystemDescriptor], order: &[usize], edges: &[(usize, usize)]) -> Vec<Ambiguity> {
+    //Everything that has to run before each system, directly or not
+    let mut ancestors: HashMap<usize, HashSet<usize>> = HashMap::new();
+    for i in order.iter() {
+        let mut mine = HashSet::new();
+        for (first, second) in edges.iter() {
+            if second == i {
+                mine.insert(*first);
+                mine.extend(ancestors[first].iter().copied());
+            }
+        }
+        ancestors.insert(*i, mine);
+    }
+
+    let mut found = Vec::new();
+    for (n, a) in order.iter().enumerate() {
+        for b in order[n+1..].iter() {
+            if ancestors[b].contains(a) {
+                continue;
+            }
+
+            if let Some(access) = systems[*a].conflict_with(&systems[*b]) {
+                found.push(Ambiguity {first: systems[*a].name, second: systems[*b].name, access});
+            }
+        }
+    }
+
+    return found;
+}
+
+//Splits a stage's run order into batches that can run at the same time. A system goes in the
+//batch after the latest earlier system it has to wait for or conflicts with, so every pair that
+//could affect each other still runs in the serial order and the results are the same either way.
+//Returns positions into order.
+fn parallel_batches(systems: &[SystemDescriptor], order: &[usize], edges: &[(usize, usize)]) -> Vec<Vec<usize>> {
+    let mut batch_of: Vec<usize> = Vec::new();
+    let mut batches: Vec<Vec<usize>> = Vec::new();
+
+    for (n, i) in order.iter().enumerate() {
+        let mut batch = 0;
+        for (m, earlier) in order[..n].iter().enumerate() {
+            let system = &systems[*i];
+            let other = &systems[*earlier];
+            let must_wait = edges.contains(&(*earlier, *i))
+                || system.conflict_with(other).is_some()
+                || (system.main_thread && other.main_thread);
+
+            if must_wait {
+                batch = batch.max(batch_of[m] + 1);
+            }
+        }
+
+        batch_of.push(batch);
+        if batches.len() <= batch {
+            batches.resize(batch + 1, Vec::new());
+        }
+        batches[batch].push(n);
+    }
+
+    return batches;
+}
+
+//n is the system's position in the run order, it reserves entities from lane n + 1 so a system
+//spawning through Commands gets the same entities whichever executor runs it
+fn run_system(system: &SystemDescriptor, n: usize, es: &EntitySystem, dt: f64) -> Result<(), GameError> {
+    es.begin_system(system.name);
+    es.set_running_lane(n + 1);
+    es.restrict_access(system.declared_access());
+    let result = (system.run)(es, dt);
+    es.end_system();
+
+    return result;
+}
+
+//Every system in the order it will run
+pub struct Schedule {
+    systems: Vec<SystemDescriptor>,
+    batches: Vec<Vec<usize>>, //Positions in systems that can run together, in order
+    executor: Executor,
+    #[cfg(test)]
+    ambiguities: Vec<Ambiguity>,
+}
+
+impl Schedule {
+    //Events and removals are aged at the end even if a system failed, otherwise the next run would
+    //see this run's events and removals all over again
+    pub fn run(&self, es: &mut EntitySystem, dt: f64) -> Result<(), ScheduleError> {
+        es.set_reservation_lanes(self.systems.len() + 1);
+        let result = self.run_stages(es, dt);
+        es.set_reservation_lanes(1);
+
+        es.update_events();
+        es.clear_trackers();
+
+        return result;
+    }
+
+    fn run_stages(&self, es: &mut EntitySystem, dt: f64) -> Result<(), ScheduleError> {
+        match self.executor {
+            Executor::Serial => {
+                for (n, system) in self.systems.iter().enumerate() {
+                    if let Err(error) = run_system(system, n, es, dt) {
+                        return Err(ScheduleError::SystemFailed {system: system.name, error});
+                    }
+
+                    let stage_done = match self.systems.get(n + 1) {
+                        Some(next) => next.stage != system.stage,
+                        None => true,
+                    };
+                    if stage_done {
+                        self.sync(es, system.stage)?;
+                    }
+                }
+            },
+            Executor::Parallel => {
+                for (b, batch) in self.batches.iter().enumerate() {
+                    self.run_batch(es, batch, dt)?;
+
+                    let stage = self.systems[batch[0]].stage;
+                    let stage_done = match self.batches.get(b + 1) {
+                        Some(next) => self.systems[next[0]].stage != stage,
+                        None => true,
+                    };
+                    if stage_done {
+                        self.sync(es, stage)?;
+                    }
+                }
+            },
+        }
+
+        return Ok(());
+    }
+
+    //The sync point at the end of every stage, where the commands queued by its systems get
+    //applied so the next stage sees the spawned/despawned entities
+    fn sync(&self, es: &mut EntitySystem, stage: Stage) -> Result<(), ScheduleError> {
+        let system_order: Vec<&'static str> = self.systems.iter().filter(|s| s.stage == stage).map(|s| s.name).collect();
+
+        match es.apply_commands_ordered(&system_order) {
+            Ok(_) => return Ok(()),
+            Err(error) => return Err(ScheduleError::CommandsFailed {stage, error}),
+        }
+    }
+
+    //Main thread systems run on the calling thread, everything else gets its own scoped thread.
+    //Errors are reported in run order so it's the same one the serial executor would give.
+    fn run_batch(&self, es: &EntitySystem, batch: &[usize], dt: f64) -> Result<(), ScheduleError> {
+        let mut results: Vec<(usize, Result<(), GameError>)> = Vec::new();
+
+        if batch.len() == 1 {
+            results.push((batch[0], run_system(&self.systems[batch[0]], batch[0], es, dt)));
+        } else {
+            thread::scope(|scope| {
+                let mut handles = Vec::new();
+                for n in batch.iter().filter(|n| !self.systems[**n].main_thread) {
+                    let system = &self.systems[*n];
+                    let n = *n;
+                    handles.push((n, scope.spawn(move || run_system(system, n, es, dt))));
+                }
+
+                for n in batch.iter().filter(|n| self.systems[**n].main_thread) {
+                    results.push((*n, run_system(&self.systems[*n], *n, es, dt)));
+                }
+
+                for (n, handle) in handles {
+                    match handle.join() {
+                        Ok(r) => results.push((n, r)),
+                        Err(panic) => std::panic::resume_unwind(panic),
+                    }
+                }
+            });
+        }
+
+        results.sort_by_key(|(n, _)| *n);
+        for (n, result) in results {
+            if let Err(error) = result {
+                return Err(ScheduleError::SystemFailed {system: self.systems[n].name, error});
+            }
+        }
+
+        return Ok(());
+    }
+
+    //Pairs of systems that touch the same data with no ordering between them
+    #[cfg(test)]
+    pub fn ambiguities(&self) -> &[Ambiguity] {
+        return &self.ambiguities;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Score;
+    struct Lives;
+
+    fn noop(_es: &EntitySystem, _dt: f64) -> Result<(), GameError> {
+        return Ok(());
+    }
+
+    #[test]
+    fn unordered_writers_are_ambiguous() {
+        let schedule = ScheduleBuilder::new()
+            .add_system(SystemDescriptor::new("a", Stage::Update, noop).writes::<Score>())
+            .add_system(SystemDescriptor::new("b", Stage::Update, noop).reads::<Score>())
+            .add_system(SystemDescriptor::new("c", Stage::Update, noop).writes::<Lives>())
+            .build()
+            .unwrap();
+
+        assert_eq!(schedule.ambiguities(), &[Ambiguity {first: "a", second: "b", access: std::any::type_name::<Score>()}]);
+    }
+
+    #[test]
+    fn ordering_or_stages_resolve_ambiguities() {
+        let schedule = ScheduleBuilder::new()
+            .add_system(SystemDescriptor::new("a", Stage::Update, noop).writes::<Score>())
+            .add_system(SystemDescriptor::new("b", Stage::Update, noop).after("a").reads::<Score>())
+            .add_system(SystemDescriptor::new("c", Stage::PostUpdate, noop).writes::<Score>())
+            .strict()
+            .build()
+            .unwrap();
+
+        assert!(schedule.ambiguities().is_empty());
+    }
+
+    #[test]
+    fn strict_build_fails_on_ambiguity() {
+        let result = ScheduleBuilder::new()
+            .add_system(SystemDescriptor::new("a", Stage::Update, noop).writes::<Score>())
+            .add_system(SystemDescriptor::new("b", Stage::Update, noop).writes::<Score>())
+            .strict()
+            .build();
+
+        match result {
+            Err(ScheduleError::Ambiguous(ambiguities)) => assert_eq!(ambiguities.len(), 1),
+            Err(e) => panic!("Wrong error {}", e),
+            Ok(_) => panic!("Built an ambiguous schedule in strict mode"),
+        }
+    }
+
+    fn read_score(es: &EntitySystem, _dt: f64) -> Result<(), GameError> {
+        let _guard = es.resource::<Score>()?;
+        return Ok(());
+    }
+
+    fn write_score(es: &EntitySystem, _dt: f64) -> Result<(), GameError> {
+        let _guard = es.resource_mut::<Score>()?;
+        return Ok(());
+    }
+
+    fn run_one(system: SystemDescriptor) -> Result<(), ScheduleError> {
+        let mut es = EntitySystem::new();
+        es.insert_resource(Score);
+        return ScheduleBuilder::new().add_system(system).build().unwrap().run(&mut es, 0.1);
+    }
+
+    #[test]
+    fn borrows_have_to_be_declared() {
+        assert!(run_one(SystemDescriptor::new("reader", Stage::Update, read_score).reads::<Score>()).is_ok());
+        assert!(run_one(SystemDescriptor::new("writer", Stage::Update, write_score).writes::<Score>()).is_ok());
+        assert!(run_one(SystemDescriptor::new("writer_reading", Stage::Update, read_score).writes::<Score>()).is_ok());
+
+        match run_one(SystemDescriptor::new("reader_writing", Stage::Update, write_score).reads::<Score>()) {
+            Err(ScheduleError::SystemFailed {error: GameError::EntitySystemError(EntitySystemError::UndeclaredAccess {system, ..}), ..}) => assert_eq!(system, "reader_writing"),
+            _ => panic!("Writing a resource only declared as read should fail"),
+        }
+        assert!(run_one(SystemDescriptor::new("undeclared", Stage::Update, read_score).reads::<Lives>()).is_err());
+
+        //Outside of a system anything goes
+        let mut es = EntitySystem::new();
+        es.insert_resource(Score);
+        assert!(write_score(&es, 0.1).is_ok());
+    }
+
+    #[derive(Clone)]
+    struct Ping;
+
+    fn send_ping(es: &EntitySystem, _dt: f64) -> Result<(), GameError> {
+        es.send(Ping)?;
+        return Ok(());
+    }
+
+    fn fail(_es: &EntitySystem, _dt: f64) -> Result<(), GameError> {
+        return Err(GameError::EntitySystemError(EntitySystemError::NoSuchResource {resource: "Nothing"}));
+    }
+
+    #[test]
+    fn events_are_declared_access() {
+        let schedule = ScheduleBuilder::new()
+            .add_system(SystemDescriptor::new("sender", Stage::Update, send_ping).writes_events::<Ping>())
+            .add_system(SystemDescriptor::new("reader", Stage::Update, noop).reads_events::<Ping>())
+            .add_system(SystemDescriptor::new("other_reader", Stage::Update, noop).reads_events::<Ping>())
+            .build()
+            .unwrap();
+        //The readers don't care about each other, only about the sender
+        assert_eq!(schedule.ambiguities().len(), 2);
+
+        let mut es = EntitySystem::new();
+        es.add_event::<Ping>();
+        assert!(schedule.run(&mut es, 0.1).is_ok());
+        assert!(run_one(SystemDescriptor::new("undeclared_sender", Stage::Update, send_ping).reads_events::<Ping>()).is_err());
+    }
+
+    #[test]
+    fn events_age_when_a_system_fails() {
+        let mut es = EntitySystem::new();
+        es.add_event::<Ping>();
+        es.send(Ping).unwrap();
+
+        let schedule = ScheduleBuilder::new()
+            .add_system(SystemDescriptor::new("fail", Stage::Update, fail))
+            .build()
+            .unwrap();
+        assert!(schedule.run(&mut es, 0.1).is_err());
+        assert!(schedule.run(&mut es, 0.1).is_err());
+
+        //Two runs later the event is gone like it would be if nothing had failed
+        assert!(es.read::<Ping>().unwrap().is_empty());
+    }
+
+    #[derive(Clone)]
+    struct Numbered(u32);
+
+    struct Sent(u32);
+
+    struct Seen(Vec<u32>);
+
+    fn send_numbered(es: &EntitySystem, _dt: f64) -> Result<(), GameError> {
+        let mut sent = es.resource_mut::<Sent>()?;
+        es.send(Numbered(sent.0))?;
+        sent.0 += 1;
+        return Ok(());
+    }
+
+    fn read_numbered(es: &EntitySystem, _dt: f64) -> Result<(), GameError> {
+        let numbers = es.read::<Numbered>()?;
+        es.resource_mut::<Seen>()?.0.extend(numbers.iter().map(|n| n.0));
+        return Ok(());
+    }
+
+    //The reader runs first so it only gets each event the run after it was sent, but it still
+    //gets every one of them once
+    #[test]
+    fn readers_before_the_sender_see_every_event_once() {
+        let schedule = ScheduleBuilder::new()
+            .add_system(SystemDescriptor::new("reader", Stage::Update, read_numbered)
+                .before("sender")
+                .reads_events::<Numbered>().writes::<Seen>())
+            .add_system(SystemDescriptor::new("sender", Stage::Update, send_numbered)
+                .writes_events::<Numbered>().writes::<Sent>())
+            .strict()
+            .build()
+            .unwrap();
+
+        let mut es = EntitySystem::new();
+        es.add_event::<Numbered>();
+        es.insert_resource(Sent(0));
+        es.insert_resource(Seen(Vec::new()));
+
+        schedule.run(&mut es, 0.1).unwrap();
+        assert!(es.resource::<Seen>().unwrap().0.is_empty());
+
+        for _ in 0..3 {
+            schedule.run(&mut es, 0.1).unwrap();
+        }
+        assert_eq!(es.resource::<Seen>().unwrap().0, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn non_send_resources_leak_when_dropped_on_another_thread() {
+        let marker = std::rc::Rc::new(());
+        let mut es = EntitySystem::new();
+        es.insert_non_send_resource(marker.clone()).unwrap();
+
+        thread::spawn(move || drop(es)).join().unwrap();
+        assert_eq!(std::rc::Rc::strong_count(&marker), 2); //Never dropped
+    }
+}
@@ -0,0 +1,31 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+
+use crate::entity_system::Entity;
+use crate::events::EventQueue;
+
+//A copy of an EntitySystem's entities, registered components, registered resources and events held
+//in memory, see EntitySystem::snapshot and EntitySystem::restore. Cheap enough to take every tick
+//for rollback.
+pub struct WorldSnapshot {
+	pub(crate) next_index: u64,
+	pub(crate) generations: Vec<u32>,
+	pub(crate) free_indices: Vec<u64>,
+	pub(crate) entities: HashMap<u64, String>,
+	pub(crate) entity_names: HashMap<String, Vec<u64>>,
+	pub(crate) tags: HashMap<String, Vec<u64>>,
+	pub(crate) entity_tags: HashMap<u64, Vec<String>>,
+	pub(crate) components: HashMap<TypeId, Box<dyn Any + Send + Sync>>, //A SparseSet<T> per registered type that had a store
+	pub(crate) resources: HashMap<TypeId, Box<dyn Any + Send + Sync>>, //Registered resources that were there
+	pub(crate) events: HashMap<TypeId, Box<dyn EventQueue>>,
+	pub(crate) removed: HashMap<TypeId, Vec<(Entity, u64)>>,
+	pub(crate) removed_cleared_at: u64,
+	pub(crate) change_tick: u64,
+	pub(crate) system_ticks: HashMap<&'static str, u64>,
+}
+
+impl WorldSnapshot {
+	pub fn entity_count(&self) -> usize {
+		return self.entities.len();
+	}
+}
@@ -0,0 +1,214 @@
+//When a component was added and last changed, in EntitySystem change ticks
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct ComponentTicks {
+	pub added: u64,
+	pub changed: u64,
+}
+
+//Component storage keyed by entity index. The components are packed together in dense so
+//iterating them is a walk over contiguous memory, while sparse maps an entity index to where its
+//component lives in dense giving O(1) lookup, insert and remove.
+#[derive(Clone)]
+pub struct SparseSet<T> {
+	sparse: Vec<Option<usize>>, //Entity index -> position in dense/data
+	dense: Vec<u64>, //Entity index of each component in data
+	data: Vec<T>,
+	ticks: Vec<ComponentTicks>, //Parallel to data
+}
+
+impl<T> SparseSet<T> {
+	pub fn new() -> Self {
+		return SparseSet {sparse: Vec::new(), dense: Vec::new(), data: Vec::new(), ticks: Vec::new()};
+	}
+
+	#[cfg(test)]
+	pub fn len(&self) -> usize {
+		return self.data.len();
+	}
+
+	#[cfg(test)]
+	pub fn is_empty(&self) -> bool {
+		return self.data.is_empty();
+	}
+
+	fn dense_index(&self, id: u64) -> Option<usize> {
+		return *self.sparse.get(id as usize)?;
+	}
+
+	pub fn contains(&self, id: u64) -> bool {
+		return self.dense_index(id).is_some();
+	}
+
+	pub fn get(&self, id: u64) -> Option<&T> {
+		let i = self.dense_index(id)?;
+		return Some(&self.data[i]);
+	}
+
+	pub fn get_mut(&mut self, id: u64) -> Option<&mut T> {
+		let i = self.dense_index(id)?;
+		return Some(&mut self.data[i]);
+	}
+
+	//Returns the component that was already stored for the id, if there was one. Replacing a
+	//component counts as changing it at tick, not adding it.
+	pub fn insert(&mut self, id: u64, value: T, tick: u64) -> Option<T> {
+		if let Some(i) = self.dense_index(id) {
+			self.ticks[i].changed = tick;
+			return Some(std::mem::replace(&mut self.data[i], value));
+		}
+
+		if self.sparse.len() <= id as usize {
+			self.sparse.resize(id as usize + 1, None);
+		}
+
+		self.sparse[id as usize] = Some(self.data.len());
+		self.dense.push(id);
+		self.data.push(value);
+		self.ticks.push(ComponentTicks {added: tick, changed: tick});
+
+		return None;
+	}
+
+	//The last component is swapped into the hole left by the removed one to keep dense packed
+	pub fn remove(&mut self, id: u64) -> Option<T> {
+		let i = self.dense_index(id)?;
+
+		self.sparse[id as usize] = None;
+		self.dense.swap_remove(i);
+		let value = self.data.swap_remove(i);
+		self.ticks.swap_remove(i);
+
+		if i < self.dense.len() {
+			self.sparse[self.dense[i] as usize] = Some(i);
+		}
+
+		return Some(value);
+	}
+
+	pub fn ticks(&self, id: u64) -> Option<ComponentTicks> {
+		let i = self.dense_index(id)?;
+		return Some(self.ticks[i]);
+	}
+
+	//For wrappers that only mark the component changed when it's actually written to
+	pub fn get_mut_with_ticks(&mut self, id: u64) -> Option<(&mut T, &mut ComponentTicks)> {
+		let i = self.dense_index(id)?;
+		return Some((&mut self.data[i], &mut self.ticks[i]));
+	}
+
+	pub fn set_changed(&mut self, id: u64, tick: u64) {
+		if let Some(i) = self.dense_index(id) {
+			self.ticks[i].changed = tick;
+		}
+	}
+
+	//Entity indices in the same order as iter
+	pub fn ids(&self) -> &[u64] {
+		return &self.dense;
+	}
+
+	pub fn iter(&self) -> impl Iterator<Item = (u64, &T)> {
+		return self.dense.iter().copied().zip(self.data.iter());
+	}
+
+	#[cfg(test)]
+	pub fn iter_mut(&mut self) -> impl Iterator<Item = (u64, &mut T)> {
+		return self.dense.iter().copied().zip(self.data.iter_mut());
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn ids_and_values(set: &SparseSet<&'static str>) -> Vec<(u64, &'static str)> {
+		return set.iter().map(|(id, v)| (id, *v)).collect();
+	}
+
+	#[test]
+	fn insert_and_get() {
+		let mut set = SparseSet::new();
+		assert_eq!(set.insert(3, "a", 1), None);
+		assert_eq!(set.insert(0, "b", 2), None);
+
+		assert_eq!(set.len(), 2);
+		assert_eq!(set.get(3), Some(&"a"));
+		assert_eq!(set.get(0), Some(&"b"));
+		assert_eq!(set.get(1), None);
+		assert_eq!(set.get(100), None); //Past the end of sparse
+		assert_eq!(set.ticks(3), Some(ComponentTicks {added: 1, changed: 1}));
+	}
+
+	#[test]
+	fn replace_keeps_added_tick() {
+		let mut set = SparseSet::new();
+		set.insert(2, "a", 1);
+		assert_eq!(set.insert(2, "b", 5), Some("a"));
+
+		assert_eq!(set.len(), 1);
+		assert_eq!(set.get(2), Some(&"b"));
+		assert_eq!(set.ticks(2), Some(ComponentTicks {added: 1, changed: 5}));
+	}
+
+	#[test]
+	fn remove_middle_moves_last_into_the_hole() {
+		let mut set = SparseSet::new();
+		set.insert(0, "a", 1);
+		set.insert(1, "b", 2);
+		set.insert(2, "c", 3);
+
+		assert_eq!(set.remove(1), Some("b"));
+
+		assert_eq!(ids_and_values(&set), vec![(0, "a"), (2, "c")]);
+		assert_eq!(set.ids(), &[0, 2]);
+		assert_eq!(set.get(2), Some(&"c"));
+		assert_eq!(set.ticks(2), Some(ComponentTicks {added: 3, changed: 3})); //Ticks move with it
+		assert!(!set.contains(1));
+	}
+
+	#[test]
+	fn remove_last() {
+		let mut set = SparseSet::new();
+		set.insert(0, "a", 1);
+		set.insert(1, "b", 1);
+
+		assert_eq!(set.remove(1), Some("b"));
+		assert_eq!(set.remove(1), None);
+
+		assert_eq!(ids_and_values(&set), vec![(0, "a")]);
+		assert_eq!(set.get(0), Some(&"a"));
+	}
+
+	#[test]
+	fn remove_only_then_reinsert() {
+		let mut set = SparseSet::new();
+		set.insert(4, "a", 1);
+		assert_eq!(set.remove(4), Some("a"));
+		assert!(set.is_empty());
+
+		assert_eq!(set.insert(4, "b", 7), None); //Not a replace, it was removed
+		assert_eq!(set.get(4), Some(&"b"));
+		assert_eq!(set.ticks(4), Some(ComponentTicks {added: 7, changed: 7}));
+	}
+
+	#[test]
+	fn iter_order_after_swap_remove() {
+		let mut set = SparseSet::new();
+		for (id, v) in [(5, "a"), (1, "b"), (8, "c"), (3, "d")] {
+			set.insert(id, v, 0);
+		}
+
+		//Dense is in insert order until something is removed, then the last one fills the gap
+		assert_eq!(ids_and_values(&set), vec![(5, "a"), (1, "b"), (8, "c"), (3, "d")]);
+		set.remove(5);
+		assert_eq!(ids_and_values(&set), vec![(3, "d"), (1, "b"), (8, "c")]);
+		set.insert(5, "e", 0);
+		assert_eq!(ids_and_values(&set), vec![(3, "d"), (1, "b"), (8, "c"), (5, "e")]);
+
+		for (_, v) in set.iter_mut() {
+			*v = "z";
+		}
+		assert!(set.iter().all(|(_, v)| *v == "z"));
+		assert_eq!(set.get(8), Some(&"z"));
+	}
+}
@@ -0,0 +1,290 @@
+use std::collections::HashMap;
+
+use crate::components::Position;
+use crate::entity_system::{Component, Entity, EntitySystem, EntitySystemError};
+
+//Works the grid out again from scratch, a world without one is fine
+pub fn rebuild_spatial_grid(es: &EntitySystem) -> Result<(), EntitySystemError> {
+    match es.resource_mut::<SpatialGrid>() {
+        Ok(mut grid) => return grid.rebuild(es),
+        Err(EntitySystemError::NoSuchResource {..}) => return Ok(()),
+        Err(e) => return Err(e),
+    }
+}
+
+//A tile of the SpatialGrid
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct GridCell {
+    pub x: i32,
+    pub y: i32,
+}
+
+impl GridCell {
+    pub fn new(x: i32, y: i32) -> GridCell {
+        return GridCell {x, y};
+    }
+}
+
+//Which entities are in which tile, so "what's at (x, y)?" doesn't mean going through every
+//Position. Kept up to date from Position changes by system_spatial_grid, so it's only as current
+//as the last time that ran. Restoring and loading don't go through change detection, so worlds
+//with a grid register rebuild_spatial_grid as a reload hook.
+pub struct SpatialGrid {
+    cell_size: f64,
+    cells: HashMap<GridCell, Vec<Entity>>,
+    occupied: HashMap<u64, (Entity, GridCell)>, //Where each entity is by index, to move it out of its old cell
+}
+
+impl SpatialGrid {
+    pub fn new(cell_size: f64) -> Self {
+        return SpatialGrid {cell_size, cells: HashMap::new(), occupied: HashMap::new()};
+    }
+
+    pub fn cell_size(&self) -> f64 {
+        return self.cell_size;
+    }
+
+    pub fn cell_for(&self, x: f64, y: f64) -> GridCell {
+        return GridCell::new((x / self.cell_size).floor() as i32, (y / self.cell_size).floor() as i32);
+    }
+
+    //Puts the entity in the cell containing (x, y), moving it out of wherever it was before
+    pub fn update(&mut self, ent: Entity, x: f64, y: f64) {
+        let cell = self.cell_for(x, y);
+        if let Some((old, old_cell)) = self.occupied.get(&ent.index()) {
+            if *old == ent && *old_cell == cell {
+                return;
+            }
+        }
+
+        self.remove_index(ent.index());
+        self.cells.entry(cell).or_default().push(ent);
+        self.occupied.insert(ent.index(), (ent, cell));
+    }
+
+    //Forgets everything and puts every entity with a Position back in
+    pub fn rebuild(&mut self, es: &EntitySystem) -> Result<(), EntitySystemError> {
+        self.cells.clear();
+        self.occupied.clear();
+
+        let positions = match es.borrow_all_components_of_type::<Position>() {
+            Ok(p) => p,
+            Err(EntitySystemError::NoSuchComponent {..}) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        for (index, position) in positions.iter() {
+            if let Some(ent) = es.entity_for_index(index) {
+                self.update(ent, position.x, position.y);
+            }
+        }
+        return Ok(());
+    }
+
+    //Stale handles are ignored, an entity that's reused the index might be in the grid already
+    pub fn remove(&mut self, ent: Entity) {
+        match self.occupied.get(&ent.index()) {
+            Some((current, _)) if *current == ent => self.remove_index(ent.index()),
+            _ => (),
+        }
+    }
+
+    fn remove_index(&mut self, index: u64) {
+        let (_, cell) = match self.occupied.remove(&index) {
+            Some(o) => o,
+            None => return,
+        };
+
+        if let Some(entities) = self.cells.get_mut(&cell) {
+            entities.retain(|e| e.index() != index);
+            if entities.is_empty() {
+                self.cells.remove(&cell);
+            }
+        }
+    }
+
+    pub fn cell_of(&self, ent: Entity) -> Option<GridCell> {
+        match self.occupied.get(&ent.index()) {
+            Some((current, cell)) if *current == ent => return Some(*cell),
+            _ => return None,
+        }
+    }
+
+    pub fn entities_at(&self, cell: GridCell) -> &[Entity] {
+        match self.cells.get(&cell) {
+            Some(entities) => return entities,
+            None => return &[],
+        }
+    }
+
+    //Everything in the cells from min to max, both included
+    pub fn entities_in_rect(&self, min: GridCell, max: GridCell) -> Vec<Entity> {
+        let mut found = Vec::new();
+        for y in min.y..=max.y {
+            for x in min.x..=max.x {
+                found.extend_from_slice(self.entities_at(GridCell::new(x, y)));
+            }
+        }
+        return found;
+    }
+
+    //The closest entity with a T, searching outwards from cell up to max_distance steps away. A
+    //step is one cell up, down, left or right, the same way players move.
+    //Entities the same distance away are tied on the lowest index so the answer doesn't change
+    //with the order they went in the grid.
+    pub fn nearest_with<ComponentType: Component>(&self, es: &EntitySystem, cell: GridCell, max_distance: i32) -> Result<Option<Entity>, EntitySystemError> {
+        let store = match es.borrow_all_components_of_type::<ComponentType>() {
+            Ok(s) => s,
+            Err(EntitySystemError::NoSuchComponent {..}) => return Ok(None), //Nobody has one
+            Err(e) => return Err(e),
+        };
+
+        for distance in 0..=max_distance {
+            let mut nearest: Option<Entity> = None;
+
+            //Every cell exactly distance steps away, a diamond around cell
+            for dx in -distance..=distance {
+                let dy = distance - dx.abs();
+                let mut ring = vec![GridCell::new(cell.x + dx, cell.y + dy)];
+                if dy != 0 {
+                    ring.push(GridCell::new(cell.x + dx, cell.y - dy));
+                }
+
+                for c in ring {
+                    for ent in self.entities_at(c) {
+                        if !store.contains(ent.index()) || !es.is_alive(*ent) {
+                            continue;
+                        }
+
+                        match nearest {
+                            Some(n) if n.index() <= ent.index() => (),
+                            _ => nearest = Some(*ent),
+                        }
+                    }
+                }
+            }
+
+            if nearest.is_some() {
+                return Ok(nearest);
+            }
+        }
+
+        return Ok(None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::Moveable;
+
+    #[test]
+    fn update_moves_across_cell_edges() {
+        let mut es = EntitySystem::new();
+        let ent = es.new_entity().unwrap();
+        let mut grid = SpatialGrid::new(32.0);
+
+        grid.update(ent, 31.9, 0.0);
+        assert_eq!(grid.cell_of(ent), Some(GridCell::new(0, 0)));
+
+        grid.update(ent, 32.0, 0.0);
+        assert_eq!(grid.cell_of(ent), Some(GridCell::new(1, 0)));
+        assert!(grid.entities_at(GridCell::new(0, 0)).is_empty());
+        assert_eq!(grid.entities_at(GridCell::new(1, 0)), &[ent]);
+
+        //Negative coordinates round down rather than towards zero
+        grid.update(ent, -0.1, -32.0);
+        assert_eq!(grid.cell_of(ent), Some(GridCell::new(-1, -1)));
+        grid.update(ent, -32.1, 0.0);
+        assert_eq!(grid.cell_of(ent), Some(GridCell::new(-2, 0)));
+        assert!(grid.entities_at(GridCell::new(-1, -1)).is_empty());
+    }
+
+    #[test]
+    fn remove_ignores_a_stale_handle() {
+        let mut es = EntitySystem::new();
+        let old = es.new_entity().unwrap();
+        let mut grid = SpatialGrid::new(32.0);
+        grid.update(old, 0.0, 0.0);
+
+        es.remove_entity(old).unwrap();
+        grid.remove(old);
+        let new = es.new_entity().unwrap();
+        assert_eq!(new.index(), old.index());
+        grid.update(new, 40.0, 0.0);
+
+        grid.remove(old);
+        assert_eq!(grid.cell_of(new), Some(GridCell::new(1, 0)));
+        assert_eq!(grid.cell_of(old), None);
+        assert_eq!(grid.entities_at(GridCell::new(1, 0)), &[new]);
+    }
+
+    #[test]
+    fn entities_in_rect_includes_both_corners() {
+        let mut es = EntitySystem::new();
+        let mut grid = SpatialGrid::new(32.0);
+        let mut at = |x: f64, y: f64| {
+            let ent = es.new_entity().unwrap();
+            grid.update(ent, x, y);
+            return ent;
+        };
+        let min_corner = at(-32.0, -32.0);
+        let middle = at(0.0, 0.0);
+        let max_corner = at(63.0, 63.0);
+        at(64.0, 0.0);
+        at(0.0, -33.0);
+
+        let mut found = grid.entities_in_rect(GridCell::new(-1, -1), GridCell::new(1, 1));
+        found.sort_by_key(|e| e.index());
+        assert_eq!(found, vec![min_corner, middle, max_corner]);
+    }
+
+    #[test]
+    fn nearest_with_ties_go_to_the_lowest_index() {
+        let mut es = EntitySystem::new();
+        let first = es.spawn((Moveable::new(0.0, 0.0),)).unwrap();
+        let second = es.spawn((Moveable::new(0.0, 0.0),)).unwrap();
+        let closer_without = es.new_entity().unwrap();
+
+        //Put in the grid in the opposite order to the indices, both two steps away
+        let mut grid = SpatialGrid::new(32.0);
+        grid.update(second, 64.0, 0.0);
+        grid.update(first, 0.0, -64.0);
+        grid.update(closer_without, 32.0, 0.0);
+        assert_eq!(grid.nearest_with::<Moveable>(&es, GridCell::new(0, 0), 5).unwrap(), Some(first));
+
+        //Closer beats a lower index
+        grid.update(second, 32.0, 0.0);
+        assert_eq!(grid.nearest_with::<Moveable>(&es, GridCell::new(0, 0), 5).unwrap(), Some(second));
+        assert_eq!(grid.nearest_with::<Moveable>(&es, GridCell::new(10, 10), 5).unwrap(), None);
+    }
+
+    #[test]
+    fn rebuild_matches_the_positions() {
+        let mut es = EntitySystem::new();
+        let ent = es.spawn((Position::new(40.0, -1.0),)).unwrap();
+        let gone = es.new_entity().unwrap();
+        let mut grid = SpatialGrid::new(32.0);
+        grid.update(gone, 0.0, 0.0);
+
+        grid.rebuild(&es).unwrap();
+        assert_eq!(grid.cell_of(ent), Some(GridCell::new(1, -1)));
+        assert_eq!(grid.cell_of(gone), None);
+    }
+
+    #[test]
+    fn restore_rebuilds_the_grid() {
+        let mut es = EntitySystem::new();
+        crate::components::register_components(&mut es);
+        es.insert_resource(SpatialGrid::new(32.0));
+        es.register_reload_hook(rebuild_spatial_grid);
+        let ent = es.spawn((Position::new(0.0, 0.0),)).unwrap();
+        let snapshot = es.snapshot().unwrap();
+
+        es.get_mut::<Position>(ent).unwrap().x = 100.0;
+        es.resource_mut::<SpatialGrid>().unwrap().update(ent, 100.0, 0.0);
+
+        es.restore(&snapshot).unwrap();
+        assert_eq!(es.resource::<SpatialGrid>().unwrap().cell_of(ent), Some(GridCell::new(0, 0)));
+    }
+}
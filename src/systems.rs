@@ -7,15 +7,19 @@ use sdl2::rect::Rect;
 use sdl2::rect::Point;
 
 use crate::GameError;
-use crate::components::{Position, Moveable, Drawable, Animations, AnimationType, Direction};
-use crate::entity_system::{Entity, EntitySystem, EntitySystemError};
+use crate::components::{Position, LocalPosition, Children, Moveable, Drawable, Animations, AnimationType, Direction, Fuse, Flame};
+use crate::entity_system::{Component, Entity, EntitySystem, EntitySystemError};
+use crate::sparse_set::SparseSet;
+use crate::renderer::Renderer;
+use crate::resources::GameRules;
+use crate::query::{Changed, With, Without};
+use crate::spatial::SpatialGrid;
+use crate::events::{BombExploded, PlayerDied};
 
 #[derive(Debug)]
 pub enum SystemsError {
-    Moveable(String),
-    Drawable(String),
-    Position(String),
-    Direction(String),
+    Moveable {entity: Entity, missing: &'static str}, //A moveable without a component it needs to move
+    Position {entity: Entity, component: &'static str}, //Something drawn without a position to draw it at
 }
 
 impl Error for SystemsError {}
@@ -23,180 +27,182 @@ impl Error for SystemsError {}
 impl fmt::Display for SystemsError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            SystemsError::Moveable(e) => {
-                write!(f, "SystemsError::Moveable::{}", e)
+            SystemsError::Moveable {entity, missing} => {
+                write!(f, "SystemsError::Moveable::no <{}> component for moveable entity {}", missing, entity)
             },
-            SystemsError::Drawable(e) => {
-                write!(f, "SystemsError::Drawable::{}", e)
-            },
-            SystemsError::Position(e) => {
-                write!(f, "SystemsError::Position::{}", e)
-            },
-            SystemsError::Direction(e) => {
-                write!(f, "SystemsError::Direction::{}", e)
+            SystemsError::Position {entity, component} => {
+                write!(f, "SystemsError::Position::no position for <{}> component of entity {}", component, entity)
             },
         }
     }
 }
 
-pub fn system_moveable(es: &mut EntitySystem, dt: f64) -> Result<(), GameError> {
-	let moveables = match es.borrow_all_components_of_type::<Moveable>() {
-		Ok(m) => m,
-		Err(e) => {
-            match e {
-                EntitySystemError::NoSuchComponent(_) => {
-                    println!("No moveable components in the EntitySystem");
-                    return Ok(()); //Not having any moveables isn't the end of the world
-                },
-                _ => return Err(GameError::EntitySystemError(e)),
-            }
-        },
-	};
-
-	let mut positions = match es.borrow_all_components_of_type_mut::<Position>() {
-		Ok(p) => p,
-		Err(e) => {
-            match e {
-                EntitySystemError::NoSuchComponent(_) => {
-                    println!("No position components in the EntitySystem");
-                    return Ok(()); //Not having any positions isn't the end of the world
-                },
-                _ => return Err(GameError::EntitySystemError(e)),
-            }
-        },
-	};
-
-    const MAX_SPEED: f64 = 400.0;
-
-	for (id, moveable) in moveables.iter() {
-		match positions.get_mut(&id) {
-			Some(position) => {
-                //If we're moving in a diagonal we need to scale the movement so the actual length
-                //is 1. This will probably break when I add in smoothing....
-                if moveable.dx != 0.0 && moveable.dy != 0.0 {
-                    let length = ((moveable.dx * moveable.dx) + (moveable.dy * moveable.dy)).sqrt();
-                    position.x += (MAX_SPEED * (moveable.dx / length)) * dt;
-                    position.y += (MAX_SPEED * (moveable.dy / length)) * dt;
-                } else {
-                    position.x += (MAX_SPEED * moveable.dx) * dt;
-                    position.y += (MAX_SPEED * moveable.dy) * dt;
-                }
-			},
-			None => return Err(GameError::SystemsError(SystemsError::Moveable(format!("No position for moveable for entity {}", id)))),
-		}
+//Whether there's a store for T at all. Like before the queries, a world where nothing has ever
+//had a T isn't an error, only a moveable missing a T when others have them is.
+fn has_store<ComponentType: Component>(es: &EntitySystem) -> Result<bool, EntitySystemError> {
+    match es.borrow_all_components_of_type::<ComponentType>() {
+        Ok(_) => return Ok(true),
+        Err(EntitySystemError::NoSuchComponent {..}) => return Ok(false),
+        Err(e) => return Err(e),
+    }
+}
+
+pub fn system_moveable(es: &EntitySystem, dt: f64) -> Result<(), GameError> {
+    if !has_store::<Position>(es)? {
+        return Ok(()); //Not having any positions isn't the end of the world
+    }
+
+    if let Some(ent) = es.query_filtered::<&Moveable, Without<Position>>()?.entities().first() {
+        return Err(GameError::SystemsError(SystemsError::Moveable {entity: *ent, missing: std::any::type_name::<Position>()}));
+    }
+
+    let max_speed = es.resource::<GameRules>()?.max_speed;
 
-	}
+    es.query::<(&Moveable, &mut Position)>()?.for_each(|_, (moveable, mut position)| {
+        //Leave stationary things alone so they don't show up as changed
+        if moveable.dx == 0.0 && moveable.dy == 0.0 {
+            return;
+        }
+
+        //If we're moving in a diagonal we need to scale the movement so the actual length
+        //is 1. This will probably break when I add in smoothing....
+        if moveable.dx != 0.0 && moveable.dy != 0.0 {
+            let length = ((moveable.dx * moveable.dx) + (moveable.dy * moveable.dy)).sqrt();
+            position.x += (max_speed * (moveable.dx / length)) * dt;
+            position.y += (max_speed * (moveable.dy / length)) * dt;
+        } else {
+            position.x += (max_speed * moveable.dx) * dt;
+            position.y += (max_speed * moveable.dy) * dt;
+        }
+    });
 
 	return Ok(());
 }
 
-pub fn system_direction(es: &mut EntitySystem, _dt: f64) -> Result<(), GameError> {
-	let moveables = match es.borrow_all_components_of_type::<Moveable>() {
-		Ok(m) => m,
-		Err(e) => {
-            match e {
-                EntitySystemError::NoSuchComponent(_) => {
-                    println!("No moveable components in the EntitySystem");
-                    return Ok(()); //Not having any moveables isn't the end of the world
-                },
-                _ => return Err(GameError::EntitySystemError(e)),
+pub fn system_direction(es: &EntitySystem, _dt: f64) -> Result<(), GameError> {
+    if !has_store::<Direction>(es)? {
+        return Ok(()); //Not having any directions isn't the end of the world
+    }
+
+    if let Some(ent) = es.query_filtered::<&Moveable, Without<Direction>>()?.entities().first() {
+        return Err(GameError::SystemsError(SystemsError::Moveable {entity: *ent, missing: std::any::type_name::<Direction>()}));
+    }
+
+    es.query::<(&Moveable, &mut Direction)>()?.for_each(|_, (moveable, mut direction)| {
+        if moveable.dx != 0.0 && moveable.dy == 0.0 {
+            //println!("Moving in x direction");
+            if moveable.dx < 0.0 && *direction != Direction::Left {
+                //println!("Changing direction to left\n");
+                *direction = Direction::Left;
+            } else if moveable.dx > 0.0 && *direction != Direction::Right {
+                *direction = Direction::Right;
+                //println!("Changing direction to right\n");
             }
-        },
-	};
-
-	let mut directions = match es.borrow_all_components_of_type_mut::<Direction>() {
-		Ok(d) => d,
-		Err(e) => {
-            match e {
-                EntitySystemError::NoSuchComponent(_) => {
-                    println!("No direction components in the EntitySystem");
-                    return Ok(()); //Not having any directions isn't the end of the world
-                },
-                _ => return Err(GameError::EntitySystemError(e)),
+        } else if moveable.dx == 0.0 && moveable.dy != 0.0 {
+            //println!("Moving in y direction");
+            if moveable.dy < 0.0 && *direction != Direction::Up {
+                //println!("Changing direction to up\n");
+                *direction = Direction::Up;
+            } else if moveable.dy > 0.0 && *direction != Direction::Down {
+                //println!("Changing direction to down\n");
+                *direction = Direction::Down;
             }
-        },
-	};
-
-	for (id, moveable) in moveables.iter() {
-		match directions.get_mut(&id) {
-			Some(direction) => {
-                if moveable.dx != 0.0 && moveable.dy == 0.0 {
-                    //println!("Moving in x direction");
-                    if moveable.dx < 0.0 && *direction != Direction::Left {
-                        //println!("Changing direction to left\n");
-                        *direction = Direction::Left;
-                    } else if moveable.dx > 0.0 && *direction != Direction::Right {
-                        *direction = Direction::Right;
-                        //println!("Changing direction to right\n");
-                    }
-                } else if moveable.dx == 0.0 && moveable.dy != 0.0 {
-                    //println!("Moving in y direction");
-                    if moveable.dy < 0.0 && *direction != Direction::Up {
-                        //println!("Changing direction to up\n");
-                        *direction = Direction::Up;
-                    } else if moveable.dy > 0.0 && *direction != Direction::Down {
-                        //println!("Changing direction to down\n");
-                        *direction = Direction::Down;
-                    }
-                }
-			},
-			None => return Err(GameError::SystemsError(SystemsError::Direction(format!("No direction for moveable for entity {}", id)))),
-		}
+        }
+    });
+
+	return Ok(());
+}
+
+//Works out the Position of every child with a LocalPosition from its parent's Position, parents
+//first so grandchildren get the up to date position of their parent. Children need a Position
+//as well for this to fill in.
+pub fn system_transform(es: &EntitySystem, _dt: f64) -> Result<(), GameError> {
+    let this_run = es.system_ticks().this_run;
+
+    let children = match es.borrow_all_components_of_type::<Children>() {
+        Ok(c) => c,
+        Err(EntitySystemError::NoSuchComponent {..}) => return Ok(()), //Nobody has children
+        Err(e) => return Err(e.into()),
+    };
+    let local_positions = match es.borrow_all_components_of_type::<LocalPosition>() {
+        Ok(l) => l,
+        Err(EntitySystemError::NoSuchComponent {..}) => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut positions = es.borrow_all_components_of_type_mut::<Position>()?;
+
+    //The top of each hierarchy is a parent that isn't anyone's child
+    let mut roots = children.ids().iter().copied().filter(|id| es.entity_for_index(*id).and_then(|e| es.parent_of(e)).is_none()).collect::<Vec<u64>>();
+    roots.sort_unstable();
+
+    for root in roots {
+        propagate_position(root, &children, &local_positions, &mut positions, this_run);
+    }
+
+	return Ok(());
+}
+
+//Moves everything whose Position changed since last time into its new cell and drops anything
+//that lost its Position, including removed entities. Runs after system_transform so children
+//are in their final place.
+pub fn system_spatial_grid(es: &EntitySystem, _dt: f64) -> Result<(), GameError> {
+    let mut grid = match es.resource_mut::<SpatialGrid>() {
+        Ok(g) => g,
+        Err(EntitySystemError::NoSuchResource {..}) => return Ok(()), //Nobody wants one
+        Err(e) => return Err(e.into()),
+    };
+
+    for ent in es.removed::<Position>() {
+        grid.remove(ent);
+    }
 
-	}
+    es.query_filtered::<&Position, Changed<Position>>()?.for_each(|ent, position| {
+        grid.update(ent, position.x, position.y);
+    });
 
 	return Ok(());
 }
 
-pub fn system_animation(es: &mut EntitySystem, dt: f64) -> Result<(), GameError> {
-	let mut animations = match es.borrow_all_components_of_type_mut::<Animations>() {
-		Ok(a) => a,
-		Err(e) => {
-            match e {
-                EntitySystemError::NoSuchComponent(_) => {
-                    println!("No Animations components in the EntitySystem");
-                    return Ok(()); //Not having any positions isn't the end of the world
-                },
-                _ => return Err(GameError::EntitySystemError(e)),
-            }
-        },
-	};
-
-	let mut moveables = match es.borrow_all_components_of_type_mut::<Moveable>() {
-		Ok(m) => m,
-		Err(e) => {
-            match e {
-                EntitySystemError::NoSuchComponent(_) => {
-                    println!("No Moveable components in the EntitySystem");
-                    return Ok(()); //Not having any Moveables isn't the end of the world
-                },
-                _ => return Err(GameError::EntitySystemError(e)),
-            }
-        },
-	};
-
-	let mut directions = match es.borrow_all_components_of_type_mut::<Direction>() {
-		Ok(d) => d,
-		Err(e) => {
-            match e {
-                EntitySystemError::NoSuchComponent(_) => {
-                    println!("No Direction components in the EntitySystem");
-                    return Ok(()); //Not having any Directions isn't the end of the world
-                },
-                _ => return Err(GameError::EntitySystemError(e)),
+fn propagate_position(parent: u64, children: &SparseSet<Children>, local_positions: &SparseSet<LocalPosition>, positions: &mut SparseSet<Position>, this_run: u64) {
+    let (parent_x, parent_y) = match positions.get(parent) {
+        Some(p) => (p.x, p.y),
+        None => return,
+    };
+
+    let kids = match children.get(parent) {
+        Some(c) => c,
+        None => return,
+    };
+
+    for child in kids.entities.iter() {
+        if let Some(local) = local_positions.get(child.index()) {
+            if let Some((position, ticks)) = positions.get_mut_with_ticks(child.index()) {
+                let (x, y) = (parent_x + local.x, parent_y + local.y);
+                //Only counts as a change if it actually moved
+                if position.x != x || position.y != y {
+                    position.x = x;
+                    position.y = y;
+                    ticks.changed = this_run;
+                }
             }
-        },
-	};
+        }
 
-    for (id, animations) in animations.iter_mut() {
+        propagate_position(child.index(), children, local_positions, positions, this_run);
+    }
+}
+
+pub fn system_animation(es: &EntitySystem, dt: f64) -> Result<(), GameError> {
+    es.query::<(&mut Animations, Option<&Moveable>, Option<&Direction>)>()?.for_each(|_, (mut animations, moveable, direction)| {
         if animations.current_animation != AnimationType::Empty {
+            //Worked out on a copy so animations are only marked changed when they actually change
             let old_animation_type = animations.current_animation; //Used to check if we changed animation
-            let current_animation_type = &mut animations.current_animation;
+            let mut new_animation_type = old_animation_type;
+            let current_animation_type = &mut new_animation_type;
             if animations.animations.contains_key(&current_animation_type) {
                 //Sigh this is pretty filthy, is it really the animation systems problem to figure out
                 //if we're facing the right way? There should probably be a direction component to make
                 //this less shit...
-                let is_moving = match moveables.get(&id) {
+                let is_moving = match moveable {
                     Some(m) => {
                         if m.dx != 0.0 || m.dy != 0.0 {
                             true
@@ -207,7 +213,7 @@ pub fn system_animation(es: &mut EntitySystem, dt: f64) -> Result<(), GameError>
                     None => false,
                 };
 
-                match directions.get(&id) {
+                match direction {
                     Some(d) => { //Check to make sure the animation lines up with how the entity is "moving"
                         match d{
                             Direction::Up => {
@@ -254,95 +260,155 @@ pub fn system_animation(es: &mut EntitySystem, dt: f64) -> Result<(), GameError>
                                     }
                                 }
                             },
-                            _ => (),
                         }
                     },
                     None => (), //Not having a moveable associated with the animation is totally okay!
                 };
 
-                if *current_animation_type != old_animation_type { //If we changed animation we need to reset the frame count
+                if new_animation_type != old_animation_type { //If we changed animation we need to reset the frame count
+                    animations.current_animation = new_animation_type;
                     animations.current_frame = 0;
                 }
 
-                let current_animation = &animations.animations[&current_animation_type];
+                let current_animation = &animations.animations[&new_animation_type];
 
                 if current_animation.fps > 0.0 {
                     let time_between_frames = 1.0 / current_animation.fps; //Last_frame_time is accumilative, so we need to know how much time elapses between frames, not how frames there are per second
-                    animations.last_frame_time += dt;
+                    let frame_count = current_animation.frames.len();
+                    //Time building up towards the next frame isn't a change, the frame moving on is
+                    animations.bypass_change_detection().last_frame_time += dt;
                     if animations.last_frame_time >= time_between_frames { //Change frames
-                        animations.current_frame = (animations.current_frame + 1) % animations.animations[&animations.current_animation].frames.len();
+                        animations.current_frame = (animations.current_frame + 1) % frame_count;
                         animations.last_frame_time -= time_between_frames;
                     }
                 }
             }
         }
-    }
+    });
 
     return Ok(());
 }
 
-pub fn system_drawable(es: &mut EntitySystem, _dt: f64) -> Result<(), GameError> {
-    let mut canvas = es.get_mut_canvas();
-
-    let game_texture = es.get_texture();
-    let drawables = match es.borrow_all_components_of_type::<Drawable>() {
-        Ok(d) => Some(d),
-		Err(e) => {
-            match e {
-                EntitySystemError::NoSuchComponent(_) => {
-                    //println!("No Drawable components in the EntitySystem");
-                    None //Not having any drawables isn't the end of the world
-                },
-                _ => return Err(GameError::EntitySystemError(e)),
-            }
-        },
-    };
+//Counts the bombs down, one that runs out is gone and sends a BombExploded in its place
+pub fn system_fuse(es: &EntitySystem, dt: f64) -> Result<(), GameError> {
+    let commands = es.commands();
 
-    let animations = match es.borrow_all_components_of_type::<Animations>() {
-        Ok(a) => Some(a),
-		Err(e) => {
-            match e {
-                EntitySystemError::NoSuchComponent(_) => {
-                    println!("No Animations components in the EntitySystem");
-                    None //Not having any animations isn't the end of the world
-                },
-                _ => return Err(GameError::EntitySystemError(e)),
-            }
-        },
+    es.query::<(&mut Fuse, &Position)>()?.try_for_each(|ent, (mut fuse, position)| {
+        fuse.remaining -= dt;
+        if fuse.remaining <= 0.0 {
+            commands.despawn(ent);
+            es.send(BombExploded {bomb: ent, x: position.x, y: position.y})?;
+        }
+        return Ok::<(), GameError>(());
+    })?;
+
+    return Ok(());
+}
+
+//Sets off every bomb that went off this tick. The flames reach bomb_range tiles up, down, left
+//and right of the bomb, any player standing in them dies and any other bomb in them goes off on
+//the next tick. Runs after system_spatial_grid so the players are in the tiles they're actually in.
+pub fn system_explosion(es: &EntitySystem, _dt: f64) -> Result<(), GameError> {
+    let explosions = es.read::<BombExploded>()?;
+    if explosions.is_empty() {
+        return Ok(());
+    }
+
+    let (range, flame_time) = {
+        let rules = es.resource::<GameRules>()?;
+        (rules.bomb_range, rules.flame_time)
     };
+    let grid = es.resource::<SpatialGrid>()?;
+    let mut bombs = es.query_filtered::<&Position, With<Fuse>>()?;
+    let commands = es.commands();
+
+    let mut dead = Vec::new();
+    for explosion in explosions {
+        let mut reach = vec![(0, 0)];
+        for distance in 1..=range {
+            reach.extend([(distance, 0), (-distance, 0), (0, distance), (0, -distance)]);
+        }
 
-    let positions = match es.borrow_all_components_of_type::<Position>() {
-        Ok(p) => p,
-		Err(e) => {
-            match e {
-                EntitySystemError::NoSuchComponent(_) => {
-                    println!("No position components in the EntitySystem");
-                    return Ok(()); //Not having any positions isn't the end of the world
-                },
-                _ => return Err(GameError::EntitySystemError(e)),
+        for (dx, dy) in reach {
+            let x = explosion.x + dx as f64 * grid.cell_size();
+            let y = explosion.y + dy as f64 * grid.cell_size();
+            commands.spawn("Flame")?
+                .insert(Position::new(x, y))
+                .insert(Drawable::new(32, 288, 16, 16, 2))
+                .insert(Flame {remaining: flame_time});
+
+            for ent in grid.entities_at(grid.cell_for(x, y)) {
+                if es.has_tag(*ent, "player") && !dead.contains(ent) {
+                    dead.push(*ent);
+                }
+                if *ent != explosion.bomb && bombs.get(*ent).is_some() {
+                    commands.insert(*ent, Fuse {remaining: 0.0});
+                }
             }
-        },
-    };
+        }
+    }
 
-    canvas.clear();
+    for player in dead {
+        es.send(PlayerDied {player})?;
+    }
 
-    #[derive(PartialEq)]
-    enum DrawableLocation {
-        Drawable,
-        Animation,
+    return Ok(());
+}
+
+//Puts out flames that have burnt for long enough
+pub fn system_flames(es: &EntitySystem, dt: f64) -> Result<(), GameError> {
+    let commands = es.commands();
+
+    es.query::<&mut Flame>()?.for_each(|ent, mut flame| {
+        flame.remaining -= dt;
+        if flame.remaining <= 0.0 {
+            commands.despawn(ent);
+        }
+    });
+
+    return Ok(());
+}
+
+pub fn system_drawable(es: &EntitySystem, _dt: f64) -> Result<(), GameError> {
+    if let Some(ent) = es.query_filtered::<&Drawable, Without<Position>>()?.entities().first() {
+        return Err(GameError::SystemsError(SystemsError::Position {entity: *ent, component: std::any::type_name::<Drawable>()}));
     }
 
+    es.query_filtered::<&Animations, Without<Position>>()?.try_for_each(|ent, animations| {
+        if animations.current_animation != AnimationType::Empty { //Empty animations aren't drawn
+            return Err(GameError::SystemsError(SystemsError::Position {entity: ent, component: std::any::type_name::<Animations>()}));
+        }
+        return Ok(());
+    })?;
+
+    let mut renderer = match es.non_send_resource_mut::<Renderer>() {
+        Ok(r) => r,
+        Err(EntitySystemError::NoSuchResource {..}) => return Ok(()), //Running headless so there's nothing to draw to
+        Err(e) => return Err(GameError::EntitySystemError(e)),
+    };
+    let Renderer {texture: game_texture, canvas, ..} = &mut *renderer;
+
+    canvas.clear();
+
+    //Everything needed to copy the entity's current sprite onto the canvas
     #[derive(PartialEq)]
     struct Renderable {
-        id: u64, //Entity id of the drawable/animation
-        drawable_location: DrawableLocation, //Where is this entity? In the drawable list or part of an animation
-        layer: u32, //What layer is the drawable to be drawn in
+        src: Rect, //Where the sprite is in the game texture
+        center: Point, //Where on screen the sprite is centered
+        flip_horizontal: bool,
+        flip_vertical: bool,
         y: f64, //Each drawable is sorted by it's y position
     }
 
     impl Renderable {
-        fn new(id: u64, drawable_location: DrawableLocation, layer: u32, y: f64) -> Renderable {
-            return Renderable {id, drawable_location, layer, y};
+        fn new(drawable: &Drawable, position: &Position, flip_horizontal: bool, flip_vertical: bool) -> Renderable {
+            return Renderable {
+                src: Rect::new(drawable.x, drawable.y, drawable.w, drawable.h),
+                center: Point::new(position.x as i32, position.y as i32),
+                flip_horizontal,
+                flip_vertical,
+                y: position.y,
+            };
         }
     }
 
@@ -364,93 +430,49 @@ pub fn system_drawable(es: &mut EntitySystem, _dt: f64) -> Result<(), GameError>
     let mut layers: HashMap<u32, Vec<Renderable>> = HashMap::new();
     let mut highest_layer = 0;
 
-    if let Some(ref d) = drawables {
-        for (id, drawable) in d.iter() {
-            match positions.get(&id) {
-                Some(position) => {
-                    let mut layer = layers.entry(drawable.layer).or_insert(Vec::new());
-                    layer.push(Renderable::new(*id, DrawableLocation::Drawable, drawable.layer, position.y));
-                    if drawable.layer > highest_layer {
-                        highest_layer = drawable.layer;
-                    }
-                },
-                None => return Err(GameError::SystemsError(SystemsError::Position(format!("No position for drawable for entity {}", id)))),
-            }
+    es.query::<(&Drawable, &Position)>()?.for_each(|_, (drawable, position)| {
+        let layer = layers.entry(drawable.layer).or_insert(Vec::new());
+        layer.push(Renderable::new(drawable, position, false, false));
+        if drawable.layer > highest_layer {
+            highest_layer = drawable.layer;
+        }
+    });
 
+    es.query::<(&Animations, &Position)>()?.for_each(|_, (animations, position)| {
+        if animations.current_animation == AnimationType::Empty { //Empty animations aren't drawn
+            return;
         }
-    }
 
-    if let Some(ref a) = animations {
-        for (id, animations) in a.iter() {
-            if animations.current_animation != AnimationType::Empty { //Empty animations aren't drawn
-                match positions.get(&id) {
-                    Some(position) => {
-                        let animation = match animations.animations.get(&animations.current_animation) {
-                            Some(a) => a,
-                            None => continue,
-                        };
-                        match animation.frames.get(animations.current_frame) {
-                            Some(drawable) => {
-                                let mut layer = layers.entry(drawable.layer).or_insert(Vec::new());
-                                layer.push(Renderable::new(*id, DrawableLocation::Animation, drawable.layer, position.y));
-                                if drawable.layer > highest_layer {
-                                    highest_layer = drawable.layer;
-                                }
-                            },
-                            None => println!("Failed to get current frame {} of {} frames", animations.current_frame, animation.frames.len()),
-                       }
-                    },
-                    None => return Err(GameError::SystemsError(SystemsError::Position(format!("No position for animation for entity {}", id)))),
+        let animation = match animations.animations.get(&animations.current_animation) {
+            Some(a) => a,
+            None => return,
+        };
+
+        match animation.frames.get(animations.current_frame) {
+            Some(drawable) => {
+                let layer = layers.entry(drawable.layer).or_insert(Vec::new());
+                layer.push(Renderable::new(drawable, position, animation.flip_horizontal, animation.flip_vertical));
+                if drawable.layer > highest_layer {
+                    highest_layer = drawable.layer;
                 }
-            }
+            },
+            None => println!("Failed to get current frame {} of {} frames", animations.current_frame, animation.frames.len()),
         }
-    }
+    });
 
     //Sort each layer by the y. This needs to be the opposite though because Don't forget y:0 is at
     //the top of the screen meaning the smaller the y it should be drawn first
-    for (layer_id, layer) in layers.iter_mut() {
+    for (_, layer) in layers.iter_mut() {
         layer.sort_by(|a, b| a.partial_cmp(b).unwrap());
     }
 
-    //Fuck me dead.... we can actually perform the drawing finally
-    //Also this is filthy and just awful to think about, let alone read. 
-    //I pray for future me's sanity trying to understand this garbage.
     for i in 0..(highest_layer+1) {
         if let Some(layer) = layers.get(&i) {
             for renderable in layer {
-                match renderable.drawable_location {
-                    DrawableLocation::Drawable => {
-                        if let Some(ref d) = drawables {
-                            if let Some(drawable) = d.get(&renderable.id) {
-                                if let Some(position) = positions.get(&renderable.id) {
-                                    let center = Point::new(position.x as i32, position.y as i32);
-                                    match canvas.copy(&game_texture, Some(Rect::new(drawable.x, drawable.y, drawable.w, drawable.h)), Some(Rect::from_center(center, drawable.w*2, drawable.h*2))) {
-                                        Ok(_) => (),
-                                        Err(e) => println!("Failed to copy texture:{}", e),
-                                    }
-                                }
-                            }
-                        }
-                    },
-                    //Surely this is the record for using the word "animations" consecutively as
-                    //variable names.....
-                    DrawableLocation::Animation => {
-                        if let Some(ref animations) = animations {
-                            if let Some(ref animations) = animations.get(&renderable.id) {
-                                if let Some(ref animation) = animations.animations.get(&animations.current_animation) {
-                                    let drawable = &animation.frames[animations.current_frame];
-                                    if let Some(position) = positions.get(&renderable.id) {
-                                        let center = Point::new(position.x as i32, position.y as i32);
-                                        match canvas.copy_ex(&game_texture, Some(Rect::new(drawable.x, drawable.y, drawable.w, drawable.h)), Some(Rect::from_center(center, drawable.w*2, drawable.h*2)), 0.0, None,
-                                            animation.flip_horizontal, animation.flip_vertical) {
-                                            Ok(_) => (),
-                                            Err(e) => println!("Failed to copy texture:{}", e),
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
+                let dst = Rect::from_center(renderable.center, renderable.src.width()*2, renderable.src.height()*2);
+                match canvas.copy_ex(&game_texture, Some(renderable.src), Some(dst), 0.0, None, renderable.flip_horizontal, renderable.flip_vertical) {
+                    Ok(_) => (),
+                    Err(e) => println!("Failed to copy texture:{}", e),
                 }
             }
         }
@@ -460,3 +482,133 @@ pub fn system_drawable(es: &mut EntitySystem, _dt: f64) -> Result<(), GameError>
 
     return Ok(());
 }
+
+//No Renderer anywhere in these, the world runs headless
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::Animation;
+    use crate::schedule::{ScheduleBuilder, Stage, SystemDescriptor};
+
+    fn world() -> EntitySystem {
+        let mut es = EntitySystem::new();
+        es.insert_resource(GameRules::new());
+        return es;
+    }
+
+    fn animations() -> Animations {
+        let frame = Drawable::new(0, 0, 16, 16, 0);
+        let mut animations = HashMap::new();
+        animations.insert(AnimationType::StandingDown, Animation::new_with_frames(vec![frame.clone()], 0.0, false, false));
+        animations.insert(AnimationType::WalkingLeft, Animation::new_with_frames(vec![frame.clone(), frame.clone()], 10.0, false, false));
+        return Animations::new(AnimationType::StandingDown, animations);
+    }
+
+    #[test]
+    fn moveable_moves_position() {
+        let mut es = world();
+        let straight = es.spawn((Position::new(0.0, 0.0), Moveable::new(1.0, 0.0))).unwrap();
+        let diagonal = es.spawn((Position::new(0.0, 0.0), Moveable::new(1.0, 1.0))).unwrap();
+        let still = es.spawn((Position::new(5.0, 5.0), Moveable::new(0.0, 0.0))).unwrap();
+
+        system_moveable(&es, 0.5).unwrap();
+
+        let max_speed = GameRules::new().max_speed;
+        es.with_component(straight, |p: &Position| {
+            assert_eq!((p.x, p.y), (max_speed * 0.5, 0.0));
+        }).unwrap();
+        es.with_component(diagonal, |p: &Position| {
+            let expected = max_speed * 0.5 / 2.0f64.sqrt(); //Same speed as going straight
+            assert!((p.x - expected).abs() < 1e-9 && (p.y - expected).abs() < 1e-9);
+        }).unwrap();
+        es.with_component(still, |p: &Position| {
+            assert_eq!((p.x, p.y), (5.0, 5.0));
+        }).unwrap();
+    }
+
+    #[test]
+    fn moveable_without_any_positions_is_fine() {
+        let mut es = world();
+        es.spawn((Moveable::new(1.0, 0.0),)).unwrap();
+        assert!(system_moveable(&es, 1.0).is_ok());
+
+        //Once there are positions though a moveable without one is a mistake
+        let ent = es.entities()[0];
+        es.spawn((Position::new(0.0, 0.0),)).unwrap();
+        match system_moveable(&es, 1.0) {
+            Err(GameError::SystemsError(SystemsError::Moveable {entity, missing})) => {
+                assert_eq!(entity, ent);
+                assert_eq!(missing, std::any::type_name::<Position>());
+            },
+            _ => panic!("expected the moveable's missing position"),
+        }
+    }
+
+    #[test]
+    fn direction_follows_movement() {
+        let mut es = world();
+        let left = es.spawn((Moveable::new(-1.0, 0.0), Direction::Down)).unwrap();
+        let up = es.spawn((Moveable::new(0.0, -1.0), Direction::Down)).unwrap();
+        let diagonal = es.spawn((Moveable::new(1.0, 1.0), Direction::Up)).unwrap(); //Keeps facing the way it was
+
+        system_direction(&es, 0.1).unwrap();
+
+        assert_eq!(*es.get::<Direction>(left).unwrap(), Direction::Left);
+        assert_eq!(*es.get::<Direction>(up).unwrap(), Direction::Up);
+        assert_eq!(*es.get::<Direction>(diagonal).unwrap(), Direction::Up);
+    }
+
+    #[test]
+    fn animation_switches_and_advances() {
+        let mut es = world();
+        let walking = es.spawn((animations(), Moveable::new(-1.0, 0.0), Direction::Left)).unwrap();
+        let standing = es.spawn((animations(), Moveable::new(0.0, 0.0), Direction::Down)).unwrap();
+
+        system_animation(&es, 0.15).unwrap();
+
+        let a = es.get::<Animations>(walking).unwrap();
+        assert_eq!(a.current_animation, AnimationType::WalkingLeft);
+        assert_eq!(a.current_frame, 1); //Reset to 0 on the switch then 0.15s at 10fps is one frame on
+        assert!((a.last_frame_time - 0.05).abs() < 1e-9);
+
+        let a = es.get::<Animations>(standing).unwrap();
+        assert_eq!(a.current_animation, AnimationType::StandingDown);
+        assert_eq!(a.current_frame, 0);
+    }
+
+    #[test]
+    fn drawable_without_renderer_does_nothing() {
+        let mut es = world();
+        es.spawn((Position::new(0.0, 0.0), Drawable::new(0, 0, 16, 16, 0))).unwrap();
+        assert!(system_drawable(&es, 0.1).is_ok());
+    }
+
+    //Static animations and ones part way to their next frame shouldn't show up in Changed<Animations>
+    #[test]
+    fn animation_only_changes_when_it_moves_on() {
+        let mut es = world();
+        let standing = es.spawn((animations(), Moveable::new(0.0, 0.0), Direction::Down)).unwrap();
+        let walking = es.spawn((animations(), Moveable::new(-1.0, 0.0), Direction::Left)).unwrap();
+        let schedule = ScheduleBuilder::new()
+            .add_system(SystemDescriptor::new("system_animation", Stage::Update, system_animation)
+                .reads::<Moveable>().reads::<Direction>().writes::<Animations>())
+            .build()
+            .unwrap();
+
+        let changed_at = |es: &EntitySystem, ent: Entity| es.borrow_all_components_of_type::<Animations>().unwrap().ticks(ent.index()).unwrap().changed;
+        let standing_since = changed_at(&es, standing);
+
+        schedule.run(&mut es, 0.02).unwrap(); //Switches to walking
+        let walking_since = changed_at(&es, walking);
+        assert_eq!(changed_at(&es, standing), standing_since);
+
+        schedule.run(&mut es, 0.05).unwrap(); //Most of the way to the next frame
+        assert_eq!(changed_at(&es, walking), walking_since);
+        assert_eq!(es.get::<Animations>(walking).unwrap().current_frame, 0);
+
+        schedule.run(&mut es, 0.05).unwrap();
+        assert!(changed_at(&es, walking) > walking_since);
+        assert_eq!(es.get::<Animations>(walking).unwrap().current_frame, 1);
+        assert_eq!(changed_at(&es, standing), standing_since);
+    }
+}